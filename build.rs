@@ -1,43 +1,18 @@
-extern crate bindgen;
-extern crate pkg_config;
+/// The optional libtdb symbols that trivialdb-sys reports, as the `DEP_TDB_*` variable it sets
+/// and the cfg to enable in this crate.
+const OPTIONAL_SYMBOLS: &[(&str, &str)] = &[
+    ("DEP_TDB_STOREV", "tdb_storev"),
+    ("DEP_TDB_TRAVERSE_CHAIN", "tdb_traverse_chain"),
+    ("DEP_TDB_MUTEX_LOCKING", "tdb_mutex_locking"),
+];
 
 fn main() {
-    system_deps::Config::new().probe().unwrap();
-
-    // Use pkg-config to get the necessary flags for the `tdb` library
-    let pc_tdb = pkg_config::Config::new()
-        .probe("tdb")
-        .unwrap_or_else(|e| panic!("Failed to find tdb library: {}", e));
-
-    if pc_tdb.include_paths.len() != 1 {
-        panic!("Expected to find exactly one tdb include path");
+    for (var, cfg) in OPTIONAL_SYMBOLS {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+        // The native backend implements everything itself, and doesn't depend on
+        // trivialdb-sys unless `libtdb` is also enabled.
+        if !cfg!(feature = "native") && std::env::var_os(var).is_some() {
+            println!("cargo:rustc-cfg={}", cfg);
+        }
     }
-
-    let tdb_header = pc_tdb.include_paths[0].join("tdb.h");
-
-    // Generate bindings using bindgen
-    let bindings = bindgen::Builder::default()
-        .header("sys/stat.h")
-        .header(tdb_header.to_str().unwrap())
-        .blocklist_type("TDB_DATA")
-        .blocklist_function("tdb_store")
-        .blocklist_function("tdb_fetch")
-        .blocklist_function("tdb_append")
-        .blocklist_function("tdb_delete")
-        .blocklist_function("tdb_exists")
-        .blocklist_function("tdb_nextkey")
-        .clang_args(
-            pc_tdb
-                .include_paths
-                .iter()
-                .map(|path| format!("-I{}", path.display())),
-        )
-        .generate_inline_functions(true)
-        .generate()
-        .expect("Failed to generate bindings");
-
-    let out_path = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
-    bindings
-        .write_to_file(out_path.join("tdb_sys.rs"))
-        .expect("Failed to write bindings");
 }
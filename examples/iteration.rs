@@ -38,14 +38,15 @@ fn main() {
         );
     }
 
-    // Filter keys with a prefix
-    println!("\nIterating over keys with 'user:' prefix:");
-    for key in tdb.keys() {
-        let key_str = String::from_utf8_lossy(&key);
-        if key_str.starts_with("user:") {
-            let value = tdb.fetch(&key).unwrap().unwrap();
-            println!("  {:?} => {:?}", key_str, String::from_utf8_lossy(&value));
-        }
+    // Iterate over the records with a prefix
+    println!("\nIterating over the 'user:' namespace:");
+    let users = tdb.namespace(b"user:");
+    for (key, value) in users.iter().expect("Failed to traverse namespace") {
+        println!(
+            "  {:?} => {:?}",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&value)
+        );
     }
 
     // Count total entries
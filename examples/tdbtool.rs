@@ -0,0 +1,56 @@
+//! A small command line tool for working with TDB files.
+//!
+//! Usage:
+//!
+//! ```text
+//! cargo run --example tdbtool -- diff OLD.tdb NEW.tdb
+//! ```
+//!
+//! `diff` prints the records that differ between two databases, with keys and values escaped
+//! as by `tdbdump`. Like diff(1), it exits with status 0 if the databases have the same
+//! records, 1 if they differ and 2 on errors.
+
+use std::process::exit;
+use trivialdb::{diff, Flags, Tdb, O_RDONLY};
+
+fn open(path: &str) -> Tdb {
+    Tdb::open(path, None, Flags::empty(), O_RDONLY, 0).unwrap_or_else(|| {
+        eprintln!("Failed to open {}", path);
+        exit(2)
+    })
+}
+
+fn cmd_diff(args: &[String]) -> i32 {
+    let [old, new] = args else {
+        eprintln!("Usage: tdbtool diff OLD NEW");
+        return 2;
+    };
+    match diff(&open(old), &open(new)) {
+        Ok(changes) => {
+            for change in &changes {
+                print!("{}", change);
+            }
+            if changes.is_empty() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to compare {} and {}: {}", old, new, e);
+            2
+        }
+    }
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let status = match args.get(1).map(String::as_str) {
+        Some("diff") => cmd_diff(&args[2..]),
+        _ => {
+            eprintln!("Usage: tdbtool diff OLD NEW");
+            2
+        }
+    };
+    exit(status)
+}
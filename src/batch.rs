@@ -0,0 +1,359 @@
+//! Applying many changes to a database at once.
+//!
+//! A [`WriteBatch`] collects puts, deletes and appends, which [`Tdb::write`] then applies
+//! atomically: either all of them or none.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb, WriteBatch, O_CREAT, O_RDWR};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("test.tdb");
+//! let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+//! tdb.store(b"stale", b"1", None).unwrap();
+//!
+//! let mut batch = WriteBatch::new();
+//! for i in 0..100u32 {
+//!     batch.put(&i.to_le_bytes(), b"value");
+//! }
+//! batch.delete(b"stale");
+//! batch.append(b"log", b"loaded 100 keys\n");
+//! tdb.write(&batch).unwrap();
+//!
+//! assert!(!tdb.exists(b"stale"));
+//! assert_eq!(tdb.keys().count(), 101);
+//! ```
+
+use crate::{Error, Flags, StoreFlags, Tdb};
+use std::collections::HashSet;
+
+/// An operation in a [`WriteBatch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Store a value, as [`Tdb::store`] does.
+    Put {
+        /// The key to store.
+        key: Vec<u8>,
+        /// The value to store.
+        value: Vec<u8>,
+        /// Whether the key must or must not exist already.
+        flags: Option<StoreFlags>,
+    },
+    /// Delete a key, if it exists.
+    Delete {
+        /// The key to delete.
+        key: Vec<u8>,
+    },
+    /// Append to the value of a key, creating it if it does not exist.
+    Append {
+        /// The key to append to.
+        key: Vec<u8>,
+        /// The data to append.
+        value: Vec<u8>,
+    },
+}
+
+impl BatchOp {
+    /// The key the operation changes.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } | BatchOp::Append { key, .. } => key,
+        }
+    }
+
+    fn apply(&self, tdb: &mut Tdb) -> Result<(), Error> {
+        match self {
+            BatchOp::Put { key, value, flags } => tdb.store(key, value, *flags),
+            BatchOp::Delete { key } => match tdb.delete(key) {
+                Err(Error::NoExist) => Ok(()),
+                ret => ret,
+            },
+            BatchOp::Append { key, value } => tdb.append(key, value),
+        }
+    }
+}
+
+/// A list of changes to apply atomically with [`Tdb::write`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+    order_by_chain: bool,
+}
+
+impl WriteBatch {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `value` under `key`, replacing any existing value.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.store(key, value, None)
+    }
+
+    /// Store `value` under `key`, failing the batch if `flags` don't allow it, e.g. if the key
+    /// exists and `flags` is [`StoreFlags::Insert`].
+    pub fn store(&mut self, key: &[u8], value: &[u8], flags: Option<StoreFlags>) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            flags,
+        });
+        self
+    }
+
+    /// Delete `key`. Deleting a key that does not exist is not an error.
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+
+    /// Append `value` to the value of `key`.
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Append {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    /// Apply the operations one hash chain after the other, rather than in the order they were
+    /// added.
+    ///
+    /// This makes large batches touch the file in fewer places. Operations on the same key are
+    /// still applied in the order they were added.
+    pub fn order_by_chain(&mut self, order: bool) -> &mut Self {
+        self.order_by_chain = order;
+        self
+    }
+
+    /// The operations, in the order they were added.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// The number of operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Remove all operations, so the batch can be reused.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+/// The error returned by [`Tdb::write`].
+#[derive(Debug)]
+pub struct BatchError {
+    /// The index of the operation that failed, in the order they were added to the batch, or
+    /// `None` if the batch could not be started or committed.
+    pub op: Option<usize>,
+    /// Why it failed.
+    pub error: Error,
+}
+
+impl From<Error> for BatchError {
+    fn from(error: Error) -> Self {
+        BatchError { op: None, error }
+    }
+}
+
+impl Tdb {
+    /// The operations of `batch` with their indices, in the order to apply them.
+    fn batch_order<'a>(&self, batch: &'a WriteBatch) -> Result<Vec<(usize, &'a BatchOp)>, Error> {
+        let mut ops = batch.ops.iter().enumerate().collect::<Vec<_>>();
+        if batch.order_by_chain {
+            let hash = self.hash_function();
+            let hash_size = self.hash_size();
+            // The sort is stable, so operations on the same key keep their order.
+            ops.sort_by_cached_key(|(_, op)| hash.hash(op.key()) % hash_size);
+        }
+        Ok(ops)
+    }
+
+    /// Apply all operations of a batch atomically.
+    ///
+    /// File databases apply the batch in a single transaction. Memory databases do not support
+    /// transactions, so they are locked for the duration instead, and the changes made before a
+    /// failing operation are undone.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The operations to apply.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - All operations were applied.
+    /// * `Err(e)` - None of them were applied; `e.op` is the operation that failed.
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<(), BatchError> {
+        let ops = self.batch_order(batch)?;
+        let memory = self.get_flags().contains(Flags::Internal);
+        let mut failed = None;
+        self.with_transaction(|tdb| {
+            // The previous values of the keys changed so far, to undo them on memory databases.
+            let mut undo = Vec::new();
+            let mut seen = HashSet::new();
+            for (i, op) in ops {
+                let saved = if memory && seen.insert(op.key()) {
+                    tdb.fetch(op.key()).map(|old| undo.push((op.key(), old)))
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = saved.and_then(|()| op.apply(tdb)) {
+                    failed = Some(i);
+                    for (key, value) in undo.into_iter().rev() {
+                        let _ = match value {
+                            Some(value) => tdb.store(key, &value, None),
+                            None => tdb.delete(key),
+                        };
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })
+        .map_err(|error| BatchError { op: failed, error })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{O_CREAT, O_RDWR};
+
+    fn open(path: &std::path::Path) -> Tdb {
+        Tdb::open(path, Some(7), Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap()
+    }
+
+    fn contents(tdb: &Tdb) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut contents = tdb.iter().collect::<Vec<_>>();
+        contents.sort();
+        contents
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = open(&dir.path().join("test.tdb"));
+        tdb.store(b"a", b"old", None).unwrap();
+        tdb.store(b"b", b"1", None).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a", b"new")
+            .delete(b"b")
+            .delete(b"missing")
+            .append(b"c", b"x")
+            .append(b"c", b"y");
+        assert_eq!(batch.len(), 5);
+        tdb.write(&batch).unwrap();
+        assert_eq!(
+            contents(&tdb),
+            vec![
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"c".to_vec(), b"xy".to_vec())
+            ]
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+        tdb.write(&batch).unwrap();
+    }
+
+    #[test]
+    fn test_write_order_by_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = open(&dir.path().join("test.tdb"));
+        let mut batch = WriteBatch::new();
+        for i in 0..100u32 {
+            batch.put(&i.to_le_bytes(), b"first");
+            if i % 2 == 0 {
+                batch.delete(&i.to_le_bytes());
+            } else {
+                batch.append(&i.to_le_bytes(), b", second");
+            }
+        }
+        batch.order_by_chain(true);
+        let order = tdb.batch_order(&batch).unwrap();
+        let chains = order
+            .iter()
+            .map(|(_, op)| tdb.chain_of(op.key()))
+            .collect::<Vec<_>>();
+        assert!(chains.windows(2).all(|w| w[0] <= w[1]));
+
+        tdb.write(&batch).unwrap();
+        assert_eq!(tdb.keys().count(), 50);
+        assert_eq!(
+            tdb.fetch(&1u32.to_le_bytes()).unwrap().unwrap(),
+            b"first, second"
+        );
+    }
+
+    #[test]
+    fn test_write_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = open(&path);
+        tdb.store(b"a", b"old", None).unwrap();
+        tdb.store(b"b", b"1", None).unwrap();
+        let before = contents(&tdb);
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a", b"new")
+            .delete(b"b")
+            .put(b"c", b"3")
+            .store(b"b", b"2", Some(StoreFlags::Insert))
+            .store(b"a", b"newer", Some(StoreFlags::Insert));
+        let err = tdb.write(&batch).unwrap_err();
+        assert_eq!(err.op, Some(4));
+        assert!(matches!(err.error, Error::Exists));
+        assert_eq!(contents(&tdb), before);
+
+        // The failing operation is reported by its index in the batch, not the order it ran in.
+        batch.order_by_chain(true);
+        assert_eq!(tdb.write(&batch).unwrap_err().op, Some(4));
+        assert_eq!(contents(&tdb), before);
+    }
+
+    #[test]
+    fn test_write_readonly() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        open(&path).store(b"a", b"old", None).unwrap();
+        let mut tdb = Tdb::open(&path, None, Flags::empty(), crate::O_RDONLY, 0).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"new");
+        // The transaction can't be started, so no operation failed.
+        assert_eq!(tdb.write(&batch).unwrap_err().op, None);
+        assert_eq!(tdb.fetch(b"a").unwrap().unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_write_memory_rollback() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"a", b"old", None).unwrap();
+        tdb.store(b"b", b"1", None).unwrap();
+        let before = contents(&tdb);
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a", b"new")
+            .append(b"a", b"er")
+            .delete(b"b")
+            .put(b"c", b"3")
+            .store(b"a", b"newest", Some(StoreFlags::Insert));
+        let err = tdb.write(&batch).unwrap_err();
+        assert_eq!(err.op, Some(4));
+        assert_eq!(contents(&tdb), before);
+
+        // The database is unlocked again.
+        tdb.lockall_nonblock().unwrap();
+        tdb.unlockall().unwrap();
+    }
+}
@@ -0,0 +1,394 @@
+//! Populating a new database file quickly.
+//!
+//! Storing records one by one takes locks for every record and grows the file many times over.
+//! [`BulkLoader`] instead writes all records to a temporary file that no other process knows
+//! about, without locking or syncing, in a single transaction. The file is sized up front from
+//! the expected amount of data, synced once at the end, and renamed into place.
+//!
+//! ```rust
+//! use trivialdb::BulkLoader;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("test.tdb");
+//!
+//! let records = (0..10000u32).map(|i| (i.to_le_bytes(), format!("value {}", i)));
+//! let (tdb, stats) = BulkLoader::new(&path)
+//!     .expected_records(10000)
+//!     .expected_bytes(10000 * 14)
+//!     .load(records)
+//!     .unwrap();
+//! assert_eq!(stats.records, 10000);
+//! assert_eq!(tdb.fetch(&42u32.to_le_bytes()).unwrap().unwrap(), b"value 42");
+//! println!("{:.0} records/s", stats.records_per_sec());
+//! ```
+
+use crate::chains::hash_size_for;
+use crate::reader::RECORD_LEN;
+use crate::{Error, Flags, Tdb, O_CREAT, O_RDWR};
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The key of the record that reserves space in a new file.
+const PLACEHOLDER: &[u8] = b"\0trivialdb bulk load placeholder";
+
+/// Space taken up by each record on top of its key and value: the record header, the tailer
+/// that holds the record length, and padding.
+const RECORD_OVERHEAD: u64 = RECORD_LEN as u64 + 8;
+
+/// Allocations larger than this grow the file by twice their size, smaller ones by a hundred
+/// times their size; see `tdb_expand_adjust()` in libtdb's `common/io.c`.
+const LARGE_ALLOCATION: u64 = 100 * 1024;
+
+/// Loads records into a new database file, replacing any existing file.
+#[derive(Debug, Clone)]
+pub struct BulkLoader {
+    path: PathBuf,
+    tdb_flags: Flags,
+    mode: u32,
+    hash_size: Option<u32>,
+    expected_records: Option<u64>,
+    expected_bytes: Option<u64>,
+}
+
+/// Throughput of a [`BulkLoader::load`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadStats {
+    /// The number of records stored.
+    pub records: usize,
+    /// The total size of the keys and values stored.
+    pub bytes: u64,
+    /// How long loading took, including syncing the file.
+    pub elapsed: Duration,
+}
+
+impl LoadStats {
+    /// `count` divided by the time taken, or zero if no time was measured.
+    fn per_sec(&self, count: f64) -> f64 {
+        if self.elapsed.is_zero() {
+            0.0
+        } else {
+            count / self.elapsed.as_secs_f64()
+        }
+    }
+
+    /// The number of records stored per second, or zero if no time was measured.
+    pub fn records_per_sec(&self) -> f64 {
+        self.per_sec(self.records as f64)
+    }
+
+    /// The number of bytes of keys and values stored per second, or zero if no time was
+    /// measured.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.per_sec(self.bytes as f64)
+    }
+}
+
+impl BulkLoader {
+    /// Create a loader for the database file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        BulkLoader {
+            path: path.as_ref().to_path_buf(),
+            tdb_flags: Flags::empty(),
+            mode: 0o600,
+            hash_size: None,
+            expected_records: None,
+            expected_bytes: None,
+        }
+    }
+
+    /// The flags to open the database with once it is loaded. They are also used to create it,
+    /// e.g. to pick the hash function with [`Flags::IncompatibleHash`].
+    pub fn flags(&mut self, tdb_flags: Flags) -> &mut Self {
+        self.tdb_flags = tdb_flags;
+        self
+    }
+
+    /// The permissions of the new file. The default is `0o600`.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The hash size of the new file. The default is picked from
+    /// [`BulkLoader::expected_records`], as [`crate::ChainAnalysis::recommended_hash_size`]
+    /// does, or libtdb's default if that is not known either.
+    pub fn hash_size(&mut self, hash_size: u32) -> &mut Self {
+        self.hash_size = Some(hash_size);
+        self
+    }
+
+    /// The number of records that will be loaded.
+    pub fn expected_records(&mut self, records: u64) -> &mut Self {
+        self.expected_records = Some(records);
+        self
+    }
+
+    /// The total size of the keys and values that will be loaded.
+    pub fn expected_bytes(&mut self, bytes: u64) -> &mut Self {
+        self.expected_bytes = Some(bytes);
+        self
+    }
+
+    /// The number of bytes to reserve in the new file.
+    fn reserve(&self) -> u64 {
+        // Records are allocated with a quarter extra, so they can grow in place.
+        self.expected_bytes.unwrap_or(0) * 5 / 4
+            + self.expected_records.unwrap_or(0) * RECORD_OVERHEAD
+    }
+
+    /// Store `records` in a new file and move it into place.
+    ///
+    /// Later records replace earlier ones with the same key. Processes that have the old file
+    /// open keep using it until they reopen it.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The keys and values to store.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((tdb, stats))` - The loaded database, opened with the configured flags.
+    /// * `Err(Error::Invalid)` - The flags ask for a memory database, or for one that is
+    ///   cleared when it is opened.
+    /// * `Err(e)` - The file could not be created or written; any existing file is unchanged.
+    pub fn load<I, K, V>(&self, records: I) -> Result<(Tdb, LoadStats), Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self
+            .tdb_flags
+            .intersects(Flags::Internal | Flags::ClearIfFirst)
+        {
+            return Err(Error::Invalid);
+        }
+        let start = Instant::now();
+        let (tmp_path, tmp) = create_temp(&self.path, "load", self.mode)?;
+        drop(tmp);
+
+        let ret = self.load_into(&tmp_path, records).and_then(|stats| {
+            std::fs::rename(&tmp_path, &self.path).map_err(|_| Error::IO)?;
+            sync_parent(&self.path)?;
+            Ok(stats)
+        });
+        let mut stats = match ret {
+            Ok(stats) => stats,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+        let tdb = Tdb::open(&self.path, None, self.tdb_flags, O_RDWR, 0).ok_or(Error::IO)?;
+        stats.elapsed = start.elapsed();
+        Ok((tdb, stats))
+    }
+
+    /// Store `records` in the new, empty file at `path`.
+    fn load_into<I, K, V>(&self, path: &Path, records: I) -> Result<LoadStats, Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let hash_size = self.hash_size.or(self
+            .expected_records
+            .map(|records| hash_size_for(records as usize)));
+        // Nothing else has the file open, so it needs neither locks nor syncs until it is done.
+        // O_CREAT makes the empty file a new database.
+        let mut tdb = Tdb::open(
+            path,
+            hash_size,
+            self.tdb_flags | Flags::NoLock | Flags::NoSync,
+            O_RDWR | O_CREAT,
+            self.mode as _,
+        )
+        .ok_or(Error::IO)?;
+        reserve(&mut tdb, self.reserve())?;
+
+        let mut stats = LoadStats {
+            records: 0,
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        };
+        tdb.with_transaction(|tdb| {
+            for (key, value) in records {
+                let (key, value) = (key.as_ref(), value.as_ref());
+                tdb.store(key, value, None)?;
+                stats.records += 1;
+                stats.bytes += (key.len() + value.len()) as u64;
+            }
+            Ok(())
+        })?;
+        drop(tdb);
+        File::open(path)
+            .and_then(|f| f.sync_all())
+            .map_err(|_| Error::IO)?;
+        Ok(stats)
+    }
+}
+
+/// Grow the file by about `bytes`, and leave the space on the freelist.
+///
+/// The space is reserved by storing and then deleting a placeholder record, so how much the
+/// file grows follows from libtdb's allocation policy, which the native backend shares:
+///
+/// * `tdb_allocate_from_freelist()` in `common/freelist.c` allocates a quarter more than a
+///   record needs, so it can grow in place.
+/// * `tdb_expand_adjust()` in `common/io.c` grows a file without room for an allocation by
+///   twice its size, if it is larger than [`LARGE_ALLOCATION`].
+///
+/// A placeholder of two fifths of `bytes` therefore grows the file by `2 * 1.25 * 2/5 = 1`
+/// times `bytes`.
+fn reserve(tdb: &mut Tdb, bytes: u64) -> Result<(), Error> {
+    let len = bytes * 2 / 5;
+    // Smaller allocations grow the file by a hundred times their size, which is already more
+    // than reserving would.
+    if len <= LARGE_ALLOCATION {
+        return Ok(());
+    }
+    if len >= u32::MAX as u64 {
+        return Err(Error::OOM);
+    }
+    tdb.store(PLACEHOLDER, &vec![0; len as usize], None)?;
+    tdb.delete(PLACEHOLDER)
+}
+
+/// Create a new, empty file next to `path`, to be renamed over it once it is written.
+///
+/// The name of the file is `path` followed by `suffix` and a unique part, so callers working
+/// on the same path don't get in each other's way.
+pub(crate) fn create_temp(path: &Path, suffix: &str, mode: u32) -> Result<(PathBuf, File), Error> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    for _ in 0..100 {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(
+            ".{}.{}.{:x}{:08x}",
+            suffix,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos,
+        ));
+        let tmp_path = PathBuf::from(name);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp_path)
+        {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(_) => return Err(Error::IO),
+        }
+    }
+    Err(Error::IO)
+}
+
+/// Sync the directory containing `path`, so a rename into it is durable.
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|_| Error::IO)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn records(n: u32) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        (0..n).map(|i| (i.to_le_bytes().to_vec(), vec![i as u8; 200]))
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let (tdb, stats) = BulkLoader::new(&path)
+            .expected_records(2000)
+            .expected_bytes(2000 * 204)
+            .load(records(2000))
+            .unwrap();
+        assert_eq!(stats.records, 2000);
+        assert_eq!(stats.bytes, 2000 * 204);
+        assert!(stats.records_per_sec() > 0.0);
+        assert_eq!(tdb.hash_size(), 2003);
+        assert_eq!(tdb.keys().count(), 2000);
+        assert_eq!(tdb.fetch(&7u32.to_le_bytes()).unwrap().unwrap(), [7; 200]);
+        assert!(!tdb.exists(PLACEHOLDER));
+        // The locking and syncing flags only applied while loading.
+        assert!(!tdb.get_flags().intersects(Flags::NoLock | Flags::NoSync));
+        // The temporary file is gone.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // The reserved space was used rather than growing the file further.
+        let reserved = 2000 * 204 * 5 / 4 + 2000 * RECORD_OVERHEAD;
+        assert!((tdb.map_size() as u64) < reserved + reserved / 20 + 16384);
+        assert!(tdb.layout().unwrap().free_bytes() < reserved / 20);
+    }
+
+    #[test]
+    fn test_load_replaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut old = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        old.store(b"old", b"1", None).unwrap();
+        drop(old);
+
+        let (tdb, stats) = BulkLoader::new(&path)
+            .flags(Flags::IncompatibleHash)
+            .load([(b"new", b"1"), (b"new", b"2")])
+            .unwrap();
+        assert_eq!(stats.records, 2);
+        assert_eq!(tdb.keys().collect::<Vec<_>>(), vec![b"new".to_vec()]);
+        assert_eq!(tdb.fetch(b"new").unwrap().unwrap(), b"2");
+        assert_eq!(tdb.hash_size(), 131);
+        assert_eq!(tdb.hash_function(), crate::reader::HashFunction::Jenkins);
+    }
+
+    #[test]
+    fn test_temp_files_are_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let (first, _) = create_temp(&path, "load", 0o600).unwrap();
+        let (second, _) = create_temp(&path, "load", 0o600).unwrap();
+        assert_ne!(first, second);
+        assert!(first.exists() && second.exists());
+        assert_eq!(first.parent(), Some(dir.path()));
+    }
+
+    #[test]
+    fn test_stats_without_time() {
+        let stats = LoadStats {
+            records: 10,
+            bytes: 100,
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(stats.records_per_sec(), 0.0);
+        assert_eq!(stats.bytes_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        for flags in [Flags::Internal, Flags::ClearIfFirst] {
+            let ret = BulkLoader::new(&path).flags(flags).load(records(10));
+            assert!(matches!(ret, Err(Error::Invalid)));
+        }
+        // A file that can't be created leaves nothing behind.
+        let missing = dir.path().join("missing").join("test.tdb");
+        assert!(BulkLoader::new(&missing).load(records(10)).is_err());
+        assert!(!path.exists());
+    }
+}
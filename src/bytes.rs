@@ -0,0 +1,160 @@
+//! Fetching and storing values as [`Bytes`] and [`Buf`] without copying them.
+//!
+//! ```rust
+//! use bytes::{Buf, Bytes};
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+//! let header = Bytes::from_static(b"HTTP/1.1 200 OK\r\n\r\n");
+//! let body = Bytes::from_static(b"hello");
+//! tdb.store_buf(b"response", header.chain(body), None).unwrap();
+//!
+//! let value: Bytes = tdb.fetch_bytes(b"response").unwrap().unwrap();
+//! assert!(value.ends_with(b"\r\n\r\nhello"));
+//! ```
+
+use crate::{Error, StoreFlags, Tdb};
+use ::bytes::{Buf, Bytes};
+use std::io::IoSlice;
+
+/// Call `f` with the chunks of `buf`.
+///
+/// Buffers that don't expose all their chunks at once are copied into a single chunk.
+fn with_chunks<R>(mut buf: impl Buf, f: impl FnOnce(&[&[u8]]) -> R) -> R {
+    let mut iov = vec![IoSlice::new(&[]); 16];
+    loop {
+        let n = buf.chunks_vectored(&mut iov);
+        if iov[..n].iter().map(|chunk| chunk.len()).sum::<usize>() == buf.remaining() {
+            let chunks = iov[..n].iter().map(|chunk| &**chunk).collect::<Vec<_>>();
+            return f(&chunks);
+        }
+        if n < iov.len() {
+            break;
+        }
+        // There may be more chunks than slots.
+        iov = vec![IoSlice::new(&[]); iov.len() * 2];
+    }
+    let data = buf.copy_to_bytes(buf.remaining());
+    f(&[&data])
+}
+
+impl Tdb {
+    /// Fetch a value from the database as [`Bytes`].
+    ///
+    /// With libtdb, the returned value owns the buffer libtdb allocated for it rather than a
+    /// copy, and frees it when the last clone is dropped.
+    ///
+    /// # Arguments
+    /// * `key` - The key to fetch.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found.
+    /// * `Err(e)` - An error occurred.
+    pub fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Bytes>, Error> {
+        self.0.fetch_bytes(key)
+    }
+
+    /// Store a value from a [`Buf`], passing its chunks to libtdb as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `value` - The value to store.
+    /// * `flags` - As for [`Tdb::store`].
+    pub fn store_buf(
+        &mut self,
+        key: &[u8],
+        value: impl Buf,
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        with_chunks(value, |chunks| self.storev(key, chunks, flags))
+    }
+
+    /// Append the contents of a [`Buf`] to the value of a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to append to.
+    /// * `value` - The data to append.
+    pub fn append_buf(&mut self, key: &[u8], value: impl Buf) -> Result<(), Error> {
+        with_chunks(value, |chunks| match chunks {
+            [chunk] => self.append(key, chunk),
+            chunks => self.with_chainlock(key, |tdb| {
+                let old = tdb.fetch_bytes(key)?.unwrap_or_default();
+                let mut vals = vec![&old[..]];
+                vals.extend_from_slice(chunks);
+                tdb.storev(key, &vals, None)
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    #[test]
+    fn test_fetch_bytes() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"key", b"value", None).unwrap();
+        tdb.store(b"empty", b"", None).unwrap();
+        assert_eq!(tdb.fetch_bytes(b"missing").unwrap(), None);
+        assert_eq!(tdb.fetch_bytes(b"empty").unwrap().unwrap(), Bytes::new());
+
+        let value = tdb.fetch_bytes(b"key").unwrap().unwrap();
+        // The value outlives the database, and slices outlive the value.
+        drop(tdb);
+        let slice = value.slice(1..3);
+        assert_eq!(value, Bytes::from_static(b"value"));
+        drop(value);
+        assert_eq!(slice, Bytes::from_static(b"al"));
+    }
+
+    #[test]
+    fn test_store_buf() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        let parts = (0..40u8).map(|i| Bytes::from(vec![i; 3]));
+        let chained = parts.fold(Box::new(Bytes::new()) as Box<dyn Buf>, |buf, part| {
+            Box::new(buf.chain(part))
+        });
+        tdb.store_buf(b"chained", chained, None).unwrap();
+        let expected = (0..40u8).flat_map(|i| [i; 3]).collect::<Vec<_>>();
+        assert_eq!(tdb.fetch(b"chained").unwrap().unwrap(), expected);
+
+        // A partially consumed buffer stores what remains.
+        let mut buf = Bytes::from_static(b"skip:value").chain(&b"!"[..]);
+        buf.advance(5);
+        tdb.store_buf(b"advanced", buf, None).unwrap();
+        assert_eq!(tdb.fetch(b"advanced").unwrap().unwrap(), b"value!");
+
+        // A buffer that only exposes one chunk at a time is copied.
+        let mut deque = std::collections::VecDeque::with_capacity(8);
+        deque.extend(b"xxxxabc");
+        deque.drain(..4);
+        deque.extend(b"defgh");
+        tdb.store_buf(b"deque", deque, None).unwrap();
+        assert_eq!(tdb.fetch(b"deque").unwrap().unwrap(), b"abcdefgh");
+
+        assert!(matches!(
+            tdb.store_buf(b"advanced", &b"new"[..], Some(StoreFlags::Insert)),
+            Err(Error::Exists)
+        ));
+        tdb.store_buf(b"empty", Bytes::new(), None).unwrap();
+        assert_eq!(tdb.fetch(b"empty").unwrap().unwrap(), b"");
+    }
+
+    #[test]
+    fn test_append_buf() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.append_buf(b"key", &b"a"[..]).unwrap();
+        tdb.append_buf(b"key", (&b"bc"[..]).chain(&b"de"[..]))
+            .unwrap();
+        tdb.append_buf(b"new", (&b"x"[..]).chain(&b"y"[..]))
+            .unwrap();
+        assert_eq!(tdb.fetch(b"key").unwrap().unwrap(), b"abcde");
+        assert_eq!(tdb.fetch(b"new").unwrap().unwrap(), b"xy");
+    }
+}
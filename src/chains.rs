@@ -0,0 +1,247 @@
+//! Analysing the distribution of records over hash chains, and rebuilding a database with a
+//! different hash size.
+//!
+//! TDB looks records up by walking the linked list of the hash chain their key hashes to, so
+//! a database that has outgrown its hash size gets slower with every record. libtdb can't
+//! change the hash size of an existing file; [`rehash`] rebuilds it instead.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let mut tdb = Tdb::memory(Some(7), Flags::empty()).unwrap();
+//! for i in 0..100u32 {
+//!     tdb.store(&i.to_le_bytes(), b"value", None).unwrap();
+//! }
+//! let analysis = tdb.analyze_chains().unwrap();
+//! assert_eq!(analysis.records(), 100);
+//! assert!(analysis.max_length() >= 15);
+//! assert!(analysis.recommended_hash_size() >= 100);
+//! ```
+
+use crate::bulk::create_temp;
+use crate::reader::HashFunction;
+use crate::{Error, Flags, Tdb, O_CREAT, O_RDWR};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The hash size libtdb uses when none is given.
+const DEFAULT_HASH_SIZE: u32 = 131;
+
+/// The distribution of the records of a database over its hash chains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainAnalysis {
+    /// The hash function the database uses.
+    pub hash: HashFunction,
+    /// The number of records in each hash chain, indexed by chain.
+    ///
+    /// Only live records are counted; deleted records that are still in a chain are not.
+    pub chain_lengths: Vec<usize>,
+}
+
+fn is_prime(n: u32) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|&d| d <= n / d)
+            .all(|d| !n.is_multiple_of(d))
+}
+
+/// The smallest prime hash size that is at least `records`, and no smaller than libtdb's
+/// default of 131.
+pub(crate) fn hash_size_for(records: usize) -> u32 {
+    let target = u32::try_from(records)
+        .unwrap_or(u32::MAX)
+        .max(DEFAULT_HASH_SIZE);
+    (target..).find(|&n| is_prime(n)).unwrap_or(target)
+}
+
+impl ChainAnalysis {
+    /// The number of hash chains.
+    pub fn hash_size(&self) -> u32 {
+        self.chain_lengths.len() as u32
+    }
+
+    /// The number of records.
+    pub fn records(&self) -> usize {
+        self.chain_lengths.iter().sum()
+    }
+
+    /// The length of the longest hash chain.
+    pub fn max_length(&self) -> usize {
+        self.chain_lengths.iter().copied().max().unwrap_or(0)
+    }
+
+    /// The average length of the hash chains.
+    pub fn mean_length(&self) -> f64 {
+        self.records() as f64 / self.chain_lengths.len().max(1) as f64
+    }
+
+    /// The number of hash chains without any records.
+    pub fn empty_chains(&self) -> usize {
+        self.chain_lengths.iter().filter(|&&n| n == 0).count()
+    }
+
+    /// A hash size that gives an average chain length of at most one for the current number
+    /// of records: the smallest prime at least as large as that, and no smaller than libtdb's
+    /// default of 131.
+    pub fn recommended_hash_size(&self) -> u32 {
+        hash_size_for(self.records())
+    }
+}
+
+impl Tdb {
+    /// Work out how the records are distributed over the hash chains.
+    ///
+    /// Every key is hashed with the hash function of the database during a read traversal.
+    pub fn analyze_chains(&self) -> Result<ChainAnalysis, Error> {
+        let hash = self.hash_function();
+        let hash_size = self.hash_size();
+        let mut chain_lengths = vec![0; hash_size as usize];
+        self.traverse_read(|key, _| {
+            chain_lengths[(hash.hash(key) % hash_size) as usize] += 1;
+            true
+        })?;
+        Ok(ChainAnalysis {
+            hash,
+            chain_lengths,
+        })
+    }
+}
+
+/// Rebuild a database file with a new hash size, and atomically replace the original with it.
+///
+/// The records are copied into a temporary file next to the original, which is then renamed
+/// over it. The original is locked for writing until the rename, so other processes can't
+/// modify it in the meantime.
+///
+/// Handles that other processes opened before the rename still refer to the old file, even
+/// after [`Tdb::reopen`], which reopens the same file. Every other opener must close the
+/// database and open it again; until it does, its writes go to the old file and are lost.
+///
+/// # Arguments
+///
+/// * `path` - The database file.
+/// * `new_hash_size` - The hash size of the new file.
+/// * `incompatible_hash` - Whether the new file uses the Jenkins hash
+///   ([`Flags::IncompatibleHash`]) rather than the old default hash.
+pub fn rehash<P: AsRef<Path>>(
+    path: P,
+    new_hash_size: u32,
+    incompatible_hash: bool,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    if new_hash_size == 0 {
+        return Err(Error::Invalid);
+    }
+    let mode = std::fs::metadata(path)
+        .map_err(|_| Error::IO)?
+        .permissions()
+        .mode()
+        & 0o7777;
+    let src = Tdb::open(path, None, Flags::empty(), O_RDWR, 0).ok_or(Error::IO)?;
+
+    let flags = if incompatible_hash {
+        Flags::IncompatibleHash
+    } else {
+        Flags::empty()
+    };
+
+    src.lockall()?;
+    let ret = create_temp(path, "rehash", mode).and_then(|(tmp_path, tmp)| {
+        drop(tmp);
+        // O_CREAT makes the empty file a new database.
+        let ret = Tdb::open(&tmp_path, Some(new_hash_size), flags, O_RDWR | O_CREAT, 0)
+            .ok_or(Error::IO)
+            .and_then(|mut dest| src.copy_to(&mut dest))
+            .and_then(|_| std::fs::rename(&tmp_path, path).map_err(|_| Error::IO));
+        if ret.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        ret
+    });
+    src.unlockall()?;
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recommended_hash_size() {
+        let analysis = |records| ChainAnalysis {
+            hash: HashFunction::Old,
+            chain_lengths: vec![records],
+        };
+        assert_eq!(analysis(0).recommended_hash_size(), 131);
+        assert_eq!(analysis(131).recommended_hash_size(), 131);
+        assert_eq!(analysis(132).recommended_hash_size(), 137);
+        assert_eq!(analysis(10000).recommended_hash_size(), 10007);
+    }
+
+    #[test]
+    fn test_is_prime() {
+        assert!(!is_prime(1));
+        assert!(is_prime(2));
+        assert!(!is_prime(65536));
+        assert!(is_prime(65521));
+        // Near u32::MAX, squaring the divisor would overflow.
+        assert!(is_prime(u32::MAX - 4));
+        assert!(!is_prime(u32::MAX));
+        assert_eq!(hash_size_for(u32::MAX as usize - 10), u32::MAX - 4);
+    }
+
+    #[test]
+    fn test_analyze_chains() {
+        let mut tdb = Tdb::memory(Some(3), Flags::IncompatibleHash).unwrap();
+        for key in [&b"a"[..], b"b", b"c", b"d"] {
+            tdb.store(key, b"", None).unwrap();
+        }
+        let analysis = tdb.analyze_chains().unwrap();
+        assert_eq!(analysis.hash, HashFunction::Jenkins);
+        let mut expected = vec![0; 3];
+        for key in [&b"a"[..], b"b", b"c", b"d"] {
+            expected[(crate::jenkins_hash(key) % 3) as usize] += 1;
+        }
+        assert_eq!(analysis.chain_lengths, expected);
+        assert_eq!(analysis.hash_size(), 3);
+        assert_eq!(analysis.records(), 4);
+        assert_eq!(analysis.mean_length(), 4.0 / 3.0);
+    }
+
+    #[test]
+    fn test_rehash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = Tdb::open(&path, Some(7), Flags::empty(), O_RDWR | O_CREAT, 0o640).unwrap();
+        for i in 0..200u32 {
+            tdb.store(&i.to_le_bytes(), &i.to_be_bytes(), None).unwrap();
+        }
+        drop(tdb);
+
+        // Temporary files are unique, so they don't replace files that happen to be there.
+        let stale = dir.path().join("test.tdb.rehash");
+        std::fs::write(&stale, b"stale").unwrap();
+        rehash(&path, 211, true).unwrap();
+        assert_eq!(std::fs::read(&stale).unwrap(), b"stale");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        let tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR, 0).unwrap();
+        assert_eq!(tdb.hash_size(), 211);
+        assert_eq!(tdb.iter().count(), 200);
+        for i in 0..200u32 {
+            assert_eq!(
+                tdb.fetch(&i.to_le_bytes()).unwrap().unwrap(),
+                i.to_be_bytes()
+            );
+        }
+        let analysis = tdb.analyze_chains().unwrap();
+        assert_eq!(analysis.hash, HashFunction::Jenkins);
+        assert!(analysis.max_length() < 10);
+
+        assert!(matches!(rehash(&path, 0, false), Err(Error::Invalid)));
+        assert!(rehash(dir.path().join("missing.tdb"), 131, false).is_err());
+    }
+}
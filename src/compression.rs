@@ -0,0 +1,293 @@
+//! Transparent compression of values with zstd.
+
+use crate::{Error, StoreFlags, Tdb};
+
+/// Header byte for values stored as-is.
+const RAW: u8 = 0;
+/// Header byte for values compressed with zstd.
+const ZSTD: u8 = 1;
+
+/// Values smaller than this are not worth compressing by default.
+const DEFAULT_THRESHOLD: usize = 256;
+
+/// Statistics about the values written through a [`CompressedTdb`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Number of values that were stored compressed.
+    pub compressed: u64,
+    /// Number of values that were stored raw, because they were below the threshold or did
+    /// not shrink when compressed.
+    pub raw: u64,
+    /// Total size of the values before compression.
+    pub bytes_in: u64,
+    /// Total size of the values as stored, including the header byte.
+    pub bytes_out: u64,
+}
+
+impl CompressionStats {
+    /// Return the ratio of stored bytes to original bytes, e.g. `0.1` for 10x compression.
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_in == 0 {
+            1.0
+        } else {
+            self.bytes_out as f64 / self.bytes_in as f64
+        }
+    }
+}
+
+/// A database that compresses values on the way in and decompresses them on the way out.
+///
+/// Every value is stored with a one-byte header that says whether it is compressed, so
+/// compressed and uncompressed records can coexist. Values below a size threshold, and values
+/// that do not get any smaller, are stored raw.
+///
+/// Keys are not compressed, so lookups and iteration order are unaffected.
+///
+/// ```rust
+/// use trivialdb::{CompressedTdb, Flags, Tdb};
+///
+/// let mut db = CompressedTdb::new(Tdb::memory(None, Flags::empty()).unwrap());
+/// let doc = br#"{"name": "value"}"#.repeat(100);
+/// db.store(b"doc", &doc, None).unwrap();
+/// assert_eq!(db.fetch(b"doc").unwrap().unwrap(), doc);
+/// assert!(db.stats().ratio() < 0.5);
+/// ```
+pub struct CompressedTdb {
+    tdb: Tdb,
+    threshold: usize,
+    level: i32,
+    stats: CompressionStats,
+}
+
+/// Decode a stored record into the original value.
+fn decode(record: &[u8]) -> Result<Vec<u8>, Error> {
+    match record.split_first() {
+        Some((&RAW, val)) => Ok(val.to_vec()),
+        Some((&ZSTD, val)) => zstd::decode_all(val).map_err(|_| Error::Corrupt),
+        _ => Err(Error::Corrupt),
+    }
+}
+
+impl CompressedTdb {
+    /// Wrap a database.
+    pub fn new(tdb: Tdb) -> Self {
+        CompressedTdb {
+            tdb,
+            threshold: DEFAULT_THRESHOLD,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    /// Set the size in bytes below which values are stored uncompressed.
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    /// Set the zstd compression level.
+    pub fn set_level(&mut self, level: i32) {
+        self.level = level;
+    }
+
+    /// Return statistics about the values stored so far.
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Reset the compression statistics.
+    pub fn reset_stats(&mut self) {
+        self.stats = CompressionStats::default();
+    }
+
+    /// Return a reference to the underlying database.
+    pub fn tdb(&self) -> &Tdb {
+        &self.tdb
+    }
+
+    /// Return the underlying database.
+    pub fn into_inner(self) -> Tdb {
+        self.tdb
+    }
+
+    /// Fetch a value from the database, decompressing it if necessary.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found.
+    /// * `Err(Error::Corrupt)` - The record has an unknown header or does not decompress.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.tdb
+            .fetch(key)?
+            .map(|record| decode(&record))
+            .transpose()
+    }
+
+    /// Store a key/value pair in the database, compressing the value if worthwhile.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `val` - The value to store.
+    /// * `flags` - The flags to use when storing the value.
+    pub fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        let record = encode(val, self.threshold, self.level, &mut self.stats)?;
+        self.tdb.store(key, &record, flags)
+    }
+
+    /// Append a value to an existing key.
+    ///
+    /// Compressed values can't be appended to in place, so the existing value is read,
+    /// extended and stored again under the chain lock of the key.
+    ///
+    /// # Arguments
+    /// * `key` - The key to append to.
+    /// * `val` - The value to append.
+    pub fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        let CompressedTdb {
+            tdb,
+            threshold,
+            level,
+            stats,
+        } = self;
+        tdb.with_chainlock(key, |tdb| {
+            let mut new = match tdb.fetch(key)? {
+                Some(record) => decode(&record)?,
+                None => Vec::new(),
+            };
+            new.extend_from_slice(val);
+            let record = encode(&new, *threshold, *level, stats)?;
+            tdb.store(key, &record, None)
+        })
+    }
+
+    /// Delete a key from the database.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.tdb.delete(key)
+    }
+
+    /// Check if a particular key exists
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.tdb.exists(key)
+    }
+}
+
+/// Encode a value for storage, compressing it if it is large enough and actually shrinks.
+fn encode(
+    val: &[u8],
+    threshold: usize,
+    level: i32,
+    stats: &mut CompressionStats,
+) -> Result<Vec<u8>, Error> {
+    let mut record = Vec::with_capacity(val.len() + 1);
+    if val.len() >= threshold {
+        let compressed = zstd::bulk::compress(val, level).map_err(|_| Error::IO)?;
+        if compressed.len() < val.len() {
+            record.push(ZSTD);
+            record.extend_from_slice(&compressed);
+        }
+    }
+    if record.is_empty() {
+        record.push(RAW);
+        record.extend_from_slice(val);
+        stats.raw += 1;
+    } else {
+        stats.compressed += 1;
+    }
+    stats.bytes_in += val.len() as u64;
+    stats.bytes_out += record.len() as u64;
+    Ok(record)
+}
+
+impl From<Tdb> for CompressedTdb {
+    fn from(tdb: Tdb) -> Self {
+        CompressedTdb::new(tdb)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    fn db() -> CompressedTdb {
+        CompressedTdb::new(Tdb::memory(None, Flags::empty()).unwrap())
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut db = db();
+        let large = b"0123456789".repeat(100);
+
+        db.store(b"small", b"tiny", None).unwrap();
+        db.store(b"large", &large, None).unwrap();
+
+        assert_eq!(db.tdb().fetch(b"small").unwrap().unwrap(), b"\0tiny");
+        let stored = db.tdb().fetch(b"large").unwrap().unwrap();
+        assert_eq!(stored[0], ZSTD);
+        assert!(stored.len() < large.len() / 10);
+
+        assert_eq!(db.fetch(b"small").unwrap().unwrap(), b"tiny");
+        assert_eq!(db.fetch(b"large").unwrap().unwrap(), large);
+        assert_eq!(db.fetch(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let mut db = db();
+        db.set_threshold(0);
+        // A value that zstd can't shrink is stored raw.
+        let val: Vec<u8> = (0..=255u8).collect();
+        db.store(b"key", &val, None).unwrap();
+        assert_eq!(db.tdb().fetch(b"key").unwrap().unwrap()[0], RAW);
+        assert_eq!(db.fetch(b"key").unwrap().unwrap(), val);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut db = db();
+        db.set_threshold(16);
+        db.append(b"log", b"first line\n").unwrap();
+        for _ in 0..10 {
+            db.append(b"log", b"another line\n").unwrap();
+        }
+        let expected = [b"first line\n".to_vec(), b"another line\n".repeat(10)].concat();
+        assert_eq!(db.fetch(b"log").unwrap().unwrap(), expected);
+        assert_eq!(db.tdb().fetch(b"log").unwrap().unwrap()[0], ZSTD);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut db = db();
+        let large = b"abc".repeat(1000);
+        db.store(b"small", b"tiny", None).unwrap();
+        db.store(b"large", &large, None).unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.raw, 1);
+        assert_eq!(stats.compressed, 1);
+        assert_eq!(stats.bytes_in, 4 + 3000);
+        assert!(stats.bytes_out < 100);
+        assert!(stats.ratio() < 0.05);
+
+        db.reset_stats();
+        assert_eq!(db.stats(), CompressionStats::default());
+        assert_eq!(db.stats().ratio(), 1.0);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut db = db();
+        db.tdb.store(b"unknown", b"\x07data", None).unwrap();
+        db.tdb.store(b"empty", b"", None).unwrap();
+        db.tdb.store(b"garbage", b"\x01not zstd", None).unwrap();
+        assert!(matches!(db.fetch(b"unknown"), Err(Error::Corrupt)));
+        assert!(matches!(db.fetch(b"empty"), Err(Error::Corrupt)));
+        assert!(matches!(db.fetch(b"garbage"), Err(Error::Corrupt)));
+    }
+}
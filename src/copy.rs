@@ -0,0 +1,189 @@
+//! Copying records between databases, e.g. to persist a memory database or to load a file into
+//! memory.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("staged.tdb");
+//!
+//! let mut staging = Tdb::memory(None, Flags::empty()).unwrap();
+//! staging.store(b"key", b"value", None).unwrap();
+//! staging.persist(&path).unwrap();
+//!
+//! let loaded = Tdb::load_into_memory(&path).unwrap();
+//! assert_eq!(loaded.fetch(b"key").unwrap().unwrap(), b"value");
+//! ```
+
+use crate::reader::HashFunction;
+use crate::{Error, Flags, Tdb, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC};
+use std::path::Path;
+
+impl Tdb {
+    /// The flags for a new database that hashes records the same way as this one.
+    fn copy_flags(&self) -> Result<Flags, Error> {
+        Ok(if self.hash_function() == HashFunction::Jenkins {
+            Flags::IncompatibleHash
+        } else {
+            Flags::empty()
+        })
+    }
+
+    /// Copy all records into another database.
+    ///
+    /// The records are written in a single transaction on `dest`, so either all of them or none
+    /// are stored. Existing records in `dest` with the same keys are replaced; other records
+    /// are left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The database to copy the records into.
+    ///
+    /// # Returns
+    ///
+    /// The number of records copied.
+    pub fn copy_to(&self, dest: &mut Tdb) -> Result<usize, Error> {
+        dest.with_transaction(|dest| {
+            let mut ret = Ok(());
+            let count = self.traverse_read(|key, val| {
+                ret = dest.store(key, val, None);
+                ret.is_ok()
+            })?;
+            ret.map(|_| count)
+        })
+    }
+
+    /// Load a database file into a new memory database.
+    ///
+    /// The memory database has the same hash size and hash function as the file, which is
+    /// only locked for reading while it is copied.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The database file to load.
+    pub fn load_into_memory<P: AsRef<Path>>(path: P) -> Result<Tdb, Error> {
+        let src = Tdb::open(path.as_ref(), None, Flags::empty(), O_RDONLY, 0).ok_or(Error::IO)?;
+        let mut dest = Tdb::memory(Some(src.hash_size()), src.copy_flags()?).ok_or(Error::OOM)?;
+        src.copy_to(&mut dest)?;
+        Ok(dest)
+    }
+
+    /// Write all records to a new database file.
+    ///
+    /// Any existing file at `path` is replaced. The new database has the same hash size and
+    /// hash function as this one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where to create the database file.
+    ///
+    /// # Returns
+    ///
+    /// The new database, open for reading and writing.
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> Result<Tdb, Error> {
+        let mut dest = Tdb::open(
+            path.as_ref(),
+            Some(self.hash_size()),
+            self.copy_flags()?,
+            O_RDWR | O_CREAT | O_TRUNC,
+            0o600,
+        )
+        .ok_or(Error::IO)?;
+        self.copy_to(&mut dest)?;
+        Ok(dest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Flags, Tdb, O_CREAT, O_RDWR};
+
+    fn populated(hash_size: Option<u32>, flags: Flags) -> Tdb {
+        let mut tdb = Tdb::memory(hash_size, flags).unwrap();
+        for i in 0..100u32 {
+            tdb.store(format!("key{}", i).as_bytes(), &i.to_le_bytes(), None)
+                .unwrap();
+        }
+        tdb
+    }
+
+    fn sorted(tdb: &Tdb) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records = tdb.iter().collect::<Vec<_>>();
+        records.sort();
+        records
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let src = populated(None, Flags::empty());
+        let mut dest = Tdb::memory(None, Flags::empty()).unwrap();
+        dest.store(b"key1", b"old", None).unwrap();
+        dest.store(b"other", b"kept", None).unwrap();
+
+        assert_eq!(src.copy_to(&mut dest).unwrap(), 100);
+        assert_eq!(dest.fetch(b"key1").unwrap().unwrap(), 1u32.to_le_bytes());
+        assert_eq!(dest.fetch(b"other").unwrap().unwrap(), b"kept");
+        assert_eq!(dest.iter().count(), 101);
+    }
+
+    #[test]
+    fn test_persist_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let src = populated(Some(31), Flags::IncompatibleHash);
+
+        let file = src.persist(&path).unwrap();
+        assert_eq!(file.hash_size(), 31);
+        assert_eq!(sorted(&file), sorted(&src));
+        drop(file);
+
+        let reader = crate::reader::TdbReader::open(&path).unwrap();
+        assert_eq!(reader.hash_function(), crate::reader::HashFunction::Jenkins);
+
+        let loaded = Tdb::load_into_memory(&path).unwrap();
+        assert!(loaded.get_flags().contains(Flags::Internal));
+        assert!(loaded.get_flags().contains(Flags::IncompatibleHash));
+        assert_eq!(loaded.hash_size(), 31);
+        assert_eq!(sorted(&loaded), sorted(&src));
+    }
+
+    #[test]
+    fn test_persist_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut old = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        old.store(b"stale", b"record", None).unwrap();
+        drop(old);
+
+        let file = populated(None, Flags::empty()).persist(&path).unwrap();
+        assert!(!file.exists(b"stale"));
+        assert_eq!(file.iter().count(), 100);
+    }
+
+    #[test]
+    fn test_hash_check_keeps_locks() {
+        // Closing another descriptor for the file would release the locks this process holds.
+        const CHILD_ENV: &str = "TRIVIALDB_COPY_LOCKED_PATH";
+        if let Some(path) = std::env::var_os(CHILD_ENV) {
+            let tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR, 0).unwrap();
+            assert!(tdb.lockall_read_nonblock().is_err());
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        tdb.lockall().unwrap();
+        assert_eq!(tdb.hash_function(), crate::reader::HashFunction::Old);
+        let mut child =
+            crate::test::spawn_test("copy::test::test_hash_check_keeps_locks", CHILD_ENV, &path);
+        assert!(child.wait().unwrap().success());
+        tdb.unlockall().unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Tdb::load_into_memory(dir.path().join("missing.tdb")).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Fixed-width integer values, compatible with Samba's `util_tdb` helpers.
+//!
+//! Samba stores counters with `tdb_store_int32` and friends as little-endian integers of exactly
+//! four bytes. The methods here use the same encoding, so counters can be shared with Samba
+//! tools. Note that Samba's `*_bystring` helpers include the terminating NUL byte in the key.
+
+use crate::{Error, Tdb};
+
+impl Tdb {
+    fn fetch_fixed<const N: usize>(&self, key: &[u8]) -> Result<Option<[u8; N]>, Error> {
+        match self.fetch(key)? {
+            Some(val) => val
+                .as_slice()
+                .try_into()
+                .map(Some)
+                .map_err(|_| Error::Invalid),
+            None => Ok(None),
+        }
+    }
+
+    /// Apply `f` to the value of `key` under its chain lock, returning the previous value.
+    ///
+    /// A missing key is treated as holding `initial`.
+    fn change_fixed<const N: usize>(
+        &mut self,
+        key: &[u8],
+        initial: [u8; N],
+        f: impl FnOnce([u8; N]) -> [u8; N],
+    ) -> Result<[u8; N], Error> {
+        self.with_chainlock(key, |tdb| {
+            let old = tdb.fetch_fixed(key)?.unwrap_or(initial);
+            tdb.store(key, &f(old), None)?;
+            Ok(old)
+        })
+    }
+
+    /// Fetch a little-endian 32-bit signed integer.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found.
+    /// * `Err(Error::Invalid)` - The value is not exactly four bytes long.
+    pub fn fetch_i32(&self, key: &[u8]) -> Result<Option<i32>, Error> {
+        Ok(self.fetch_fixed(key)?.map(i32::from_le_bytes))
+    }
+
+    /// Store a 32-bit signed integer in little-endian byte order.
+    pub fn store_i32(&mut self, key: &[u8], value: i32) -> Result<(), Error> {
+        self.store(key, &value.to_le_bytes(), None)
+    }
+
+    /// Fetch a little-endian 32-bit unsigned integer.
+    ///
+    /// See [`Tdb::fetch_i32`] for the possible return values.
+    pub fn fetch_u32(&self, key: &[u8]) -> Result<Option<u32>, Error> {
+        Ok(self.fetch_fixed(key)?.map(u32::from_le_bytes))
+    }
+
+    /// Store a 32-bit unsigned integer in little-endian byte order.
+    pub fn store_u32(&mut self, key: &[u8], value: u32) -> Result<(), Error> {
+        self.store(key, &value.to_le_bytes(), None)
+    }
+
+    /// Fetch a little-endian 64-bit signed integer.
+    ///
+    /// Returns `Err(Error::Invalid)` if the value is not exactly eight bytes long.
+    pub fn fetch_i64(&self, key: &[u8]) -> Result<Option<i64>, Error> {
+        Ok(self.fetch_fixed(key)?.map(i64::from_le_bytes))
+    }
+
+    /// Store a 64-bit signed integer in little-endian byte order.
+    pub fn store_i64(&mut self, key: &[u8], value: i64) -> Result<(), Error> {
+        self.store(key, &value.to_le_bytes(), None)
+    }
+
+    /// Fetch a little-endian 64-bit unsigned integer.
+    ///
+    /// Returns `Err(Error::Invalid)` if the value is not exactly eight bytes long.
+    pub fn fetch_u64(&self, key: &[u8]) -> Result<Option<u64>, Error> {
+        Ok(self.fetch_fixed(key)?.map(u64::from_le_bytes))
+    }
+
+    /// Store a 64-bit unsigned integer in little-endian byte order.
+    pub fn store_u64(&mut self, key: &[u8], value: u64) -> Result<(), Error> {
+        self.store(key, &value.to_le_bytes(), None)
+    }
+
+    /// Atomically add `delta` to a 32-bit signed counter.
+    ///
+    /// The change is made under the chain lock of the key, so concurrent changes from other
+    /// processes are never lost. The addition wraps on overflow.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the counter.
+    /// * `initial` - The value of the counter if the key is missing. Samba's
+    ///   `tdb_change_int32_atomic` takes this from the caller's `*oldval`.
+    /// * `delta` - The amount to add.
+    ///
+    /// # Returns
+    ///
+    /// The value before the change, which Samba's `tdb_change_int32_atomic` stores in
+    /// `*oldval`.
+    pub fn change_i32_atomic(
+        &mut self,
+        key: &[u8],
+        initial: i32,
+        delta: i32,
+    ) -> Result<i32, Error> {
+        self.change_fixed(key, initial.to_le_bytes(), |old| {
+            i32::from_le_bytes(old).wrapping_add(delta).to_le_bytes()
+        })
+        .map(i32::from_le_bytes)
+    }
+
+    /// Atomically add `delta` to a 32-bit unsigned counter, like Samba's
+    /// `tdb_change_uint32_atomic`.
+    ///
+    /// See [`Tdb::change_i32_atomic`].
+    pub fn change_u32_atomic(
+        &mut self,
+        key: &[u8],
+        initial: u32,
+        delta: u32,
+    ) -> Result<u32, Error> {
+        self.change_fixed(key, initial.to_le_bytes(), |old| {
+            u32::from_le_bytes(old).wrapping_add(delta).to_le_bytes()
+        })
+        .map(u32::from_le_bytes)
+    }
+
+    /// Atomically add `delta` to a 64-bit signed counter.
+    ///
+    /// See [`Tdb::change_i32_atomic`].
+    pub fn change_i64_atomic(
+        &mut self,
+        key: &[u8],
+        initial: i64,
+        delta: i64,
+    ) -> Result<i64, Error> {
+        self.change_fixed(key, initial.to_le_bytes(), |old| {
+            i64::from_le_bytes(old).wrapping_add(delta).to_le_bytes()
+        })
+        .map(i64::from_le_bytes)
+    }
+
+    /// Atomically add `delta` to a 64-bit unsigned counter.
+    ///
+    /// See [`Tdb::change_i32_atomic`].
+    pub fn change_u64_atomic(
+        &mut self,
+        key: &[u8],
+        initial: u64,
+        delta: u64,
+    ) -> Result<u64, Error> {
+        self.change_fixed(key, initial.to_le_bytes(), |old| {
+            u64::from_le_bytes(old).wrapping_add(delta).to_le_bytes()
+        })
+        .map(u64::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Error, Flags, Tdb};
+
+    #[test]
+    fn test_encoding() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+
+        tdb.store_i32(b"i32", -2).unwrap();
+        assert_eq!(
+            tdb.fetch(b"i32").unwrap().unwrap(),
+            [0xfe, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(tdb.fetch_i32(b"i32").unwrap(), Some(-2));
+        assert_eq!(tdb.fetch_u32(b"i32").unwrap(), Some(0xffff_fffe));
+
+        tdb.store_u32(b"u32", 0x0102_0304).unwrap();
+        assert_eq!(tdb.fetch(b"u32").unwrap().unwrap(), [4, 3, 2, 1]);
+
+        tdb.store_i64(b"i64", -3).unwrap();
+        assert_eq!(tdb.fetch_i64(b"i64").unwrap(), Some(-3));
+        tdb.store_u64(b"u64", u64::MAX).unwrap();
+        assert_eq!(tdb.fetch_u64(b"u64").unwrap(), Some(u64::MAX));
+
+        assert_eq!(tdb.fetch_i32(b"missing").unwrap(), None);
+        assert!(matches!(tdb.fetch_i32(b"i64"), Err(Error::Invalid)));
+        assert!(matches!(tdb.fetch_u64(b"u32"), Err(Error::Invalid)));
+    }
+
+    #[test]
+    fn test_change_atomic() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+
+        assert_eq!(tdb.change_i32_atomic(b"counter", 0, 5).unwrap(), 0);
+        assert_eq!(tdb.change_i32_atomic(b"counter", 0, -7).unwrap(), 5);
+        assert_eq!(tdb.fetch_i32(b"counter").unwrap(), Some(-2));
+
+        // The initial value is only used for a missing key
+        assert_eq!(tdb.change_i32_atomic(b"counter", 100, 1).unwrap(), -2);
+        assert_eq!(tdb.change_i32_atomic(b"start", 100, 1).unwrap(), 100);
+        assert_eq!(tdb.fetch_i32(b"start").unwrap(), Some(101));
+
+        tdb.store_u32(b"wrap", u32::MAX).unwrap();
+        assert_eq!(tdb.change_u32_atomic(b"wrap", 0, 2).unwrap(), u32::MAX);
+        assert_eq!(tdb.fetch_u32(b"wrap").unwrap(), Some(1));
+
+        assert_eq!(tdb.change_i64_atomic(b"i64", 0, i64::MIN).unwrap(), 0);
+        assert_eq!(tdb.change_u64_atomic(b"u64", 7, 1 << 40).unwrap(), 7);
+        assert_eq!(tdb.fetch_u64(b"u64").unwrap(), Some(7 + (1 << 40)));
+
+        // A value of the wrong width is left alone
+        tdb.store(b"text", b"hello", None).unwrap();
+        assert!(matches!(
+            tdb.change_i32_atomic(b"text", 0, 1),
+            Err(Error::Invalid)
+        ));
+        assert_eq!(tdb.fetch(b"text").unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_change_atomic_concurrent() {
+        // Forking the multi-threaded test harness is unsafe, so the increments are made by
+        // copies of the test binary that only run this test.
+        const CHILD_ENV: &str = "TRIVIALDB_COUNTER_PATH";
+        const CHILDREN: usize = 4;
+        const INCREMENTS: i32 = 100;
+
+        let open = |path: &std::path::Path| {
+            Tdb::open(
+                path,
+                None,
+                Flags::empty(),
+                libc::O_RDWR | libc::O_CREAT,
+                0o600,
+            )
+            .unwrap()
+        };
+
+        if let Some(path) = std::env::var_os(CHILD_ENV) {
+            let mut tdb = open(path.as_ref());
+            for _ in 0..INCREMENTS {
+                tdb.change_i32_atomic(b"counter", 0, 1).unwrap();
+            }
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters.tdb");
+        drop(open(&path));
+
+        let exe = std::env::current_exe().unwrap();
+        let children: Vec<_> = (0..CHILDREN)
+            .map(|_| {
+                std::process::Command::new(&exe)
+                    .args(["--exact", "counters::test::test_change_atomic_concurrent"])
+                    .env(CHILD_ENV, &path)
+                    .stdout(std::process::Stdio::null())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for mut child in children {
+            assert!(child.wait().unwrap().success());
+        }
+
+        assert_eq!(
+            open(&path).fetch_i32(b"counter").unwrap(),
+            Some(CHILDREN as i32 * INCREMENTS)
+        );
+    }
+}
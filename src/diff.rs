@@ -0,0 +1,332 @@
+//! Comparing two databases and applying the differences to a third.
+//!
+//! ```rust
+//! use trivialdb::{apply_diff, diff, ConflictStrategy, DiffEntry, Flags, Tdb};
+//!
+//! let mut backup = Tdb::memory(None, Flags::empty()).unwrap();
+//! backup.store(b"a", b"1", None).unwrap();
+//! backup.store(b"b", b"2", None).unwrap();
+//!
+//! let mut production = Tdb::memory(None, Flags::empty()).unwrap();
+//! production.store(b"b", b"3", None).unwrap();
+//! production.store(b"c", b"4", None).unwrap();
+//!
+//! let changes = diff(&backup, &production).unwrap();
+//! assert_eq!(
+//!     changes,
+//!     vec![
+//!         DiffEntry::Removed { key: b"a".to_vec(), value: b"1".to_vec() },
+//!         DiffEntry::Changed { key: b"b".to_vec(), old: b"2".to_vec(), new: b"3".to_vec() },
+//!         DiffEntry::Added { key: b"c".to_vec(), value: b"4".to_vec() },
+//!     ]
+//! );
+//!
+//! apply_diff(&mut backup, &changes, ConflictStrategy::Theirs).unwrap();
+//! assert!(diff(&backup, &production).unwrap().is_empty());
+//! ```
+
+use crate::{Error, Tdb};
+
+/// A difference in a single record between two databases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// The key only exists in the new database.
+    Added {
+        /// The key of the record.
+        key: Vec<u8>,
+        /// The value in the new database.
+        value: Vec<u8>,
+    },
+    /// The key only exists in the old database.
+    Removed {
+        /// The key of the record.
+        key: Vec<u8>,
+        /// The value in the old database.
+        value: Vec<u8>,
+    },
+    /// The key exists in both databases, with different values.
+    Changed {
+        /// The key of the record.
+        key: Vec<u8>,
+        /// The value in the old database.
+        old: Vec<u8>,
+        /// The value in the new database.
+        new: Vec<u8>,
+    },
+}
+
+impl DiffEntry {
+    /// The key of the record that differs.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            DiffEntry::Added { key, .. }
+            | DiffEntry::Removed { key, .. }
+            | DiffEntry::Changed { key, .. } => key,
+        }
+    }
+
+    /// The value the record had before the change, or `None` if it did not exist.
+    pub fn old_value(&self) -> Option<&[u8]> {
+        match self {
+            DiffEntry::Added { .. } => None,
+            DiffEntry::Removed { value, .. } => Some(value),
+            DiffEntry::Changed { old, .. } => Some(old),
+        }
+    }
+
+    /// The value the record has after the change, or `None` if it was removed.
+    pub fn new_value(&self) -> Option<&[u8]> {
+        match self {
+            DiffEntry::Added { value, .. } => Some(value),
+            DiffEntry::Removed { .. } => None,
+            DiffEntry::Changed { new, .. } => Some(new),
+        }
+    }
+}
+
+/// Write `data` the way `tdbdump` does: printable characters as they are, anything else,
+/// as well as `"` and `\`, as a backslash followed by two hex digits.
+fn write_escaped(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    for &c in data {
+        if (c.is_ascii_graphic() || c == b' ') && c != b'"' && c != b'\\' {
+            write!(f, "{}", c as char)?;
+        } else {
+            write!(f, "\\{:02X}", c)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_field(
+    f: &mut std::fmt::Formatter,
+    prefix: char,
+    name: &str,
+    data: &[u8],
+) -> std::fmt::Result {
+    write!(f, "{} {}({}) = \"", prefix, name, data.len())?;
+    write_escaped(f, data)?;
+    writeln!(f, "\"")
+}
+
+/// Formats the entry in the style of `tdbdump`, with lines prefixed by `+` for added data,
+/// `-` for removed data and a space for the key of a changed record.
+impl std::fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiffEntry::Added { key, value } => {
+                write_field(f, '+', "key", key)?;
+                write_field(f, '+', "data", value)
+            }
+            DiffEntry::Removed { key, value } => {
+                write_field(f, '-', "key", key)?;
+                write_field(f, '-', "data", value)
+            }
+            DiffEntry::Changed { key, old, new } => {
+                write_field(f, ' ', "key", key)?;
+                write_field(f, '-', "data", old)?;
+                write_field(f, '+', "data", new)
+            }
+        }
+    }
+}
+
+/// Compare two databases.
+///
+/// Both databases are only locked for reading, one at a time, while they are traversed.
+///
+/// # Arguments
+///
+/// * `old` - The database to compare against, e.g. a backup.
+/// * `new` - The database to compare.
+///
+/// # Returns
+///
+/// The changes that turn `old` into `new`, sorted by key.
+pub fn diff(old: &Tdb, new: &Tdb) -> Result<Vec<DiffEntry>, Error> {
+    let mut ret = Vec::new();
+    let mut err = Ok(());
+    old.traverse_read(|key, value| match new.fetch(key) {
+        Ok(Some(new)) if new != value => {
+            ret.push(DiffEntry::Changed {
+                key: key.to_vec(),
+                old: value.to_vec(),
+                new,
+            });
+            true
+        }
+        Ok(Some(_)) => true,
+        Ok(None) => {
+            ret.push(DiffEntry::Removed {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            });
+            true
+        }
+        Err(e) => {
+            err = Err(e);
+            false
+        }
+    })?;
+    err?;
+    new.traverse_read(|key, value| {
+        if !old.exists(key) {
+            ret.push(DiffEntry::Added {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            });
+        }
+        true
+    })?;
+    ret.sort_by(|a, b| a.key().cmp(b.key()));
+    Ok(ret)
+}
+
+/// How [`apply_diff`] resolves a record whose current value is neither the value the change
+/// started from nor the value it leads to.
+#[allow(clippy::type_complexity)]
+pub enum ConflictStrategy<'a> {
+    /// Keep the current value.
+    Ours,
+    /// Apply the change anyway.
+    Theirs,
+    /// Call the function with the change and the current value, or `None` if the key does not
+    /// exist. It returns the value to store, or `None` to delete the key.
+    Resolve(&'a mut dyn FnMut(&DiffEntry, Option<&[u8]>) -> Option<Vec<u8>>),
+}
+
+/// Apply changes, e.g. as returned by [`diff`], to a database.
+///
+/// All changes are applied in a single transaction, so either all of them or none are.
+///
+/// # Arguments
+///
+/// * `tdb` - The database to change.
+/// * `changes` - The changes to apply.
+/// * `strategy` - How to resolve conflicts.
+///
+/// # Returns
+///
+/// The number of conflicts that were encountered.
+pub fn apply_diff(
+    tdb: &mut Tdb,
+    changes: &[DiffEntry],
+    mut strategy: ConflictStrategy,
+) -> Result<usize, Error> {
+    tdb.with_transaction(|tdb| {
+        let mut conflicts = 0;
+        for change in changes {
+            let key = change.key();
+            let current = tdb.fetch(key)?;
+            let current = current.as_deref();
+            if current == change.new_value() {
+                continue;
+            }
+            let value = if current == change.old_value() {
+                change.new_value().map(<[u8]>::to_vec)
+            } else {
+                conflicts += 1;
+                match &mut strategy {
+                    ConflictStrategy::Ours => continue,
+                    ConflictStrategy::Theirs => change.new_value().map(<[u8]>::to_vec),
+                    ConflictStrategy::Resolve(f) => f(change, current),
+                }
+            };
+            match value {
+                Some(value) => tdb.store(key, &value, None)?,
+                None if current.is_some() => tdb.delete(key)?,
+                None => {}
+            }
+        }
+        Ok(conflicts)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    fn tdb(records: &[(&[u8], &[u8])]) -> Tdb {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        for (key, value) in records {
+            tdb.store(key, value, None).unwrap();
+        }
+        tdb
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let a = tdb(&[(b"a", b"1"), (b"b", b"2")]);
+        let b = tdb(&[(b"b", b"2"), (b"a", b"1")]);
+        assert_eq!(diff(&a, &b).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_apply_conflicts() {
+        let base = tdb(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")]);
+        let theirs = tdb(&[(b"a", b"10"), (b"c", b"30"), (b"d", b"4")]);
+        let changes = diff(&base, &theirs).unwrap();
+        assert_eq!(changes.len(), 4);
+
+        // "a" was changed the same way, "b" was changed and "d" added differently.
+        let ours = || tdb(&[(b"a", b"10"), (b"b", b"20"), (b"c", b"3"), (b"d", b"5")]);
+
+        let mut target = ours();
+        assert_eq!(
+            apply_diff(&mut target, &changes, ConflictStrategy::Ours).unwrap(),
+            2
+        );
+        assert_eq!(
+            diff(
+                &target,
+                &tdb(&[(b"a", b"10"), (b"b", b"20"), (b"c", b"30"), (b"d", b"5")])
+            )
+            .unwrap(),
+            vec![]
+        );
+
+        let mut target = ours();
+        assert_eq!(
+            apply_diff(&mut target, &changes, ConflictStrategy::Theirs).unwrap(),
+            2
+        );
+        assert_eq!(diff(&target, &theirs).unwrap(), vec![]);
+
+        let mut target = ours();
+        let mut seen = vec![];
+        let mut resolve = |change: &DiffEntry, current: Option<&[u8]>| {
+            seen.push((change.key().to_vec(), current.map(<[u8]>::to_vec)));
+            let mut merged = current.unwrap_or_default().to_vec();
+            merged.extend(change.new_value().unwrap_or_default());
+            Some(merged)
+        };
+        apply_diff(
+            &mut target,
+            &changes,
+            ConflictStrategy::Resolve(&mut resolve),
+        )
+        .unwrap();
+        assert_eq!(
+            seen,
+            vec![
+                (b"b".to_vec(), Some(b"20".to_vec())),
+                (b"d".to_vec(), Some(b"5".to_vec()))
+            ]
+        );
+        assert_eq!(target.fetch(b"b").unwrap().unwrap(), b"20");
+        assert_eq!(target.fetch(b"d").unwrap().unwrap(), b"54");
+    }
+
+    #[test]
+    fn test_display() {
+        let change = DiffEntry::Changed {
+            key: b"k\"ey".to_vec(),
+            old: b"a b".to_vec(),
+            new: vec![0, b'\\', 0xff],
+        };
+        assert_eq!(
+            change.to_string(),
+            "  key(4) = \"k\\22ey\"\n- data(3) = \"a b\"\n+ data(3) = \"\\00\\5C\\FF\"\n"
+        );
+    }
+}
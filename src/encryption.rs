@@ -0,0 +1,305 @@
+//! Authenticated encryption of values at rest.
+
+use crate::{Error, StoreFlags, Tdb};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Version byte at the start of every sealed value.
+const VERSION: u8 = 1;
+
+/// Length of the ChaCha20-Poly1305 nonce.
+const NONCE_LEN: usize = 12;
+
+/// A database that seals every value with ChaCha20-Poly1305.
+///
+/// Each value is stored as a version byte, a random 96-bit nonce and the ciphertext with its
+/// authentication tag. The record key is used as associated data, so a value copied to a
+/// different key fails to decrypt instead of silently being accepted.
+///
+/// Optionally the keys themselves can be replaced by their HMAC-SHA256 (see
+/// [`EncryptedTdb::with_hashed_keys`]), so that key names are not leaked either. Lookups
+/// still work, but the original key names can no longer be recovered from the file.
+///
+/// ```rust
+/// use trivialdb::{EncryptedTdb, Flags, Tdb};
+///
+/// let key = [0x42; 32];
+/// let mut db = EncryptedTdb::new(Tdb::memory(None, Flags::empty()).unwrap(), &key);
+/// db.store(b"password", b"hunter2", None).unwrap();
+/// assert_eq!(db.fetch(b"password").unwrap().unwrap(), b"hunter2");
+/// ```
+pub struct EncryptedTdb {
+    tdb: Tdb,
+    cipher: ChaCha20Poly1305,
+    key_mac: Option<Hmac<Sha256>>,
+}
+
+fn seal(cipher: &ChaCha20Poly1305, key: &[u8], val: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: val, aad: key })
+        .map_err(|_| Error::Invalid)?;
+    let mut record = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+    record.push(VERSION);
+    record.extend_from_slice(&nonce);
+    record.extend_from_slice(&ciphertext);
+    Ok(record)
+}
+
+fn open(cipher: &ChaCha20Poly1305, key: &[u8], record: &[u8]) -> Result<Vec<u8>, Error> {
+    match record.split_first() {
+        Some((&VERSION, rest)) if rest.len() >= NONCE_LEN => {
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: key,
+                    },
+                )
+                .map_err(|_| Error::Corrupt)
+        }
+        _ => Err(Error::Corrupt),
+    }
+}
+
+impl EncryptedTdb {
+    /// Wrap a database, encrypting values with a 256-bit key.
+    pub fn new(tdb: Tdb, key: &[u8; 32]) -> Self {
+        EncryptedTdb {
+            tdb,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            key_mac: None,
+        }
+    }
+
+    /// Wrap a database, encrypting values and storing keys as their HMAC-SHA256 under
+    /// `key_mac_key`.
+    ///
+    /// Records stored this way can be fetched by their original key, but the key names
+    /// themselves can't be recovered from the database.
+    pub fn with_hashed_keys(tdb: Tdb, key: &[u8; 32], key_mac_key: &[u8]) -> Self {
+        EncryptedTdb {
+            key_mac: Some(
+                <Hmac<Sha256> as Mac>::new_from_slice(key_mac_key)
+                    .expect("HMAC accepts keys of any length"),
+            ),
+            ..Self::new(tdb, key)
+        }
+    }
+
+    /// Return a reference to the underlying database.
+    pub fn tdb(&self) -> &Tdb {
+        &self.tdb
+    }
+
+    /// Return the underlying database.
+    pub fn into_inner(self) -> Tdb {
+        self.tdb
+    }
+
+    /// Return the key under which `key` is stored in the underlying database.
+    fn stored_key(&self, key: &[u8]) -> Vec<u8> {
+        match &self.key_mac {
+            Some(mac) => {
+                let mut mac = mac.clone();
+                mac.update(key);
+                mac.finalize().into_bytes().to_vec()
+            }
+            None => key.to_vec(),
+        }
+    }
+
+    /// Fetch and decrypt a value from the database.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found.
+    /// * `Err(Error::Corrupt)` - The value could not be decrypted, e.g. because it was
+    ///   tampered with, moved from another key or sealed with a different key.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let key = self.stored_key(key);
+        self.tdb
+            .fetch(&key)?
+            .map(|record| open(&self.cipher, &key, &record))
+            .transpose()
+    }
+
+    /// Encrypt and store a key/value pair in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `val` - The value to store.
+    /// * `flags` - The flags to use when storing the value.
+    pub fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        let key = self.stored_key(key);
+        let record = seal(&self.cipher, &key, val)?;
+        self.tdb.store(&key, &record, flags)
+    }
+
+    /// Delete a key from the database.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        let key = self.stored_key(key);
+        self.tdb.delete(&key)
+    }
+
+    /// Check if a particular key exists
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.tdb.exists(&self.stored_key(key))
+    }
+
+    /// Re-encrypt every value in the database with a new key.
+    ///
+    /// All values are re-encrypted inside a single transaction, so the database never
+    /// contains a mix of old and new keys. If any value fails to decrypt with the current key
+    /// the transaction is cancelled and the current key stays in use.
+    ///
+    /// The HMAC key used by [`EncryptedTdb::with_hashed_keys`] can't be rotated this way,
+    /// since the original key names are not known.
+    pub fn rotate_key(&mut self, new_key: &[u8; 32]) -> Result<(), Error> {
+        let new_cipher = ChaCha20Poly1305::new(new_key.into());
+        let cipher = &self.cipher;
+        self.tdb.with_transaction(|tdb| {
+            let mut records = Vec::new();
+            let mut failed = None;
+            tdb.traverse_read(|key, record| match open(cipher, key, record) {
+                Ok(val) => {
+                    records.push((key.to_vec(), val));
+                    true
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    false
+                }
+            })?;
+            if let Some(e) = failed {
+                return Err(e);
+            }
+            for (key, val) in records {
+                tdb.store(&key, &seal(&new_cipher, &key, &val)?, None)?;
+            }
+            Ok(())
+        })?;
+        self.cipher = new_cipher;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    const KEY: [u8; 32] = [1; 32];
+
+    fn db() -> EncryptedTdb {
+        EncryptedTdb::new(Tdb::memory(None, Flags::empty()).unwrap(), &KEY)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut db = db();
+        db.store(b"secret", b"value", None).unwrap();
+        assert!(db.exists(b"secret"));
+        assert_eq!(db.fetch(b"secret").unwrap().unwrap(), b"value");
+        assert_eq!(db.fetch(b"missing").unwrap(), None);
+
+        let stored = db.tdb().fetch(b"secret").unwrap().unwrap();
+        assert_eq!(stored[0], VERSION);
+        assert_eq!(stored.len(), 1 + NONCE_LEN + 5 + 16);
+        assert!(!stored.windows(5).any(|w| w == b"value"));
+
+        // Values are sealed with a fresh nonce every time
+        db.store(b"other", b"value", None).unwrap();
+        assert_ne!(db.tdb().fetch(b"other").unwrap().unwrap()[1..], stored[1..]);
+
+        db.delete(b"secret").unwrap();
+        assert!(!db.exists(b"secret"));
+    }
+
+    #[test]
+    fn test_tampering() {
+        let mut db = db();
+        db.store(b"a", b"value a", None).unwrap();
+        db.store(b"b", b"value b", None).unwrap();
+
+        // Swapping values between keys is detected
+        let sealed_a = db.tdb().fetch(b"a").unwrap().unwrap();
+        db.tdb.store(b"b", &sealed_a, None).unwrap();
+        assert!(matches!(db.fetch(b"b"), Err(Error::Corrupt)));
+
+        // So is flipping a bit
+        let mut flipped = sealed_a.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        db.tdb.store(b"a", &flipped, None).unwrap();
+        assert!(matches!(db.fetch(b"a"), Err(Error::Corrupt)));
+
+        // And plain garbage
+        db.tdb.store(b"c", b"plain", None).unwrap();
+        assert!(matches!(db.fetch(b"c"), Err(Error::Corrupt)));
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        let tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        let mut db = EncryptedTdb::with_hashed_keys(tdb, &KEY, b"mac key");
+        db.store(b"username", b"value", None).unwrap();
+        assert_eq!(db.fetch(b"username").unwrap().unwrap(), b"value");
+        assert!(db.exists(b"username"));
+
+        let keys = db.tdb().keys().collect::<Vec<_>>();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].len(), 32);
+        assert!(!db.tdb().exists(b"username"));
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.tdb");
+        let open_tdb = || {
+            Tdb::open(
+                &path,
+                None,
+                Flags::empty(),
+                libc::O_RDWR | libc::O_CREAT,
+                0o600,
+            )
+            .unwrap()
+        };
+        let mut db = EncryptedTdb::new(open_tdb(), &KEY);
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            db.store(key.as_bytes(), key.as_bytes(), None).unwrap();
+        }
+
+        let new_key = [2; 32];
+        db.rotate_key(&new_key).unwrap();
+        assert!(!db.tdb().transaction_active());
+        assert_eq!(db.fetch(b"key3").unwrap().unwrap(), b"key3");
+        drop(db);
+
+        assert!(matches!(
+            EncryptedTdb::new(open_tdb(), &KEY).fetch(b"key3"),
+            Err(Error::Corrupt)
+        ));
+        let mut db = EncryptedTdb::new(open_tdb(), &new_key);
+        assert_eq!(db.fetch(b"key9").unwrap().unwrap(), b"key9");
+
+        // A record that can't be decrypted aborts the rotation
+        db.tdb.store(b"bogus", b"not sealed", None).unwrap();
+        assert!(matches!(db.rotate_key(&KEY), Err(Error::Corrupt)));
+        assert!(!db.tdb().transaction_active());
+        assert_eq!(db.fetch(b"key9").unwrap().unwrap(), b"key9");
+    }
+}
@@ -0,0 +1,308 @@
+//! A view of a single key, in the style of `std::collections::hash_map::Entry`.
+//!
+//! An [`Entry`] holds the lock on the hash chain of its key until it is dropped, so no other
+//! process can change the key between looking at it and acting on it.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+//! for word in ["apple", "pear", "apple"] {
+//!     tdb.entry(word.as_bytes())
+//!         .unwrap()
+//!         .and_modify(|count| count[0] += 1)
+//!         .unwrap()
+//!         .or_insert(vec![1])
+//!         .unwrap();
+//! }
+//! assert_eq!(tdb.fetch(b"apple").unwrap().unwrap(), [2]);
+//! assert_eq!(tdb.fetch(b"pear").unwrap().unwrap(), [1]);
+//! ```
+
+use crate::{Error, Tdb};
+
+/// The lock on the hash chain of a key, released on drop.
+struct ChainLock<'a> {
+    tdb: &'a mut Tdb,
+    key: Vec<u8>,
+}
+
+impl Drop for ChainLock<'_> {
+    fn drop(&mut self) {
+        let ret = self.tdb.chainunlock(&self.key);
+        // A chain lock that is never released blocks every other process that uses the chain.
+        // Don't turn a panic into an abort, though.
+        debug_assert!(
+            ret.is_ok() || std::thread::panicking(),
+            "failed to unlock the hash chain: {:?}",
+            ret
+        );
+    }
+}
+
+/// A key in a database, which may or may not exist, returned by [`Tdb::entry`].
+pub enum Entry<'a> {
+    /// The key exists.
+    Occupied(OccupiedEntry<'a>),
+    /// The key does not exist.
+    Vacant(VacantEntry<'a>),
+}
+
+/// A key that exists, and its value.
+pub struct OccupiedEntry<'a> {
+    lock: ChainLock<'a>,
+    value: Vec<u8>,
+}
+
+/// A key that does not exist.
+pub struct VacantEntry<'a> {
+    lock: ChainLock<'a>,
+}
+
+impl Tdb {
+    /// Look up a key to act on it depending on whether it exists.
+    ///
+    /// The hash chain of the key is locked until the returned entry is dropped, so the check
+    /// and the change made through the entry are atomic, also across processes.
+    ///
+    /// # Arguments
+    /// * `key` - The key to look up.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(entry)` - The entry for the key.
+    /// * `Err(e)` - The chain could not be locked or the value could not be read.
+    pub fn entry(&mut self, key: &[u8]) -> Result<Entry<'_>, Error> {
+        self.chainlock(key)?;
+        let lock = ChainLock {
+            tdb: self,
+            key: key.to_vec(),
+        };
+        Ok(match lock.tdb.fetch(key)? {
+            Some(value) => Entry::Occupied(OccupiedEntry { lock, value }),
+            None => Entry::Vacant(VacantEntry { lock }),
+        })
+    }
+}
+
+impl<'a> Entry<'a> {
+    /// The key of the entry.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Store `default` if the key does not exist.
+    ///
+    /// # Returns
+    ///
+    /// The value of the key afterwards.
+    pub fn or_insert(self, default: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Store the result of `default` if the key does not exist. `default` is only called if
+    /// it doesn't.
+    ///
+    /// # Returns
+    ///
+    /// The value of the key afterwards.
+    pub fn or_insert_with<F: FnOnce() -> Vec<u8>>(self, default: F) -> Result<Vec<u8>, Error> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_value()),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Store an empty value if the key does not exist.
+    ///
+    /// # Returns
+    ///
+    /// The value of the key afterwards.
+    pub fn or_default(self) -> Result<Vec<u8>, Error> {
+        self.or_insert_with(Vec::new)
+    }
+
+    /// Change the value of the key with `f` and store it, if the key exists.
+    ///
+    /// # Returns
+    ///
+    /// The entry, so it can be followed by e.g. [`Entry::or_insert`].
+    pub fn and_modify<F: FnOnce(&mut Vec<u8>)>(self, f: F) -> Result<Self, Error> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = std::mem::take(&mut entry.value);
+                f(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+
+    /// Delete the key if it exists.
+    ///
+    /// # Returns
+    ///
+    /// The value the key had, or `None` if it did not exist.
+    pub fn remove(self) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Entry::Occupied(entry) => entry.remove().map(Some),
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+}
+
+impl<'a> OccupiedEntry<'a> {
+    /// The key of the entry.
+    pub fn key(&self) -> &[u8] {
+        &self.lock.key
+    }
+
+    /// The value of the key.
+    pub fn get(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Return the value of the key, releasing the lock.
+    pub fn into_value(self) -> Vec<u8> {
+        self.value
+    }
+
+    /// Replace the value of the key.
+    ///
+    /// # Returns
+    ///
+    /// The previous value.
+    pub fn insert(&mut self, value: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.lock.tdb.store(&self.lock.key, &value, None)?;
+        Ok(std::mem::replace(&mut self.value, value))
+    }
+
+    /// Delete the key.
+    ///
+    /// # Returns
+    ///
+    /// The value the key had.
+    pub fn remove(self) -> Result<Vec<u8>, Error> {
+        self.lock.tdb.delete(&self.lock.key)?;
+        Ok(self.value)
+    }
+}
+
+impl<'a> VacantEntry<'a> {
+    /// The key of the entry.
+    pub fn key(&self) -> &[u8] {
+        &self.lock.key
+    }
+
+    /// Store a value for the key.
+    ///
+    /// # Returns
+    ///
+    /// The value.
+    pub fn insert(self, value: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.lock.tdb.store(&self.lock.key, &value, None)?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Flags, O_CREAT, O_RDWR};
+
+    #[test]
+    fn test_entry() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"a", b"1", None).unwrap();
+
+        match tdb.entry(b"a").unwrap() {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), b"a");
+                assert_eq!(entry.get(), b"1");
+                assert_eq!(entry.insert(b"2".to_vec()).unwrap(), b"1");
+                assert_eq!(entry.get(), b"2");
+            }
+            Entry::Vacant(_) => panic!("a exists"),
+        }
+        assert_eq!(tdb.fetch(b"a").unwrap().unwrap(), b"2");
+
+        let entry = tdb.entry(b"b").unwrap();
+        assert_eq!(entry.key(), b"b");
+        assert!(matches!(entry, Entry::Vacant(_)));
+        drop(entry);
+        assert!(!tdb.exists(b"b"));
+
+        assert_eq!(tdb.entry(b"b").unwrap().or_default().unwrap(), b"");
+        assert_eq!(
+            tdb.entry(b"a").unwrap().or_insert(b"3".to_vec()).unwrap(),
+            b"2"
+        );
+        let mut called = false;
+        let value = tdb
+            .entry(b"a")
+            .unwrap()
+            .or_insert_with(|| {
+                called = true;
+                b"4".to_vec()
+            })
+            .unwrap();
+        assert_eq!(value, b"2");
+        assert!(!called);
+    }
+
+    #[test]
+    fn test_entry_and_modify() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        for _ in 0..3 {
+            tdb.entry(b"count")
+                .unwrap()
+                .and_modify(|value| value.push(b'+'))
+                .unwrap()
+                .or_insert(b"+".to_vec())
+                .unwrap();
+        }
+        assert_eq!(tdb.fetch(b"count").unwrap().unwrap(), b"+++");
+    }
+
+    #[test]
+    fn test_entry_remove() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"a", b"1", None).unwrap();
+        assert_eq!(
+            tdb.entry(b"a").unwrap().remove().unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert!(!tdb.exists(b"a"));
+        assert_eq!(tdb.entry(b"a").unwrap().remove().unwrap(), None);
+    }
+
+    #[test]
+    fn test_entry_locks_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        match tdb.entry(b"key").unwrap() {
+            Entry::Vacant(entry) => {
+                // The whole database can't be locked while a chain lock is held.
+                assert!(entry.lock.tdb.lockall_nonblock().is_err());
+                entry.insert(b"value".to_vec()).unwrap();
+            }
+            Entry::Occupied(_) => panic!("key does not exist"),
+        }
+        tdb.lockall_nonblock().unwrap();
+        tdb.unlockall().unwrap();
+
+        // The lock is also released when unwinding.
+        let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _entry = tdb.entry(b"key").unwrap();
+            panic!("while holding the entry");
+        }));
+        assert!(ret.is_err());
+        tdb.lockall_nonblock().unwrap();
+        tdb.unlockall().unwrap();
+    }
+}
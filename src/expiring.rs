@@ -0,0 +1,351 @@
+//! Records that expire after a time-to-live, for using a database as a cache.
+
+use crate::{Error, Tdb};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Length of the expiry header stored in front of every value.
+const HEADER_LEN: usize = 8;
+
+/// Default number of records deleted per transaction by [`ExpiringTdb::sweep`].
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// A database whose records can expire, similar to Samba's gencache.
+///
+/// Every value is stored with an eight-byte little-endian header holding its expiry time in
+/// milliseconds since the Unix epoch, where zero means the record never expires. Expired
+/// records are treated as missing when read, and are removed from the file by
+/// [`ExpiringTdb::sweep`] or by a background [`Sweeper`].
+///
+/// ```rust
+/// use std::time::Duration;
+/// use trivialdb::{ExpiringTdb, Flags, Tdb};
+///
+/// let mut cache = ExpiringTdb::new(Tdb::memory(None, Flags::empty()).unwrap());
+/// cache.store(b"session", b"data", Some(Duration::from_secs(60))).unwrap();
+/// assert_eq!(cache.fetch(b"session").unwrap().unwrap(), b"data");
+/// ```
+pub struct ExpiringTdb {
+    tdb: Tdb,
+    batch_size: usize,
+}
+
+/// A value fetched from an [`ExpiringTdb`], along with its expiry time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiringValue {
+    /// The stored value.
+    pub value: Vec<u8>,
+    /// When the value expires, or `None` if it never does.
+    pub expiry: Option<SystemTime>,
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Split a stored record into its expiry time and value.
+fn decode(record: &[u8]) -> Result<(u64, &[u8]), Error> {
+    if record.len() < HEADER_LEN {
+        return Err(Error::Invalid);
+    }
+    let (header, val) = record.split_at(HEADER_LEN);
+    Ok((u64::from_le_bytes(header.try_into().unwrap()), val))
+}
+
+fn is_expired(expiry: u64, now: u64) -> bool {
+    expiry != 0 && expiry <= now
+}
+
+impl ExpiringTdb {
+    /// Wrap a database.
+    pub fn new(tdb: Tdb) -> Self {
+        ExpiringTdb {
+            tdb,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    /// Set the maximum number of records [`ExpiringTdb::sweep`] deletes per transaction.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Return a reference to the underlying database.
+    pub fn tdb(&self) -> &Tdb {
+        &self.tdb
+    }
+
+    /// Return the underlying database.
+    pub fn into_inner(self) -> Tdb {
+        self.tdb
+    }
+
+    /// Store a value that expires after `ttl`, or never if `ttl` is `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `val` - The value to store.
+    /// * `ttl` - How long the value should live for.
+    pub fn store(&mut self, key: &[u8], val: &[u8], ttl: Option<Duration>) -> Result<(), Error> {
+        let expiry = ttl.map(|ttl| SystemTime::now() + ttl);
+        self.store_until(key, val, expiry)
+    }
+
+    /// Store a value that expires at `expiry`, or never if `expiry` is `None`.
+    pub fn store_until(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        expiry: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        // Make sure a deadline in the past doesn't end up meaning "never".
+        let expiry = expiry.map_or(0, |t| millis_since_epoch(t).max(1));
+        let mut record = Vec::with_capacity(HEADER_LEN + val.len());
+        record.extend_from_slice(&expiry.to_le_bytes());
+        record.extend_from_slice(val);
+        self.tdb.store(key, &record, None)
+    }
+
+    /// Fetch a value, treating expired records as missing.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found or has expired.
+    /// * `Err(Error::Invalid)` - The record is too short to hold an expiry time.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.fetch_with_expiry(key)?.map(|v| v.value))
+    }
+
+    /// Fetch a value along with its expiry time, treating expired records as missing.
+    pub fn fetch_with_expiry(&self, key: &[u8]) -> Result<Option<ExpiringValue>, Error> {
+        let Some(record) = self.tdb.fetch(key)? else {
+            return Ok(None);
+        };
+        let (expiry, val) = decode(&record)?;
+        if is_expired(expiry, millis_since_epoch(SystemTime::now())) {
+            return Ok(None);
+        }
+        Ok(Some(ExpiringValue {
+            value: val.to_vec(),
+            expiry: (expiry != 0).then(|| UNIX_EPOCH + Duration::from_millis(expiry)),
+        }))
+    }
+
+    /// Check if a key exists and has not expired.
+    pub fn exists(&self, key: &[u8]) -> bool {
+        matches!(self.fetch(key), Ok(Some(_)))
+    }
+
+    /// Delete a key.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.tdb.delete(key)
+    }
+
+    /// Delete all expired records.
+    ///
+    /// Expired keys are found with a read traversal, and then deleted in batches of at most
+    /// [`ExpiringTdb::set_batch_size`] records. On file databases each batch is deleted in its
+    /// own transaction; memory databases do not support transactions, so the whole database is
+    /// locked instead. Records that have been refreshed in the meantime are left alone.
+    ///
+    /// # Returns
+    ///
+    /// The number of records deleted.
+    pub fn sweep(&mut self) -> Result<usize, Error> {
+        let now = millis_since_epoch(SystemTime::now());
+        let mut expired = Vec::new();
+        self.tdb.traverse_read(|key, record| {
+            if matches!(decode(record), Ok((expiry, _)) if is_expired(expiry, now)) {
+                expired.push(key.to_vec());
+            }
+            true
+        })?;
+
+        let mut deleted = 0;
+        for batch in expired.chunks(self.batch_size) {
+            deleted += self
+                .tdb
+                .with_transaction(|tdb| delete_expired(tdb, batch, now))?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Delete those of `keys` that are still expired.
+fn delete_expired(tdb: &mut Tdb, keys: &[Vec<u8>], now: u64) -> Result<usize, Error> {
+    let mut deleted = 0;
+    for key in keys {
+        // Another process may have refreshed or removed the record since the traversal.
+        let Some(record) = tdb.fetch(key)? else {
+            continue;
+        };
+        if matches!(decode(&record), Ok((expiry, _)) if is_expired(expiry, now)) {
+            tdb.delete(key)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+impl From<Tdb> for ExpiringTdb {
+    fn from(tdb: Tdb) -> Self {
+        ExpiringTdb::new(tdb)
+    }
+}
+
+/// A background thread that periodically sweeps expired records from an [`ExpiringTdb`].
+///
+/// The thread is stopped when the sweeper is dropped. Errors from individual sweeps are
+/// ignored; the sweep is simply retried after the next interval.
+///
+/// Note that libtdb does not allow the same file to be opened twice within one process, so
+/// the database is shared with the sweeper through a mutex rather than opened again.
+pub struct Sweeper {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    swept: Arc<AtomicUsize>,
+}
+
+impl Sweeper {
+    /// Start a thread that calls [`ExpiringTdb::sweep`] every `interval`.
+    pub fn spawn(db: Arc<Mutex<ExpiringTdb>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let swept = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let swept = swept.clone();
+            std::thread::spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let mut db = match db.lock() {
+                        Ok(db) => db,
+                        Err(_) => return,
+                    };
+                    if let Ok(count) = db.sweep() {
+                        swept.fetch_add(count, Ordering::Relaxed);
+                    }
+                }
+            })
+        };
+        Sweeper {
+            stop: Some(stop),
+            thread: Some(thread),
+            swept,
+        }
+    }
+
+    /// Return the total number of records deleted by this sweeper so far.
+    pub fn swept(&self) -> usize {
+        self.swept.load(Ordering::Relaxed)
+    }
+
+    /// Stop the sweeper thread and wait for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the sender wakes up the thread.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    fn expired() -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut db = ExpiringTdb::new(Tdb::memory(None, Flags::empty()).unwrap());
+
+        db.store(b"forever", b"a", None).unwrap();
+        db.store(b"later", b"b", Some(Duration::from_secs(3600)))
+            .unwrap();
+        db.store_until(b"past", b"c", expired()).unwrap();
+
+        assert_eq!(
+            db.fetch_with_expiry(b"forever").unwrap(),
+            Some(ExpiringValue {
+                value: b"a".to_vec(),
+                expiry: None
+            })
+        );
+        let later = db.fetch_with_expiry(b"later").unwrap().unwrap();
+        assert_eq!(later.value, b"b");
+        assert!(later.expiry.unwrap() > SystemTime::now());
+
+        assert_eq!(db.fetch(b"past").unwrap(), None);
+        assert!(!db.exists(b"past"));
+        // The record is still there until it is swept
+        assert!(db.tdb().exists(b"past"));
+
+        db.delete(b"later").unwrap();
+        assert!(!db.exists(b"later"));
+
+        db.tdb.store(b"short", b"abc", None).unwrap();
+        assert!(matches!(db.fetch(b"short"), Err(Error::Invalid)));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = Tdb::open(
+            dir.path().join("cache.tdb"),
+            None,
+            Flags::empty(),
+            libc::O_RDWR | libc::O_CREAT,
+            0o600,
+        )
+        .unwrap();
+        let mut db = ExpiringTdb::new(tdb);
+        db.set_batch_size(3);
+
+        for i in 0..10 {
+            let key = format!("expired{}", i);
+            db.store_until(key.as_bytes(), b"x", expired()).unwrap();
+        }
+        db.store(b"live", b"y", Some(Duration::from_secs(3600)))
+            .unwrap();
+        db.store(b"forever", b"z", None).unwrap();
+
+        assert_eq!(db.sweep().unwrap(), 10);
+        assert!(!db.tdb().transaction_active());
+        let mut keys = db.tdb().keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec![b"forever".to_vec(), b"live".to_vec()]);
+
+        assert_eq!(db.sweep().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sweeper() {
+        let mut db = ExpiringTdb::new(Tdb::memory(None, Flags::empty()).unwrap());
+        db.store_until(b"past", b"x", expired()).unwrap();
+        let db = Arc::new(Mutex::new(db));
+
+        let sweeper = Sweeper::spawn(db.clone(), Duration::from_millis(10));
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        while sweeper.swept() == 0 && SystemTime::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        sweeper.stop();
+
+        assert!(!db.lock().unwrap().tdb().exists(b"past"));
+    }
+}
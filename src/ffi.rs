@@ -0,0 +1,768 @@
+//! The libtdb backend: thin wrappers around the C API.
+
+use crate::generated;
+use crate::reader::{detect_hash, read_header, HashFunction};
+use crate::{Error, Flags, StoreFlags, O_CREAT, O_RDWR};
+use std::ffi::CStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+/// An open libtdb context, and the hash function its records are stored with.
+pub(crate) struct Handle(*mut generated::tdb_context, HashFunction);
+
+// A tdb_context is not tied to the thread that opened it, it just must not be used from
+// multiple threads at the same time - which `&mut`/`Mutex` already guarantee.
+unsafe impl Send for Handle {}
+
+/// A buffer allocated with `malloc`, as libtdb returns them, that is freed with `libc::free`.
+///
+/// The Rust global allocator need not be `malloc`, so memory must never move between a
+/// `TdbBuf` and a `Vec` without copying.
+pub(crate) struct TdbBuf {
+    ptr: *mut std::os::raw::c_uchar,
+    len: usize,
+}
+
+// The buffer is owned and never aliased, so it can be freed from any thread.
+unsafe impl Send for TdbBuf {}
+
+impl TdbBuf {
+    /// Take ownership of a buffer returned by libtdb.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be null or point to `dsize` bytes allocated with `malloc` that nothing else
+    /// frees.
+    unsafe fn from_raw(data: generated::TDB_DATA) -> Option<TdbBuf> {
+        if data.dptr.is_null() {
+            None
+        } else {
+            Some(TdbBuf {
+                ptr: data.dptr,
+                len: data.dsize,
+            })
+        }
+    }
+
+    /// Copy `data` into a new buffer.
+    fn copy_from_slice(data: &[u8]) -> TdbBuf {
+        // malloc(0) may return null, which libtdb takes as no buffer at all.
+        let ptr = unsafe { libc::malloc(data.len().max(1)) } as *mut std::os::raw::c_uchar;
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(std::alloc::Layout::for_value(data));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        TdbBuf {
+            ptr,
+            len: data.len(),
+        }
+    }
+
+    /// Borrow the buffer as an argument to libtdb.
+    fn as_raw(&self) -> generated::TDB_DATA {
+        generated::TDB_DATA {
+            dptr: self.ptr,
+            dsize: self.len,
+        }
+    }
+}
+
+impl std::ops::Deref for TdbBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { as_slice(self.as_raw()) }
+    }
+}
+
+impl AsRef<[u8]> for TdbBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Clone for TdbBuf {
+    fn clone(&self) -> Self {
+        TdbBuf::copy_from_slice(self)
+    }
+}
+
+impl Drop for TdbBuf {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.ptr as *mut libc::c_void) };
+    }
+}
+
+/// Borrow `data` as an argument to libtdb, which never writes through the pointer.
+fn borrow(data: &[u8]) -> generated::TDB_DATA {
+    generated::TDB_DATA {
+        dptr: data.as_ptr() as *mut std::os::raw::c_uchar,
+        dsize: data.len(),
+    }
+}
+
+/// View a buffer passed in by libtdb as a slice.
+///
+/// # Safety
+///
+/// The caller must ensure the underlying buffer outlives the returned slice.
+unsafe fn as_slice<'a>(data: generated::TDB_DATA) -> &'a [u8] {
+    if data.dptr.is_null() || data.dsize == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data.dptr, data.dsize)
+    }
+}
+
+/// State shared with [`traverse_callback`] for the duration of a traversal.
+struct TraverseState<F> {
+    f: F,
+    panic: Option<Box<dyn std::any::Any + Send + 'static>>,
+}
+
+unsafe extern "C" fn traverse_callback<F: FnMut(&[u8], &[u8]) -> bool>(
+    _tdb: *mut generated::tdb_context,
+    key: generated::TDB_DATA,
+    data: generated::TDB_DATA,
+    private_data: *mut std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let state = &mut *(private_data as *mut TraverseState<F>);
+    let (key, data) = (as_slice(key), as_slice(data));
+    // Unwinding across the C frames is not allowed, so stop the traversal and re-raise the
+    // panic once tdb_traverse has returned.
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (state.f)(key, data))) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(payload) => {
+            state.panic = Some(payload);
+            1
+        }
+    }
+}
+
+unsafe extern "C" fn check_callback<F: FnMut(&[u8], &[u8]) -> bool>(
+    key: generated::TDB_DATA,
+    data: generated::TDB_DATA,
+    private_data: *mut std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let state = &mut *(private_data as *mut TraverseState<F>);
+    let (key, data) = (as_slice(key), as_slice(data));
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (state.f)(key, data))) {
+        Ok(true) => 0,
+        Ok(false) => -1,
+        Err(payload) => {
+            state.panic = Some(payload);
+            -1
+        }
+    }
+}
+
+/// Reopen every open database, e.g. in the child after a fork.
+///
+/// # Safety
+///
+/// No other thread may be using any of the databases.
+pub(crate) unsafe fn reopen_all(parent_longlived: bool) -> Result<(), Error> {
+    if generated::tdb_reopen_all(parent_longlived as std::os::raw::c_int) == -1 {
+        Err(Error::IO)
+    } else {
+        Ok(())
+    }
+}
+
+impl Handle {
+    pub(crate) fn open<P: AsRef<std::path::Path>>(
+        name: P,
+        hash_size: Option<u32>,
+        tdb_flags: Flags,
+        open_flags: i32,
+        mode: generated::mode_t,
+    ) -> Option<Handle> {
+        let name = name.as_ref();
+        let hash_size = hash_size.unwrap_or(0);
+        // Ensure null termination for C API
+        let c_name = std::ffi::CString::new(name.as_os_str().as_bytes()).ok()?;
+        let ret = unsafe {
+            generated::tdb_open(
+                c_name.as_ptr(),
+                hash_size as i32,
+                tdb_flags.bits() as i32,
+                open_flags,
+                mode,
+            )
+        };
+        if ret.is_null() {
+            None
+        } else {
+            Handle::new(ret)
+        }
+    }
+
+    pub(crate) fn memory(hash_size: Option<u32>, mut tdb_flags: Flags) -> Option<Handle> {
+        let hash_size = hash_size.unwrap_or(0);
+        tdb_flags.insert(Flags::Internal);
+        let ret = unsafe {
+            generated::tdb_open(
+                c":memory:".as_ptr(),
+                hash_size as i32,
+                tdb_flags.bits() as i32,
+                O_RDWR | O_CREAT,
+                0,
+            )
+        };
+        if ret.is_null() {
+            None
+        } else {
+            Handle::new(ret)
+        }
+    }
+
+    /// Wrap a context returned by `tdb_open`, which is closed if its hash function is unknown.
+    fn new(tdb: *mut generated::tdb_context) -> Option<Handle> {
+        let mut handle = Handle(tdb, HashFunction::Old);
+        handle.1 = handle.detect_hash().ok()?;
+        Some(handle)
+    }
+
+    /// Find out which hash function the records are stored with; libtdb doesn't say.
+    fn detect_hash(&self) -> Result<HashFunction, Error> {
+        let flags = self.get_flags();
+        if flags.contains(Flags::IncompatibleHash) {
+            Ok(HashFunction::Jenkins)
+        } else if flags.contains(Flags::Internal) {
+            Ok(HashFunction::Old)
+        } else {
+            // libtdb picks up the hash of an existing file regardless of the flags it was
+            // opened with.
+            detect_hash(&read_header(self.fd())?)
+        }
+    }
+
+    fn error(&self) -> Result<(), Error> {
+        // Safety: self.0 is guaranteed to be a valid pointer for the lifetime of self
+        let err = unsafe { generated::tdb_error(self.0) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(err.into())
+        }
+    }
+
+    pub(crate) fn set_max_dead(&mut self, max_dead: u32) {
+        unsafe { generated::tdb_set_max_dead(self.0, max_dead as i32) };
+    }
+
+    pub(crate) fn reopen(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_reopen(self.0) };
+        if ret == -1 {
+            return self.error();
+        }
+        // The file may have been replaced, e.g. by `rehash`.
+        self.1 = self.detect_hash()?;
+        Ok(())
+    }
+
+    pub(crate) fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.fetch_buf(key)?.map(|buf| buf.to_vec()))
+    }
+
+    fn fetch_buf(&self, key: &[u8]) -> Result<Option<TdbBuf>, Error> {
+        match unsafe { TdbBuf::from_raw(generated::tdb_fetch(self.0, borrow(key))) } {
+            Some(buf) => Ok(Some(buf)),
+            None => match self.error() {
+                Err(Error::NoExist) => Ok(None),
+                Err(e) => Err(e),
+                Ok(_) => panic!("error but no error?"),
+            },
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    pub(crate) fn fetch_bytes(&self, key: &[u8]) -> Result<Option<::bytes::Bytes>, Error> {
+        // The buffer is freed with `libc::free` once the last clone is dropped.
+        Ok(self.fetch_buf(key)?.map(::bytes::Bytes::from_owner))
+    }
+
+    pub(crate) fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        let flags = flags.map_or(0, |f| f as i32);
+        let ret = unsafe { generated::tdb_store(self.0, borrow(key), borrow(val), flags) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(tdb_storev)]
+    pub(crate) fn storev(
+        &mut self,
+        key: &[u8],
+        vals: &[&[u8]],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        let flags = flags.map_or(0, |f| f as i32);
+        let dbufs = vals.iter().map(|val| borrow(val)).collect::<Vec<_>>();
+        let num_dbufs = i32::try_from(dbufs.len()).map_err(|_| Error::Invalid)?;
+        let ret =
+            unsafe { generated::tdb_storev(self.0, borrow(key), dbufs.as_ptr(), num_dbufs, flags) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Older versions of libtdb have no `tdb_storev`, so concatenate the buffers instead.
+    #[cfg(not(tdb_storev))]
+    pub(crate) fn storev(
+        &mut self,
+        key: &[u8],
+        vals: &[&[u8]],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        self.store(key, &vals.concat(), flags)
+    }
+
+    pub(crate) fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_delete(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_append(self.0, borrow(key), borrow(val)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn keys(&self) -> Keys<'_> {
+        Keys(self, None)
+    }
+
+    pub(crate) fn keys_after(&self, key: &[u8]) -> Keys<'_> {
+        Keys(self, Some(TdbBuf::copy_from_slice(key)))
+    }
+
+    pub(crate) fn traverse<F: FnMut(&[u8], &[u8]) -> bool>(
+        &self,
+        write: bool,
+        f: F,
+    ) -> Result<usize, Error> {
+        let traverse = if write {
+            generated::tdb_traverse
+        } else {
+            generated::tdb_traverse_read
+        };
+        let mut state = TraverseState { f, panic: None };
+        let ret = unsafe {
+            traverse(
+                self.0,
+                Some(traverse_callback::<F>),
+                &mut state as *mut TraverseState<F> as *mut std::os::raw::c_void,
+            )
+        };
+        if let Some(payload) = state.panic {
+            std::panic::resume_unwind(payload);
+        }
+        if ret == -1 {
+            self.error().map(|_| 0)
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    pub(crate) fn exists(&self, key: &[u8]) -> bool {
+        unsafe { generated::tdb_exists(self.0, borrow(key)) != 0 }
+    }
+
+    pub(crate) fn check<F: FnMut(&[u8], &[u8]) -> bool>(&self, f: F) -> Result<(), Error> {
+        let mut state = TraverseState { f, panic: None };
+        let ret = unsafe {
+            generated::tdb_check(
+                self.0,
+                Some(check_callback::<F>),
+                &mut state as *mut TraverseState<F> as *mut std::os::raw::c_void,
+            )
+        };
+        if let Some(payload) = state.panic {
+            std::panic::resume_unwind(payload);
+        }
+        if ret == -1 {
+            self.error().and(Err(Error::Corrupt))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn lockall(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_lockall(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn unlockall(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_unlockall(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn lockall_nonblock(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_lockall_nonblock(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn lockall_read(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_lockall_read(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn lockall_read_nonblock(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_lockall_read_nonblock(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn unlockall_read(&self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_unlockall_read(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn chainlock(&self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_chainlock(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn chainlock_nonblock(&self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_chainlock_nonblock(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn chainunlock(&self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_chainunlock(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn chainlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_chainlock_read(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn chainunlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_chainunlock_read(self.0, borrow(key)) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(generated::tdb_name(self.0)) }
+            .to_str()
+            .unwrap()
+    }
+
+    pub(crate) fn hash_size(&self) -> u32 {
+        unsafe { generated::tdb_hash_size(self.0) as u32 }
+    }
+
+    pub(crate) fn map_size(&self) -> u32 {
+        unsafe { generated::tdb_map_size(self.0) as u32 }
+    }
+
+    pub(crate) fn get_seqnum(&self) -> u64 {
+        unsafe { generated::tdb_get_seqnum(self.0) as u64 }
+    }
+
+    pub(crate) fn hash_function(&self) -> HashFunction {
+        self.1
+    }
+
+    pub(crate) fn get_flags(&self) -> Flags {
+        Flags::from_bits_truncate(unsafe { generated::tdb_get_flags(self.0) as u32 })
+    }
+
+    pub(crate) fn add_flags(&mut self, flags: Flags) {
+        unsafe { generated::tdb_add_flags(self.0, flags.bits()) };
+    }
+
+    pub(crate) fn remove_flags(&mut self, flags: Flags) {
+        unsafe { generated::tdb_remove_flags(self.0, flags.bits()) };
+    }
+
+    pub(crate) fn enable_seqnum(&mut self) {
+        unsafe { generated::tdb_enable_seqnum(self.0) };
+    }
+
+    pub(crate) fn increment_seqnum_nonblock(&mut self) {
+        unsafe { generated::tdb_increment_seqnum_nonblock(self.0) };
+    }
+
+    pub(crate) fn repack(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_repack(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn wipe_all(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_wipe_all(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        let buf = unsafe { generated::tdb_summary(self.0) };
+        if buf.is_null() {
+            return String::new();
+        }
+        let summary = unsafe { CStr::from_ptr(buf) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { libc::free(buf as *mut libc::c_void) };
+        summary
+    }
+
+    pub(crate) fn freelist_size(&self) -> u32 {
+        unsafe { generated::tdb_freelist_size(self.0) as u32 }
+    }
+
+    pub(crate) fn validate_freelist(&self) -> Result<u32, Error> {
+        let mut num_entries = 0;
+        let ret = unsafe { generated::tdb_validate_freelist(self.0, &mut num_entries) };
+        if ret == -1 {
+            self.error().and(Err(Error::Corrupt))
+        } else {
+            Ok(num_entries as u32)
+        }
+    }
+
+    pub(crate) fn transaction_start(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_transaction_start(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn transaction_active(&self) -> bool {
+        unsafe { generated::tdb_transaction_active(self.0) }
+    }
+
+    pub(crate) fn transaction_start_nonblock(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_transaction_start_nonblock(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn transaction_prepare_commit(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_transaction_prepare_commit(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn transaction_commit(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_transaction_commit(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        unsafe { generated::tdb_fd(self.0) }
+    }
+
+    pub(crate) fn transaction_cancel(&mut self) -> Result<(), Error> {
+        let ret = unsafe { generated::tdb_transaction_cancel(self.0) };
+        if ret == -1 {
+            self.error()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Iterator over the keys, holding on to the previous key to find the next one.
+pub(crate) struct Keys<'a>(&'a Handle, Option<TdbBuf>);
+
+impl Iterator for Keys<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let key = match self.1.take() {
+            Some(prev_key) => unsafe { generated::tdb_nextkey(self.0 .0, prev_key.as_raw()) },
+            None => unsafe { generated::tdb_firstkey(self.0 .0) },
+        };
+        match unsafe { TdbBuf::from_raw(key) } {
+            Some(key) => {
+                let ret = key.to_vec();
+                // Keep the key for the next iteration.
+                self.1 = Some(key);
+                Some(ret)
+            }
+            None => match self.0.error() {
+                Err(Error::NoExist) | Ok(_) => None,
+                Err(e) => panic!("TDB iterator error: {}", e),
+            },
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { generated::tdb_close(self.0) };
+    }
+}
+
+pub(crate) fn jenkins_hash(key: &[u8]) -> u32 {
+    let mut tdb_key = borrow(key);
+    unsafe { generated::tdb_jenkins_hash(&mut tdb_key) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Tdb;
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    /// A global allocator that is not `malloc`: every allocation starts past a header, which is
+    /// checked when it is freed. Freeing `malloc` memory here aborts, and so does passing memory
+    /// from here to `libc::free`.
+    struct OffsetAllocator;
+
+    const MAGIC: u64 = 0x7464_6272_7573_7421;
+
+    impl OffsetAllocator {
+        /// The layout of the underlying allocation and the offset of the returned pointer.
+        fn outer(layout: Layout) -> (Layout, usize) {
+            let offset = layout.align().max(16);
+            let outer = Layout::from_size_align(layout.size() + offset, offset).unwrap();
+            (outer, offset)
+        }
+    }
+
+    unsafe impl GlobalAlloc for OffsetAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let (outer, offset) = Self::outer(layout);
+            let ptr = System.alloc(outer);
+            if ptr.is_null() {
+                return ptr;
+            }
+            let ptr = ptr.add(offset);
+            (ptr.sub(8) as *mut u64).write(MAGIC);
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if (ptr.sub(8) as *const u64).read() != MAGIC {
+                std::process::abort();
+            }
+            (ptr.sub(8) as *mut u64).write(0);
+            let (outer, offset) = Self::outer(layout);
+            System.dealloc(ptr.sub(offset), outer);
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: OffsetAllocator = OffsetAllocator;
+
+    #[test]
+    fn test_tdb_buf_clone() {
+        let empty = TdbBuf::copy_from_slice(&[]);
+        assert!(!empty.as_raw().dptr.is_null());
+        assert_eq!(&*empty.clone(), b"");
+
+        let data = TdbBuf::copy_from_slice(&[1, 2, 3, 4, 5]);
+        let cloned = data.clone();
+        drop(data);
+        assert_eq!(cloned.to_vec(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(unsafe { TdbBuf::from_raw(borrow(&[])) }.map(|_| ()), None);
+    }
+
+    #[test]
+    fn test_buffers_with_other_allocator() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        for i in 0..20u32 {
+            tdb.store(&i.to_le_bytes(), &[i as u8; 100], None).unwrap();
+        }
+        tdb.store(b"empty", b"", None).unwrap();
+
+        // Values and keys returned by libtdb are copied, and both copies are freed.
+        assert_eq!(tdb.fetch(&3u32.to_le_bytes()).unwrap().unwrap(), [3; 100]);
+        assert_eq!(tdb.fetch(b"empty").unwrap().unwrap(), b"");
+        assert_eq!(tdb.fetch(b"missing").unwrap(), None);
+        assert_eq!(tdb.keys().count(), 21);
+        assert_eq!(tdb.iter().count(), 21);
+        let first = tdb.keys().next().unwrap();
+        assert_eq!(tdb.0.keys_after(&first).count(), 20);
+        assert!(!tdb.summary().is_empty());
+        #[cfg(feature = "bytes")]
+        {
+            let value = tdb.fetch_bytes(&3u32.to_le_bytes()).unwrap().unwrap();
+            drop(tdb);
+            assert_eq!(&value[..], [3; 100]);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Reopening databases in the child process after a fork.
+//!
+//! A child process inherits the file descriptors of its parent, so it shares their seek
+//! pointers, while the fcntl locks the parent holds are not inherited at all. A child that
+//! uses a database it did not open itself must reopen it first, or it will corrupt the file.
+//! [`install_fork_handler`] makes sure this happens for every open database.
+//!
+//! ```rust
+//! use trivialdb::{install_fork_handler, Flags, Tdb, O_CREAT, O_RDWR};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("shared.tdb");
+//! let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+//! // No other threads use databases in this process.
+//! unsafe { install_fork_handler(true) }.unwrap();
+//! match unsafe { libc::fork() } {
+//!     0 => {
+//!         // The database has already been reopened in the child.
+//!         let ok = tdb.store(b"child", b"1", None).is_ok();
+//!         unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+//!     }
+//!     pid => {
+//!         let mut status = 0;
+//!         unsafe { libc::waitpid(pid, &mut status, 0) };
+//!         assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+//!         assert!(tdb.exists(b"child"));
+//!     }
+//! }
+//! ```
+
+use crate::{backend, Error};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+/// Reopen every open database, e.g. in the child after a fork.
+///
+/// This is the same as calling [`crate::Tdb::reopen`] on each of them.
+///
+/// # Arguments
+///
+/// * `parent_longlived` - Whether the parent keeps running with the databases open, as a
+///   daemon that forks workers does. Databases opened with `Flags::ClearIfFirst` then rely on
+///   the parent to hold the lock that marks them as in use, rather than taking it again.
+///
+/// If a database can't be reopened, the others still are, and the first error is returned.
+///
+/// # Safety
+///
+/// No other thread may be using any database while this runs, since the databases are
+/// reopened behind the backs of their owners.
+pub unsafe fn reopen_all(parent_longlived: bool) -> Result<(), Error> {
+    backend::reopen_all(parent_longlived)
+}
+
+static PARENT_LONGLIVED: AtomicBool = AtomicBool::new(false);
+static INSTALL: Once = Once::new();
+
+extern "C" fn reopen_in_child() {
+    // Only the forking thread exists in the child, so no database is in use; the caller of
+    // `install_fork_handler` promised that no other thread was using one at the time of the
+    // fork either.
+    if unsafe { reopen_all(PARENT_LONGLIVED.load(Ordering::Relaxed)) }.is_err() {
+        // Carrying on with the file descriptors of the parent would corrupt the databases,
+        // and libtdb closes a database it fails to reopen behind the back of its handle.
+        std::process::abort();
+    }
+}
+
+/// Reopen every open database in the child whenever the process forks.
+///
+/// This installs a `pthread_atfork` handler the first time it is called; later calls only
+/// change `parent_longlived`.
+///
+/// # Arguments
+///
+/// * `parent_longlived` - See [`reopen_all`].
+///
+/// # Returns
+///
+/// * `Ok(())` - The handler is installed.
+/// * `Err(Error::OOM)` - The handler could not be installed.
+///
+/// # Safety
+///
+/// The handler runs in the child of every later `fork`, including those made by libraries,
+/// e.g. by `std::process::Command` when it can't use `posix_spawn`, and reopens the databases
+/// before the child gets to exec. The caller must make sure that whenever the process forks:
+///
+/// * No other thread is opening, closing or using a database, and no database is in the
+///   middle of an operation, e.g. in a traversal callback. The handler doesn't wait for
+///   other threads: if the list of open databases is locked at the time of the fork, it
+///   fails, as it would otherwise deadlock in the child.
+/// * Reopening the databases is acceptable in the child. It allocates memory and opens
+///   files, which is only safe after a fork if no other thread held locks inside the
+///   allocator or libc at the time.
+///
+/// If any database can't be reopened, e.g. because its file has been replaced or removed,
+/// the child aborts, even if it would never have used that database.
+pub unsafe fn install_fork_handler(parent_longlived: bool) -> Result<(), Error> {
+    PARENT_LONGLIVED.store(parent_longlived, Ordering::Relaxed);
+    let mut ret = 0;
+    INSTALL.call_once(|| {
+        ret = unsafe { libc::pthread_atfork(None, None, Some(reopen_in_child)) };
+    });
+    if ret != 0 {
+        return Err(Error::OOM);
+    }
+    Ok(())
+}
@@ -0,0 +1,621 @@
+//! A journal of mutations, for mirroring a database to a replica.
+//!
+//! A [`JournaledTdb`] appends every store, delete and append it makes to a sidecar log file,
+//! numbered with an increasing sequence number. A [`Replayer`] applies the log to a replica,
+//! e.g. on a second disk, and remembers the sequence number of the last mutation it applied
+//! in the replica itself. Once all replicas have caught up, [`JournaledTdb::checkpoint`]
+//! truncates the log.
+//!
+//! ```rust
+//! use trivialdb::{Flags, JournaledTdb, Replayer, Tdb};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let log = dir.path().join("primary.log");
+//!
+//! let mut primary =
+//!     JournaledTdb::open(Tdb::memory(None, Flags::empty()).unwrap(), &log).unwrap();
+//! primary.store(b"key", b"value", None).unwrap();
+//!
+//! let mut replayer = Replayer::new(Tdb::memory(None, Flags::empty()).unwrap(), &log);
+//! assert_eq!(replayer.replay().unwrap(), 1);
+//! assert_eq!(replayer.replica().fetch(b"key").unwrap().unwrap(), b"value");
+//!
+//! primary.checkpoint(replayer.applied_seqnum().unwrap()).unwrap();
+//! ```
+//!
+//! # Log Format
+//!
+//! The log starts with an eight-byte magic and the little-endian 64-bit sequence number up to
+//! which it was truncated. Each mutation follows as a frame: the little-endian 32-bit length
+//! and Jenkins hash of the payload, then the payload itself, made up of the 64-bit sequence
+//! number, a one-byte operation, the 32-bit key length, the key and the value. A frame that
+//! is cut short or doesn't match its hash is a partially written tail, and is ignored.
+
+use crate::{jenkins_hash, Error, StoreFlags, Tdb};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The key under which a [`Replayer`] stores the sequence number of the last mutation it
+/// applied to the replica.
+pub const JOURNAL_SEQNUM_KEY: &[u8] = b"\0trivialdb-journal-seqnum";
+
+const MAGIC: &[u8; 8] = b"TDBJRNL\x01";
+const LOG_HEADER_LEN: usize = MAGIC.len() + 8;
+const FRAME_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Store = 1,
+    Delete = 2,
+    Append = 3,
+}
+
+/// A mutation read back from the log.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    seqnum: u64,
+    op: Op,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+fn encode_frame(seqnum: u64, op: Op, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(13 + key.len() + value.len());
+    payload.extend_from_slice(&seqnum.to_le_bytes());
+    payload.push(op as u8);
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(value);
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&jenkins_hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn decode_payload(payload: &[u8]) -> Option<Entry> {
+    let seqnum = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+    let op = match payload.get(8)? {
+        1 => Op::Store,
+        2 => Op::Delete,
+        3 => Op::Append,
+        _ => return None,
+    };
+    let key_len = u32::from_le_bytes(payload.get(9..13)?.try_into().unwrap()) as usize;
+    let key = payload.get(13..13usize.checked_add(key_len)?)?;
+    Some(Entry {
+        seqnum,
+        op,
+        key: key.to_vec(),
+        value: payload[13 + key_len..].to_vec(),
+    })
+}
+
+/// The contents of a log file.
+struct Log {
+    /// The sequence number up to which the log has been truncated.
+    base: u64,
+    entries: Vec<Entry>,
+    /// The length of the log up to the end of the last complete frame.
+    valid_len: usize,
+}
+
+impl Log {
+    fn parse(data: &[u8]) -> Result<Log, Error> {
+        if data.len() < LOG_HEADER_LEN || !data.starts_with(MAGIC) {
+            return Err(Error::Corrupt);
+        }
+        let base = u64::from_le_bytes(data[MAGIC.len()..LOG_HEADER_LEN].try_into().unwrap());
+        let mut entries = Vec::new();
+        let mut off = LOG_HEADER_LEN;
+        while let Some(header) = data.get(off..off + FRAME_HEADER_LEN) {
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let hash = u32::from_le_bytes(header[4..].try_into().unwrap());
+            let start = off + FRAME_HEADER_LEN;
+            let Some(payload) = data.get(start..start + len) else {
+                break;
+            };
+            if jenkins_hash(payload) != hash {
+                break;
+            }
+            let Some(entry) = decode_payload(payload) else {
+                break;
+            };
+            entries.push(entry);
+            off = start + len;
+        }
+        Ok(Log {
+            base,
+            entries,
+            valid_len: off,
+        })
+    }
+
+    fn read(path: &Path) -> Result<Log, Error> {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|_| Error::IO)?;
+        Log::parse(&data)
+    }
+
+    /// The sequence number of the last mutation in the log.
+    fn last_seqnum(&self) -> u64 {
+        self.entries.last().map_or(self.base, |e| e.seqnum)
+    }
+}
+
+fn log_header(base: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&base.to_le_bytes());
+    header
+}
+
+/// A database that records every mutation in a journal, so it can be replayed onto a
+/// replica with a [`Replayer`].
+///
+/// Only mutations that succeed are journaled, after they have been made. If the mutation
+/// can't be journaled, the log is truncated back to where it was, the mutation is undone and
+/// [`Error::IO`] is returned. If that fails too, the database and the log may disagree, so all
+/// further mutations fail with [`Error::IO`] until the log is opened again with
+/// [`JournaledTdb::open`].
+///
+/// Only one `JournaledTdb` should write to a database and its log at a time; changes made to
+/// the database by other means are not journaled.
+pub struct JournaledTdb {
+    tdb: Tdb,
+    path: PathBuf,
+    log: File,
+    seqnum: u64,
+    poisoned: bool,
+}
+
+impl JournaledTdb {
+    /// Wrap a database, journaling to the log at `path`.
+    ///
+    /// The log is created if it doesn't exist. Otherwise numbering continues after the last
+    /// mutation in it, and a partially written frame at its end, e.g. from a crash, is
+    /// truncated.
+    ///
+    /// # Arguments
+    ///
+    /// * `tdb` - The database to journal.
+    /// * `path` - The log file.
+    pub fn open<P: AsRef<Path>>(tdb: Tdb, path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|_| Error::IO)?;
+        let mut data = Vec::new();
+        log.read_to_end(&mut data).map_err(|_| Error::IO)?;
+        let seqnum = if data.is_empty() {
+            log.write_all(&log_header(0)).map_err(|_| Error::IO)?;
+            0
+        } else {
+            let parsed = Log::parse(&data)?;
+            if parsed.valid_len < data.len() {
+                log.set_len(parsed.valid_len as u64)
+                    .map_err(|_| Error::IO)?;
+            }
+            parsed.last_seqnum()
+        };
+        Ok(JournaledTdb {
+            tdb,
+            path,
+            log,
+            seqnum,
+            poisoned: false,
+        })
+    }
+
+    /// Return a reference to the underlying database.
+    pub fn tdb(&self) -> &Tdb {
+        &self.tdb
+    }
+
+    /// Return the underlying database.
+    pub fn into_inner(self) -> Tdb {
+        self.tdb
+    }
+
+    /// The sequence number of the last journaled mutation.
+    pub fn seqnum(&self) -> u64 {
+        self.seqnum
+    }
+
+    /// Make a mutation of `key` with `f` and journal it, undoing it if it can't be journaled.
+    fn journal(
+        &mut self,
+        op: Op,
+        key: &[u8],
+        value: &[u8],
+        f: impl FnOnce(&mut Tdb) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if self.poisoned {
+            return Err(Error::IO);
+        }
+        let frame = encode_frame(self.seqnum + 1, op, key, value);
+        let JournaledTdb {
+            tdb, log, poisoned, ..
+        } = self;
+        // Hold the chain lock so nothing changes the key between saving its value and undoing.
+        tdb.with_chainlock(key, |tdb| {
+            let old = tdb.fetch(key)?;
+            let log_len = log.metadata().map_err(|_| Error::IO)?.len();
+            f(tdb)?;
+            if log.write_all(&frame).is_ok() {
+                return Ok(());
+            }
+            // Drop what was written of the frame, so later frames don't follow a broken one.
+            let truncated = log.set_len(log_len).is_ok();
+            let undone = match old {
+                Some(old) => tdb.store(key, &old, None),
+                None => match tdb.delete(key) {
+                    Err(Error::NoExist) => Ok(()),
+                    ret => ret,
+                },
+            };
+            *poisoned = !truncated || undone.is_err();
+            Err(Error::IO)
+        })?;
+        self.seqnum += 1;
+        Ok(())
+    }
+
+    /// Fetch a value from the database.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.tdb.fetch(key)
+    }
+
+    /// Check if a key exists.
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.tdb.exists(key)
+    }
+
+    /// Store a key/value pair and journal it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `val` - The value to store.
+    /// * `flags` - The flags to use when storing the value.
+    pub fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        self.journal(Op::Store, key, val, |tdb| tdb.store(key, val, flags))
+    }
+
+    /// Delete a key and journal it.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.journal(Op::Delete, key, &[], |tdb| tdb.delete(key))
+    }
+
+    /// Append to the value of a key and journal it.
+    pub fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        self.journal(Op::Append, key, val, |tdb| tdb.append(key, val))
+    }
+
+    /// Flush the log to disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.log.sync_data().map_err(|_| Error::IO)
+    }
+
+    /// Truncate the log, dropping the mutations up to and including `applied`.
+    ///
+    /// Pass the lowest [`Replayer::applied_seqnum`] of all replicas. The log is rewritten to
+    /// a temporary file that then replaces it, so a crash leaves either the old or the new
+    /// log behind.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(Error::Invalid)` - `applied` is beyond the last journaled mutation.
+    pub fn checkpoint(&mut self, applied: u64) -> Result<(), Error> {
+        if applied > self.seqnum {
+            return Err(Error::Invalid);
+        }
+        let log = Log::read(&self.path)?;
+        let base = applied.max(log.base);
+        let mut data = log_header(base);
+        for entry in log.entries.iter().filter(|e| e.seqnum > base) {
+            data.extend(encode_frame(
+                entry.seqnum,
+                entry.op,
+                &entry.key,
+                &entry.value,
+            ));
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let write = || -> std::io::Result<File> {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&data)?;
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, &self.path)?;
+            OpenOptions::new().read(true).append(true).open(&self.path)
+        };
+        self.log = write().map_err(|_| Error::IO)?;
+        Ok(())
+    }
+}
+
+/// Applies the log of a [`JournaledTdb`] to a replica.
+///
+/// The sequence number of the last applied mutation is stored in the replica under
+/// [`JOURNAL_SEQNUM_KEY`], in the same transaction as the mutations, so a replay that is
+/// interrupted can simply be repeated.
+pub struct Replayer {
+    replica: Tdb,
+    path: PathBuf,
+}
+
+impl Replayer {
+    /// Create a replayer.
+    ///
+    /// # Arguments
+    ///
+    /// * `replica` - The database to apply the mutations to.
+    /// * `path` - The log file written by the [`JournaledTdb`].
+    pub fn new<P: AsRef<Path>>(replica: Tdb, path: P) -> Self {
+        Replayer {
+            replica,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Return a reference to the replica.
+    pub fn replica(&self) -> &Tdb {
+        &self.replica
+    }
+
+    /// Return the replica.
+    pub fn into_inner(self) -> Tdb {
+        self.replica
+    }
+
+    /// The sequence number of the last mutation applied to the replica, or zero if none has
+    /// been applied yet.
+    pub fn applied_seqnum(&self) -> Result<u64, Error> {
+        match self.replica.fetch(JOURNAL_SEQNUM_KEY)? {
+            Some(val) => Ok(u64::from_le_bytes(
+                val.as_slice().try_into().map_err(|_| Error::Corrupt)?,
+            )),
+            None => Ok(0),
+        }
+    }
+
+    /// Apply the mutations from the log that the replica hasn't seen yet.
+    ///
+    /// All of them are applied in a single transaction. A partially written frame at the end
+    /// of the log is skipped; it is picked up by a later replay once it is complete.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(n)` - The number of mutations applied.
+    /// * `Err(Error::Invalid)` - The log has been truncated past mutations the replica
+    ///   hasn't seen, so it has to be rebuilt from a full copy.
+    pub fn replay(&mut self) -> Result<usize, Error> {
+        let log = Log::read(&self.path)?;
+        let applied = self.applied_seqnum()?;
+        if log.base > applied {
+            return Err(Error::Invalid);
+        }
+        let pending = log
+            .entries
+            .iter()
+            .filter(|e| e.seqnum > applied)
+            .collect::<Vec<_>>();
+        let Some(last) = pending.last() else {
+            return Ok(0);
+        };
+        self.replica.with_transaction(|tdb| {
+            for entry in &pending {
+                match entry.op {
+                    Op::Store => tdb.store(&entry.key, &entry.value, None)?,
+                    Op::Append => tdb.append(&entry.key, &entry.value)?,
+                    Op::Delete => match tdb.delete(&entry.key) {
+                        Ok(()) | Err(Error::NoExist) => {}
+                        Err(e) => return Err(e),
+                    },
+                }
+            }
+            tdb.store(JOURNAL_SEQNUM_KEY, &last.seqnum.to_le_bytes(), None)
+        })?;
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Flags, O_CREAT, O_RDWR};
+
+    fn memory() -> Tdb {
+        Tdb::memory(None, Flags::empty()).unwrap()
+    }
+
+    fn records(tdb: &Tdb) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records = tdb
+            .iter()
+            .filter(|(key, _)| key != JOURNAL_SEQNUM_KEY)
+            .collect::<Vec<_>>();
+        records.sort();
+        records
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut data = log_header(7);
+        data.extend(encode_frame(8, Op::Append, b"key", b"value"));
+        data.extend(encode_frame(9, Op::Delete, b"", b""));
+        let log = Log::parse(&data).unwrap();
+        assert_eq!(log.base, 7);
+        assert_eq!(log.valid_len, data.len());
+        assert_eq!(
+            log.entries,
+            vec![
+                Entry {
+                    seqnum: 8,
+                    op: Op::Append,
+                    key: b"key".to_vec(),
+                    value: b"value".to_vec()
+                },
+                Entry {
+                    seqnum: 9,
+                    op: Op::Delete,
+                    key: vec![],
+                    value: vec![]
+                },
+            ]
+        );
+        assert!(Log::parse(b"not a log at all").is_err());
+    }
+
+    #[test]
+    fn test_replicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let tdb = Tdb::open(
+            dir.path().join("primary.tdb"),
+            None,
+            Flags::empty(),
+            O_RDWR | O_CREAT,
+            0o600,
+        )
+        .unwrap();
+        let mut primary = JournaledTdb::open(tdb, &log).unwrap();
+        let mut replayer = Replayer::new(memory(), &log);
+
+        primary.store(b"a", b"1", None).unwrap();
+        primary.store(b"b", b"2", None).unwrap();
+        primary.append(b"b", b"3").unwrap();
+        assert!(primary.store(b"a", b"4", Some(StoreFlags::Insert)).is_err());
+        assert_eq!(primary.seqnum(), 3);
+        assert_eq!(replayer.replay().unwrap(), 3);
+        assert_eq!(replayer.applied_seqnum().unwrap(), 3);
+        assert_eq!(replayer.replay().unwrap(), 0);
+
+        primary.delete(b"a").unwrap();
+        primary.store(b"c", b"5", None).unwrap();
+        assert_eq!(replayer.replay().unwrap(), 2);
+        assert_eq!(records(replayer.replica()), records(primary.tdb()));
+        assert_eq!(records(primary.tdb()).len(), 2);
+    }
+
+    #[test]
+    fn test_partial_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let mut primary = JournaledTdb::open(memory(), &log).unwrap();
+        primary.store(b"a", b"1", None).unwrap();
+        let tdb = primary.into_inner();
+
+        let frame = encode_frame(2, Op::Store, b"b", b"2");
+        let mut f = OpenOptions::new().append(true).open(&log).unwrap();
+        f.write_all(&frame[..frame.len() - 1]).unwrap();
+        drop(f);
+
+        let mut replayer = Replayer::new(memory(), &log);
+        assert_eq!(replayer.replay().unwrap(), 1);
+
+        let mut primary = JournaledTdb::open(tdb, &log).unwrap();
+        assert_eq!(primary.seqnum(), 1);
+        primary.store(b"b", b"3", None).unwrap();
+        assert_eq!(replayer.replay().unwrap(), 1);
+        assert_eq!(replayer.replica().fetch(b"b").unwrap().unwrap(), b"3");
+    }
+
+    #[test]
+    fn test_short_write() {
+        // Writes beyond RLIMIT_FSIZE are cut short. The limit applies to the whole process, so
+        // the test runs in a process of its own.
+        const CHILD_ENV: &str = "TRIVIALDB_JOURNAL_SHORT_WRITE";
+        if std::env::var_os(CHILD_ENV).is_none() {
+            let mut child =
+                crate::test::spawn_test("journal::test::test_short_write", CHILD_ENV, "1");
+            assert!(child.wait().unwrap().success());
+            return;
+        }
+        // Otherwise writing beyond the limit kills the process.
+        unsafe { libc::signal(libc::SIGXFSZ, libc::SIG_IGN) };
+        let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_FSIZE, &mut limit) },
+            0
+        );
+        let mut limit_file_size = |len: Option<u64>| {
+            limit.rlim_cur = len.map_or(limit.rlim_max, |len| len as libc::rlim_t);
+            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_FSIZE, &limit) }, 0);
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let mut primary = JournaledTdb::open(memory(), &log).unwrap();
+        let mut replayer = Replayer::new(memory(), &log);
+        primary.store(b"a", b"1", None).unwrap();
+        let log_len = std::fs::metadata(&log).unwrap().len();
+
+        // Failed mutations are undone, and leave nothing behind in the log.
+        limit_file_size(Some(log_len + 5));
+        assert!(matches!(primary.store(b"a", b"2", None), Err(Error::IO)));
+        limit_file_size(Some(log_len));
+        assert!(matches!(primary.store(b"b", b"1", None), Err(Error::IO)));
+        limit_file_size(Some(log_len + 10));
+        assert!(matches!(primary.delete(b"a"), Err(Error::IO)));
+        limit_file_size(None);
+        assert_eq!(std::fs::metadata(&log).unwrap().len(), log_len);
+        assert_eq!(primary.seqnum(), 1);
+        assert_eq!(primary.fetch(b"a").unwrap().unwrap(), b"1");
+        assert!(!primary.exists(b"b"));
+
+        // Later mutations still reach the replica.
+        primary.append(b"a", b"3").unwrap();
+        primary.store(b"c", b"4", None).unwrap();
+        assert_eq!(primary.seqnum(), 3);
+        assert_eq!(replayer.replay().unwrap(), 3);
+        assert_eq!(records(replayer.replica()), records(primary.tdb()));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let mut primary = JournaledTdb::open(memory(), &log).unwrap();
+        let mut replayer = Replayer::new(memory(), &log);
+        primary.store(b"a", b"1", None).unwrap();
+        primary.store(b"b", b"2", None).unwrap();
+        replayer.replay().unwrap();
+        primary.store(b"c", b"3", None).unwrap();
+
+        assert!(primary.checkpoint(4).is_err());
+        primary
+            .checkpoint(replayer.applied_seqnum().unwrap())
+            .unwrap();
+        let truncated = Log::read(&log).unwrap();
+        assert_eq!(truncated.base, 2);
+        assert_eq!(truncated.entries.len(), 1);
+
+        primary.store(b"d", b"4", None).unwrap();
+        assert_eq!(replayer.replay().unwrap(), 2);
+        assert_eq!(records(replayer.replica()), records(primary.tdb()));
+
+        primary.checkpoint(primary.seqnum()).unwrap();
+        assert_eq!(
+            std::fs::metadata(&log).unwrap().len(),
+            LOG_HEADER_LEN as u64
+        );
+        assert!(matches!(
+            Replayer::new(memory(), &log).replay(),
+            Err(Error::Invalid)
+        ));
+    }
+}
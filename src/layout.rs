@@ -0,0 +1,237 @@
+//! Inspecting where records and free space are in a database file.
+//!
+//! [`Tdb::layout`] lists the same information as libtdb's `tdb_dump_all` and
+//! `tdb_printfreelist`, which print to standard output: the records on each hash chain and the
+//! blocks on the freelist. With the `serde` feature, [`Layout`] can be exported as JSON, e.g.
+//! to look into fragmentation and decide when to call [`Tdb::repack`].
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb, O_CREAT, O_RDWR};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("test.tdb");
+//! let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+//! for i in 0..100u32 {
+//!     tdb.store(&i.to_le_bytes(), &[0; 100], None).unwrap();
+//! }
+//! for i in (0..100u32).step_by(2) {
+//!     tdb.delete(&i.to_le_bytes()).unwrap();
+//! }
+//! let layout = tdb.layout().unwrap();
+//! assert_eq!(layout.records(), 50);
+//! if layout.free_bytes() > layout.map_size as u64 / 4 {
+//!     tdb.repack().unwrap();
+//! }
+//! ```
+
+use crate::reader::{FreeBlock, TdbReader, RECORD_LEN};
+use crate::{Error, Flags, Tdb};
+use std::os::unix::io::AsRawFd;
+
+/// A record in a hash chain, as listed by [`Tdb::layout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RecordLayout {
+    /// Offset of the record header in the file.
+    pub offset: u32,
+    /// Offset of the next record in the same hash chain, or zero.
+    pub next: u32,
+    /// Length of the record, excluding its header but including padding.
+    pub rec_len: u32,
+    /// Length of the key.
+    pub key_len: u32,
+    /// Length of the value.
+    pub data_len: u32,
+    /// The full 32-bit hash of the key.
+    pub full_hash: u32,
+    /// Whether the record has been deleted but not yet removed from its hash chain.
+    pub dead: bool,
+}
+
+/// The records and free space in a database file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Layout {
+    /// Size of the database file.
+    pub map_size: u32,
+    /// The records on each hash chain, indexed by chain, in the order they are linked.
+    pub chains: Vec<Vec<RecordLayout>>,
+    /// The blocks on the freelist, in the order they are linked.
+    pub free_blocks: Vec<FreeBlock>,
+}
+
+impl Layout {
+    fn read(reader: &TdbReader) -> Result<Layout, Error> {
+        let chains = (0..reader.hash_size())
+            .map(|bucket| {
+                reader
+                    .chain(bucket)
+                    .map(|record| {
+                        let record = record?;
+                        Ok(RecordLayout {
+                            offset: record.offset,
+                            next: record.next,
+                            rec_len: record.rec_len,
+                            key_len: record.key.len() as u32,
+                            data_len: record.data.len() as u32,
+                            full_hash: record.full_hash,
+                            dead: record.dead,
+                        })
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Layout {
+            map_size: reader.map_size() as u32,
+            chains,
+            free_blocks: reader.freelist()?,
+        })
+    }
+
+    /// The number of live records.
+    pub fn records(&self) -> usize {
+        self.chains.iter().flatten().filter(|r| !r.dead).count()
+    }
+
+    /// The number of bytes in free blocks, including their record headers.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_blocks
+            .iter()
+            .map(|block| block.rec_len as u64 + RECORD_LEN as u64)
+            .sum()
+    }
+
+    /// The size of the largest free block, excluding its record header.
+    pub fn largest_free_block(&self) -> u32 {
+        self.free_blocks
+            .iter()
+            .map(|block| block.rec_len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Tdb {
+    /// List the records on each hash chain and the blocks on the freelist.
+    ///
+    /// The file is read through the descriptor of the handle, without going through libtdb,
+    /// while the database is locked for reading, so other processes can't modify it in the
+    /// meantime.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(layout)` - The layout of the file.
+    /// * `Err(Error::Invalid)` - The database is a memory database.
+    /// * `Err(Error::Corrupt)` - The file is corrupt.
+    pub fn layout(&self) -> Result<Layout, Error> {
+        if self.get_flags().contains(Flags::Internal) {
+            return Err(Error::Invalid);
+        }
+        self.lockall_read()?;
+        let ret = TdbReader::from_fd(self.as_raw_fd()).and_then(|reader| Layout::read(&reader));
+        self.unlockall_read()?;
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::FREELIST_TOP;
+    use crate::{O_CREAT, O_RDWR};
+
+    fn create(path: &std::path::Path) -> Tdb {
+        let mut tdb = Tdb::open(path, Some(7), Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        for i in 0..40u32 {
+            tdb.store(&i.to_le_bytes(), &[1; 50], None).unwrap();
+        }
+        for i in (0..40u32).step_by(3) {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        tdb
+    }
+
+    #[test]
+    fn test_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = create(&dir.path().join("test.tdb"));
+        let layout = tdb.layout().unwrap();
+        assert_eq!(layout.chains.len(), 7);
+        assert_eq!(layout.records(), 26);
+        assert_eq!(layout.map_size, tdb.map_size());
+        assert_eq!(layout.free_blocks.len(), tdb.freelist_size() as usize);
+        for record in layout.chains.iter().flatten().filter(|r| !r.dead) {
+            assert_eq!((record.key_len, record.data_len), (4, 50));
+        }
+        for chain in &layout.chains {
+            for pair in chain.windows(2) {
+                assert_eq!(pair[0].next, pair[1].offset);
+            }
+            assert_eq!(chain.last().map_or(0, |r| r.next), 0);
+        }
+        assert!(layout.free_bytes() >= layout.largest_free_block() as u64);
+
+        let memory = Tdb::memory(None, Flags::empty()).unwrap();
+        assert!(matches!(memory.layout(), Err(Error::Invalid)));
+    }
+
+    #[test]
+    fn test_layout_keeps_locks() {
+        crate::test::assert_keeps_locks("layout::test::test_layout_keeps_locks", |tdb| {
+            tdb.layout().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_free_bytes_large_blocks() {
+        let block = FreeBlock {
+            offset: FREELIST_TOP,
+            rec_len: u32::MAX,
+        };
+        let layout = Layout {
+            map_size: 0,
+            chains: Vec::new(),
+            free_blocks: vec![block; 2],
+        };
+        assert_eq!(
+            layout.free_bytes(),
+            2 * (u32::MAX as u64 + RECORD_LEN as u64)
+        );
+    }
+
+    #[test]
+    fn test_validate_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let tdb = create(&path);
+        let free = tdb.layout().unwrap().free_blocks;
+        assert!(!free.is_empty());
+        assert_eq!(tdb.validate_freelist().unwrap() as usize, free.len());
+        drop(tdb);
+
+        // Make the freelist loop back onto its first block.
+        let mut data = std::fs::read(&path).unwrap();
+        let last = free.last().unwrap().offset as usize;
+        let first = &data[FREELIST_TOP as usize..FREELIST_TOP as usize + 4].to_vec();
+        data[last..last + 4].copy_from_slice(first);
+        std::fs::write(&path, data).unwrap();
+
+        let tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR, 0).unwrap();
+        assert!(matches!(tdb.validate_freelist(), Err(Error::Corrupt)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = create(&dir.path().join("test.tdb"));
+        let layout = tdb.layout().unwrap();
+        let json = serde_json::to_value(&layout).unwrap();
+        assert_eq!(json["map_size"], layout.map_size);
+        assert_eq!(json["chains"].as_array().unwrap().len(), 7);
+        assert_eq!(
+            json["free_blocks"][0]["rec_len"],
+            layout.free_blocks[0].rec_len
+        );
+    }
+}
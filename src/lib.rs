@@ -104,32 +104,150 @@
 //! - **Single-process access**: `Flags::NoLock` (faster, but unsafe with multiple processes)
 //! - **Better hashing**: `Flags::IncompatibleHash` (not compatible with TDB < 1.2.6)
 //! - **Nested transactions**: `Flags::AllowNesting`
+//!
+//! # Reading Without libtdb
+//!
+//! The [`reader`] module contains `TdbReader`, a read-only parser for the TDB file format
+//! written in pure Rust. It maps the file into memory and never calls into libtdb.
+//!
+//! # Optional Features
+//!
+//! - **`libtdb`** (default): Link against the system libtdb.
+//! - **`native`**: Use a pure-Rust implementation of TDB instead of libtdb. Files and locks are
+//!   compatible with libtdb, so processes using either implementation can share a database.
+//!   Robust mutex locking is not supported. `libtdb` is a default feature, and trivialdb-sys
+//!   is still built whenever it is enabled, so build with
+//!   `--no-default-features --features native` (or `default-features = false` in
+//!   `Cargo.toml`) to need neither the C library nor bindgen.
+//! - **`zstd`**: `CompressedTdb`, which transparently compresses large values.
+//! - **`encryption`**: `EncryptedTdb`, which seals values with ChaCha20-Poly1305.
+//! - **`serde`**: `Serialize` for [`Stats`] and [`Layout`].
+//! - **`bytes`**: [`Tdb::fetch_bytes`], which returns values as `bytes::Bytes` without copying
+//!   them, and [`Tdb::store_buf`] and [`Tdb::append_buf`], which take any `bytes::Buf`.
+//! - **`rayon`**: [`Tdb::par_records`], a rayon `ParallelIterator` over the records of a
+//!   database file.
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+#[cfg(not(feature = "native"))]
 mod generated {
-    #![allow(dead_code)]
-    include!(concat!(env!("OUT_DIR"), "/tdb_sys.rs"));
+    pub use trivialdb_sys::*;
 
-    #[repr(C)]
-    pub struct TDB_DATA {
-        pub dptr: *mut std::os::raw::c_uchar,
-        pub dsize: usize,
-    }
+    /// Only declared by tdb >= 1.3.0; older releases don't know about the flag.
+    #[cfg(not(tdb_mutex_locking))]
+    pub const TDB_MUTEX_LOCKING: u32 = 4096;
 }
 
-use generated::TDB_DATA;
+/// The constants from `tdb.h` that the public API is defined in terms of, for builds that
+/// don't run bindgen.
+#[cfg(feature = "native")]
+mod generated {
+    #![allow(dead_code)]
+    pub type mode_t = libc::mode_t;
+    pub type TDB_ERROR = u32;
+
+    pub const TDB_REPLACE: u32 = 1;
+    pub const TDB_INSERT: u32 = 2;
+    pub const TDB_MODIFY: u32 = 3;
+
+    pub const TDB_CLEAR_IF_FIRST: u32 = 1;
+    pub const TDB_INTERNAL: u32 = 2;
+    pub const TDB_NOLOCK: u32 = 4;
+    pub const TDB_NOMMAP: u32 = 8;
+    pub const TDB_CONVERT: u32 = 16;
+    pub const TDB_BIGENDIAN: u32 = 32;
+    pub const TDB_NOSYNC: u32 = 64;
+    pub const TDB_SEQNUM: u32 = 128;
+    pub const TDB_VOLATILE: u32 = 256;
+    pub const TDB_ALLOW_NESTING: u32 = 512;
+    pub const TDB_DISALLOW_NESTING: u32 = 1024;
+    pub const TDB_INCOMPATIBLE_HASH: u32 = 2048;
+    pub const TDB_MUTEX_LOCKING: u32 = 4096;
+
+    pub const TDB_ERROR_TDB_SUCCESS: TDB_ERROR = 0;
+    pub const TDB_ERROR_TDB_ERR_CORRUPT: TDB_ERROR = 1;
+    pub const TDB_ERROR_TDB_ERR_IO: TDB_ERROR = 2;
+    pub const TDB_ERROR_TDB_ERR_LOCK: TDB_ERROR = 3;
+    pub const TDB_ERROR_TDB_ERR_OOM: TDB_ERROR = 4;
+    pub const TDB_ERROR_TDB_ERR_EXISTS: TDB_ERROR = 5;
+    pub const TDB_ERROR_TDB_ERR_NOLOCK: TDB_ERROR = 6;
+    pub const TDB_ERROR_TDB_ERR_LOCK_TIMEOUT: TDB_ERROR = 7;
+    pub const TDB_ERROR_TDB_ERR_NOEXIST: TDB_ERROR = 8;
+    pub const TDB_ERROR_TDB_ERR_EINVAL: TDB_ERROR = 9;
+    pub const TDB_ERROR_TDB_ERR_RDONLY: TDB_ERROR = 10;
+    pub const TDB_ERROR_TDB_ERR_NESTING: TDB_ERROR = 11;
+}
+
+#[cfg(not(any(feature = "libtdb", feature = "native")))]
+compile_error!("either the `libtdb` or the `native` feature must be enabled");
+
+#[cfg(not(feature = "native"))]
+mod ffi;
+#[cfg(not(feature = "native"))]
+use ffi as backend;
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "native")]
+use native as backend;
+// Tests against libtdb build the native backend as well, to check that the two can share a
+// database.
+#[cfg(all(test, not(feature = "native")))]
+#[allow(dead_code)]
+mod native;
+
+mod batch;
+pub use batch::{BatchError, BatchOp, WriteBatch};
+mod bulk;
+pub use bulk::{BulkLoader, LoadStats};
+#[cfg(feature = "bytes")]
+mod bytes;
+mod chains;
+pub use chains::{rehash, ChainAnalysis};
+#[cfg(feature = "zstd")]
+mod compression;
+#[cfg(feature = "zstd")]
+pub use compression::{CompressedTdb, CompressionStats};
+mod copy;
+mod counters;
+mod diff;
+pub use diff::{apply_diff, diff, ConflictStrategy, DiffEntry};
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptedTdb;
+mod entry;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+mod expiring;
+pub use expiring::{ExpiringTdb, ExpiringValue, Sweeper};
+mod fork;
+pub use fork::{install_fork_handler, reopen_all};
+mod journal;
+pub use journal::{JournaledTdb, Replayer, JOURNAL_SEQNUM_KEY};
+mod layout;
+pub use layout::{Layout, RecordLayout};
+mod maintenance;
+pub use maintenance::{
+    Maintenance, MaintenanceAction, MaintenancePolicy, MaintenanceThread, RepackReason,
+};
+mod namespace;
+pub use namespace::Namespace;
+mod parallel;
+#[cfg(feature = "rayon")]
+pub use parallel::ParRecords;
+pub mod reader;
+mod scan;
+pub use scan::{ScanPage, ScanToken};
+mod stats;
+pub use stats::Stats;
 
 use bitflags::bitflags;
-use std::ffi::CStr;
-use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 pub use libc::{O_CREAT, O_RDONLY, O_RDWR, O_TRUNC};
 
 /// A handle to a TDB database.
-pub struct Tdb(*mut generated::tdb_context);
+pub struct Tdb(backend::Handle);
 
 /// Errors that can occur when interacting with a Trivial Database
 #[derive(Debug)]
@@ -161,6 +279,7 @@ pub enum Error {
 
 bitflags! {
     /// Flags for opening a database
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Flags: u32 {
         /// Clear database if we are the only one with it open
         const ClearIfFirst = generated::TDB_CLEAR_IF_FIRST;
@@ -172,7 +291,7 @@ bitflags! {
         /// Don't do any locking
         const NoLock = generated::TDB_NOLOCK;
         /// Don't synchronise transactions to disk
-        const NoSync = generated::TDB_SEQNUM;
+        const NoSync = generated::TDB_NOSYNC;
         /// Maintain a sequence number
         const Seqnum = generated::TDB_SEQNUM;
         /// activate the per-hashchain freelist, default 5.
@@ -197,6 +316,7 @@ impl Default for Flags {
 
 /// Store option Flags
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StoreFlags {
     /// Don't overwrite an existing entry.
     Insert = generated::TDB_INSERT as isize,
@@ -253,99 +373,6 @@ impl From<i32> for Error {
     }
 }
 
-impl From<Vec<u8>> for TDB_DATA {
-    fn from(mut data: Vec<u8>) -> Self {
-        let ptr = data.as_mut_ptr() as *mut std::os::raw::c_uchar;
-        let len = data.len();
-        let cap = data.capacity();
-        std::mem::forget(data);
-        // Ensure we're using the exact allocated memory
-        debug_assert_eq!(len, cap, "Vector should be at exact capacity");
-        TDB_DATA {
-            dptr: ptr,
-            dsize: len,
-        }
-    }
-}
-
-impl Drop for TDB_DATA {
-    fn drop(&mut self) {
-        if !self.dptr.is_null() {
-            unsafe {
-                libc::free(self.dptr as *mut libc::c_void);
-            }
-        }
-    }
-}
-
-impl Clone for TDB_DATA {
-    fn clone(&self) -> Self {
-        if self.dsize == 0 || self.dptr.is_null() {
-            return TDB_DATA {
-                dptr: std::ptr::null_mut(),
-                dsize: 0,
-            };
-        }
-        unsafe {
-            let ptr = libc::malloc(self.dsize) as *mut std::os::raw::c_uchar;
-            if ptr.is_null() {
-                panic!("Failed to allocate memory for TDB_DATA clone");
-            }
-            std::ptr::copy_nonoverlapping(self.dptr, ptr, self.dsize);
-            TDB_DATA {
-                dptr: ptr,
-                dsize: self.dsize,
-            }
-        }
-    }
-}
-
-impl From<TDB_DATA> for Vec<u8> {
-    fn from(mut data: TDB_DATA) -> Self {
-        let ret = unsafe { Vec::from_raw_parts(data.dptr, data.dsize, data.dsize) };
-        data.dptr = std::ptr::null_mut();
-        ret
-    }
-}
-
-#[repr(C)]
-struct CONST_TDB_DATA {
-    pub dptr: *const std::os::raw::c_uchar,
-    pub dsize: usize,
-}
-
-impl From<&[u8]> for CONST_TDB_DATA {
-    fn from(data: &[u8]) -> Self {
-        CONST_TDB_DATA {
-            dptr: data.as_ptr(),
-            dsize: data.len(),
-        }
-    }
-}
-
-extern "C" {
-    fn tdb_fetch(tdb: *mut generated::tdb_context, key: CONST_TDB_DATA) -> TDB_DATA;
-
-    fn tdb_store(
-        tdb: *mut generated::tdb_context,
-        key: CONST_TDB_DATA,
-        dbuf: CONST_TDB_DATA,
-        flag: ::std::os::raw::c_int,
-    ) -> ::std::os::raw::c_int;
-
-    fn tdb_append(
-        tdb: *mut generated::tdb_context,
-        key: CONST_TDB_DATA,
-        new_dbuf: CONST_TDB_DATA,
-    ) -> ::std::os::raw::c_int;
-
-    fn tdb_exists(tdb: *mut generated::tdb_context, key: CONST_TDB_DATA) -> bool;
-
-    fn tdb_delete(tdb: *mut generated::tdb_context, key: CONST_TDB_DATA) -> ::std::os::raw::c_int;
-
-    fn tdb_nextkey(tdb: *mut generated::tdb_context, key: CONST_TDB_DATA) -> TDB_DATA;
-}
-
 impl Tdb {
     /// Open the database and creating it if necessary.
     ///
@@ -363,24 +390,7 @@ impl Tdb {
         open_flags: i32,
         mode: generated::mode_t,
     ) -> Option<Tdb> {
-        let name = name.as_ref();
-        let hash_size = hash_size.unwrap_or(0);
-        // Ensure null termination for C API
-        let c_name = std::ffi::CString::new(name.as_os_str().as_bytes()).ok()?;
-        let ret = unsafe {
-            generated::tdb_open(
-                c_name.as_ptr(),
-                hash_size as i32,
-                tdb_flags.bits() as i32,
-                open_flags,
-                mode,
-            )
-        };
-        if ret.is_null() {
-            None
-        } else {
-            Some(Tdb(ret))
-        }
+        backend::Handle::open(name.as_ref(), hash_size, tdb_flags, open_flags, mode).map(Tdb)
     }
 
     /// Create a database in memory
@@ -389,52 +399,22 @@ impl Tdb {
     ///
     /// * `hash_size` - The hash size is advisory, leave None for a default.
     /// * `tdb_flags` The flags to use to open the db:
-    pub fn memory(hash_size: Option<u32>, mut tdb_flags: Flags) -> Option<Tdb> {
-        let hash_size = hash_size.unwrap_or(0);
-        tdb_flags.insert(Flags::Internal);
-        let ret = unsafe {
-            generated::tdb_open(
-                c":memory:".as_ptr(),
-                hash_size as i32,
-                tdb_flags.bits() as i32,
-                O_RDWR | O_CREAT,
-                0,
-            )
-        };
-        if ret.is_null() {
-            None
-        } else {
-            Some(Tdb(ret))
-        }
-    }
-
-    /// Return the latest error that occurred
-    fn error(&self) -> Result<(), Error> {
-        // Safety: self.0 is guaranteed to be a valid pointer for the lifetime of self
-        let err = unsafe { generated::tdb_error(self.0) };
-        if err == 0 {
-            Ok(())
-        } else {
-            Err(err.into())
-        }
+    pub fn memory(hash_size: Option<u32>, tdb_flags: Flags) -> Option<Tdb> {
+        backend::Handle::memory(hash_size, tdb_flags).map(Tdb)
     }
 
     /// Set the maximum number of dead records per hash chain.
     pub fn set_max_dead(&mut self, max_dead: u32) {
-        unsafe { generated::tdb_set_max_dead(self.0, max_dead as i32) };
+        self.0.set_max_dead(max_dead)
     }
 
     /// Reopen the database
     ///
     /// This can be used to reopen a database after a fork, to ensure that we have an independent
-    /// seek pointer and to re-establish any locks.
+    /// seek pointer and to re-establish any locks. See [`reopen_all`] and
+    /// [`install_fork_handler`] to reopen all databases at once.
     pub fn reopen(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_reopen(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.reopen()
     }
 
     /// Fetch a value from the database.
@@ -448,18 +428,7 @@ impl Tdb {
     /// * `Ok(None)` - The key was not found.
     /// * `Err(e)` - An error occurred.
     pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-        let ret = unsafe { tdb_fetch(self.0, key.into()) };
-        if ret.dptr.is_null() {
-            match self.error() {
-                Err(Error::NoExist) => Ok(None),
-                Err(e) => Err(e),
-                Ok(_) => panic!("error but no error?"),
-            }
-        } else {
-            // TODO(jelmer): Call Vec::from_raw_parts_in here once the allocator API is stable.
-            // https://github.com/rust-lang/rust/issues/32838
-            Ok(Some(ret.into()))
-        }
+        self.0.fetch(key)
     }
 
     /// Store a key/value pair in the database.
@@ -475,13 +444,23 @@ impl Tdb {
         val: &[u8],
         flags: Option<StoreFlags>,
     ) -> Result<(), Error> {
-        let flags = flags.map_or(0, |f| f as i32);
-        let ret = unsafe { tdb_store(self.0, key.into(), val.into(), flags) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.store(key, val, flags)
+    }
+
+    /// Store a value made up of several buffers, without concatenating them first.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store.
+    /// * `vals` - The buffers that make up the value, in order.
+    /// * `flags` - As for [`Tdb::store`].
+    pub fn storev(
+        &mut self,
+        key: &[u8],
+        vals: &[&[u8]],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        self.0.storev(key, vals, flags)
     }
 
     /// Delete a key from the database.
@@ -490,12 +469,7 @@ impl Tdb {
     ///
     /// * `key` - The key to delete
     pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
-        let ret = unsafe { tdb_delete(self.0, key.into()) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.delete(key)
     }
 
     /// Append a value to an existing key.
@@ -504,253 +478,336 @@ impl Tdb {
     /// * `key` - The key to append to.
     /// * `val` - The value to append.
     pub fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
-        let ret = unsafe { tdb_append(self.0, key.into(), val.into()) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.append(key, val)
     }
 
     /// Iterate over all keys in the database.
     pub fn keys(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
-        TdbKeys(self, None)
+        self.0.keys()
     }
 
     /// Iterate over all key/value pairs in the database.
     pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
-        TdbIter(self, TdbKeys(self, None))
+        TdbIter(self, self.0.keys())
+    }
+
+    /// Atomically update the value of a key.
+    ///
+    /// The hash chain of the key is locked while the current value is read, `f` is called and
+    /// its result is written back, so concurrent updates from other processes cannot be lost.
+    ///
+    /// # Arguments
+    /// * `key` - The key to update.
+    /// * `f` - Called with the current value, or `None` if the key does not exist. Returning
+    ///   `Some(value)` stores the new value, returning `None` deletes the key.
+    pub fn update<F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>>(
+        &mut self,
+        key: &[u8],
+        f: F,
+    ) -> Result<(), Error> {
+        self.with_chainlock(key, |tdb| {
+            let old = tdb.fetch(key)?;
+            match (f(old.as_deref()), old) {
+                (Some(new), _) => tdb.store(key, &new, None),
+                (None, Some(_)) => tdb.delete(key),
+                (None, None) => Ok(()),
+            }
+        })
+    }
+
+    /// Atomically replace the value of a key if it currently has the expected value.
+    ///
+    /// # Arguments
+    /// * `key` - The key to update.
+    /// * `expected` - The expected current value, or `None` if the key is expected not to exist.
+    /// * `new` - The value to store, or `None` to delete the key.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The current value matched and has been replaced.
+    /// * `Ok(false)` - The current value did not match; the database was not modified.
+    /// * `Err(e)` - An error occurred.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error> {
+        self.with_chainlock(key, |tdb| {
+            let old = tdb.fetch(key)?;
+            if old.as_deref() != expected {
+                return Ok(false);
+            }
+            match (new, old) {
+                (Some(new), _) => tdb.store(key, new, None)?,
+                (None, Some(_)) => tdb.delete(key)?,
+                (None, None) => {}
+            }
+            Ok(true)
+        })
+    }
+
+    /// Run `f` while holding the write lock on the hash chain of `key`.
+    pub(crate) fn with_chainlock<R>(
+        &mut self,
+        key: &[u8],
+        f: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.chainlock(key)?;
+        // Make sure the chain is unlocked again even if `f` panics.
+        let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self)));
+        let unlocked = self.chainunlock(key);
+        match ret {
+            Ok(ret) => {
+                let ret = ret?;
+                unlocked?;
+                Ok(ret)
+            }
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+
+    /// Run `f` atomically: inside a transaction that is committed if `f` succeeds and cancelled
+    /// if it fails.
+    ///
+    /// Memory databases do not support transactions, so for those the whole database is locked
+    /// instead and changes made before a failure are kept.
+    pub(crate) fn with_transaction<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        if self.get_flags().contains(Flags::Internal) {
+            self.lockall()?;
+            let ret = f(self);
+            self.unlockall()?;
+            return ret;
+        }
+        self.transaction_start()?;
+        match f(self) {
+            Ok(ret) => {
+                self.transaction_commit()?;
+                Ok(ret)
+            }
+            Err(e) => {
+                self.transaction_cancel()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Traverse all records in the database, holding a write lock.
+    ///
+    /// The callback is called with each key and value, and should return `true` to continue
+    /// or `false` to stop the traversal early.
+    ///
+    /// # Returns
+    ///
+    /// The number of records visited.
+    pub fn traverse<F: FnMut(&[u8], &[u8]) -> bool>(&mut self, f: F) -> Result<usize, Error> {
+        self.0.traverse(true, f)
+    }
+
+    /// Traverse all records in the database, holding only a read lock.
+    ///
+    /// See [`Tdb::traverse`] for the semantics of the callback.
+    pub fn traverse_read<F: FnMut(&[u8], &[u8]) -> bool>(&self, f: F) -> Result<usize, Error> {
+        self.0.traverse(false, f)
     }
 
     /// Check if a particular key exists
     pub fn exists(&self, key: &[u8]) -> bool {
-        unsafe { tdb_exists(self.0, key.into()) }
+        self.0.exists(key)
+    }
+
+    /// Check the consistency of the database
+    ///
+    /// Every record must be on the hash chain of its key or on the freelist, exactly once. The
+    /// callback is called with the key and value of every live record while the database is
+    /// locked for reading, and returns `false` if the record is invalid.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The database is consistent.
+    /// * `Err(Error::Corrupt)` - The database is corrupt or the callback rejected a record.
+    pub fn check<F: FnMut(&[u8], &[u8]) -> bool>(&self, f: F) -> Result<(), Error> {
+        self.0.check(f)
     }
 
     /// Lock the database
     pub fn lockall(&self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_lockall(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.lockall()
     }
 
     /// Unlock the database
     pub fn unlockall(&self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_unlockall(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.unlockall()
     }
 
     /// Lock the database, non-blocking
     pub fn lockall_nonblock(&self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_lockall_nonblock(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.lockall_nonblock()
     }
 
     /// Lock the database for reading
     pub fn lockall_read(&self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_lockall_read(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.lockall_read()
     }
 
     /// Lock the database for reading, non-blocking
     pub fn lockall_read_nonblock(&self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_lockall_read_nonblock(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.lockall_read_nonblock()
+    }
+
+    /// Unlock the database after [`Tdb::lockall_read`]
+    pub fn unlockall_read(&self) -> Result<(), Error> {
+        self.0.unlockall_read()
+    }
+
+    /// Lock the hash chain of a key
+    ///
+    /// Other processes are blocked from accessing any key on the same hash chain until
+    /// [`Tdb::chainunlock`] is called.
+    pub fn chainlock(&self, key: &[u8]) -> Result<(), Error> {
+        self.0.chainlock(key)
+    }
+
+    /// Lock the hash chain of a key, non-blocking
+    pub fn chainlock_nonblock(&self, key: &[u8]) -> Result<(), Error> {
+        self.0.chainlock_nonblock(key)
+    }
+
+    /// Unlock the hash chain of a key
+    pub fn chainunlock(&self, key: &[u8]) -> Result<(), Error> {
+        self.0.chainunlock(key)
+    }
+
+    /// Lock the hash chain of a key for reading
+    pub fn chainlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        self.0.chainlock_read(key)
+    }
+
+    /// Unlock the hash chain of a key for reading
+    pub fn chainunlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        self.0.chainunlock_read(key)
     }
 
     /// Return the name of the database
     pub fn name(&self) -> &str {
-        unsafe { CStr::from_ptr(generated::tdb_name(self.0)) }
-            .to_str()
-            .unwrap()
+        self.0.name()
     }
 
     /// Return the hash size used by the database
     pub fn hash_size(&self) -> u32 {
-        unsafe { generated::tdb_hash_size(self.0) as u32 }
+        self.0.hash_size()
     }
 
     /// Return the map size used by the database
     pub fn map_size(&self) -> u32 {
-        unsafe { generated::tdb_map_size(self.0) as u32 }
+        self.0.map_size()
     }
 
     /// Return the current sequence number
     pub fn get_seqnum(&self) -> u64 {
-        unsafe { generated::tdb_get_seqnum(self.0) as u64 }
+        self.0.get_seqnum()
     }
 
     /// Return the current flags
     pub fn get_flags(&self) -> Flags {
-        Flags::from_bits_truncate(unsafe { generated::tdb_get_flags(self.0) as u32 })
+        self.0.get_flags()
     }
 
     /// Add a flag
     pub fn add_flags(&mut self, flags: Flags) {
-        unsafe { generated::tdb_add_flags(self.0, flags.bits()) };
+        self.0.add_flags(flags)
     }
 
     /// Remove a flag
     pub fn remove_flags(&mut self, flags: Flags) {
-        unsafe { generated::tdb_remove_flags(self.0, flags.bits()) };
+        self.0.remove_flags(flags)
     }
 
     /// Enable sequence numbers
     pub fn enable_seqnum(&mut self) {
-        unsafe { generated::tdb_enable_seqnum(self.0) };
+        self.0.enable_seqnum()
     }
 
     /// Increment the sequence number
     pub fn increment_seqnum_nonblock(&mut self) {
-        unsafe { generated::tdb_increment_seqnum_nonblock(self.0) };
+        self.0.increment_seqnum_nonblock()
     }
 
     /// Repack the database
     pub fn repack(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_repack(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.repack()
     }
 
     /// Wipe the database
     pub fn wipe_all(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_wipe_all(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.wipe_all()
     }
 
     /// Return a string summarizing the database
+    ///
+    /// See [`Tdb::stats`] for the same information as a [`Stats`] struct.
     pub fn summary(&self) -> String {
-        let buf = unsafe { generated::tdb_summary(self.0) };
-        unsafe { CStr::from_ptr(buf) }.to_str().unwrap().to_owned()
+        self.0.summary()
     }
 
     /// Return the freelist size
     pub fn freelist_size(&self) -> u32 {
-        unsafe { generated::tdb_freelist_size(self.0) as u32 }
+        self.0.freelist_size()
+    }
+
+    /// Check the freelist for loops and records that are not free
+    ///
+    /// See [`Tdb::layout`] for the blocks on the freelist.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(entries)` - The freelist is valid and has this many entries.
+    /// * `Err(Error::Corrupt)` - The freelist is corrupt.
+    pub fn validate_freelist(&self) -> Result<u32, Error> {
+        self.0.validate_freelist()
     }
 
     /// Start a new transaction
     pub fn transaction_start(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_transaction_start(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.transaction_start()
     }
 
     /// Check if a transaction is active
     pub fn transaction_active(&self) -> bool {
-        unsafe { generated::tdb_transaction_active(self.0) }
+        self.0.transaction_active()
     }
 
     /// Start a new transaction, non-blocking
     pub fn transaction_start_nonblock(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_transaction_start_nonblock(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.transaction_start_nonblock()
     }
 
     /// Prepare to commit a transaction
     pub fn transaction_prepare_commit(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_transaction_prepare_commit(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.transaction_prepare_commit()
     }
 
     /// Commit a transaction
     pub fn transaction_commit(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_transaction_commit(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.transaction_commit()
     }
 
     /// Cancel a transaction
     pub fn transaction_cancel(&mut self) -> Result<(), Error> {
-        let ret = unsafe { generated::tdb_transaction_cancel(self.0) };
-        if ret == -1 {
-            self.error()
-        } else {
-            Ok(())
-        }
+        self.0.transaction_cancel()
     }
 }
 
 impl AsRawFd for Tdb {
     fn as_raw_fd(&self) -> RawFd {
-        unsafe { generated::tdb_fd(self.0) }
-    }
-}
-
-struct TdbKeys<'a>(&'a Tdb, Option<TDB_DATA>);
-
-impl Iterator for TdbKeys<'_> {
-    type Item = Vec<u8>;
-
-    fn next(&mut self) -> Option<Vec<u8>> {
-        let key = if let Some(prev_key) = self.1.take() {
-            // Convert TDB_DATA to CONST_TDB_DATA for the call
-            let const_key = CONST_TDB_DATA {
-                dptr: prev_key.dptr as *const _,
-                dsize: prev_key.dsize,
-            };
-            let result = unsafe { tdb_nextkey(self.0 .0, const_key) };
-            // Clean up the previous key
-            drop(prev_key);
-            result
-        } else {
-            unsafe { generated::tdb_firstkey(self.0 .0) }
-        };
-        if key.dptr.is_null() {
-            match self.0.error() {
-                Err(Error::NoExist) | Ok(_) => None,
-                Err(e) => panic!("TDB iterator error: {}", e),
-            }
-        } else {
-            // Store the key for the next iteration
-            self.1 = Some(key.clone());
-            // Return the key as Vec<u8>
-            Some(key.into())
-        }
+        self.0.fd()
     }
 }
 
-struct TdbIter<'a>(&'a Tdb, TdbKeys<'a>);
+struct TdbIter<'a>(&'a Tdb, backend::Keys<'a>);
 
 impl Iterator for TdbIter<'_> {
     type Item = (Vec<u8>, Vec<u8>);
@@ -771,16 +828,9 @@ impl Iterator for TdbIter<'_> {
     }
 }
 
-impl Drop for Tdb {
-    fn drop(&mut self) {
-        unsafe { generated::tdb_close(self.0) };
-    }
-}
-
 /// Generate the jenkins hash of a key
 pub fn jenkins_hash(key: &[u8]) -> u32 {
-    let mut tdb_key = CONST_TDB_DATA::from(key);
-    unsafe { generated::tdb_jenkins_hash(&mut tdb_key as *mut _ as *mut TDB_DATA) }
+    backend::jenkins_hash(key)
 }
 
 #[cfg(test)]
@@ -812,6 +862,56 @@ mod test {
         }
     }
 
+    /// Start a copy of the test binary that only runs the test `name`, with the environment
+    /// variable `var` set to `value`.
+    ///
+    /// Forking the multi-threaded test harness is unsafe, so tests that need a second process
+    /// run themselves again, and look at `var` to tell which process they are in.
+    pub(crate) fn spawn_test(
+        name: &str,
+        var: &str,
+        value: impl AsRef<std::ffi::OsStr>,
+    ) -> std::process::Child {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", name])
+            .env(var, value)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    /// Check that `f` leaves the locks this process holds on a database file alone.
+    ///
+    /// The database is opened with `Flags::ClearIfFirst`, and another process then opens it
+    /// as well. That process would wipe the database if `f` had released the lock that marks
+    /// it as in use, as closing any other descriptor for the file does.
+    pub(crate) fn assert_keeps_locks(test: &str, f: impl FnOnce(&mut super::Tdb)) {
+        const CHILD_ENV: &str = "TRIVIALDB_LOCKED_PATH";
+        let open = |path: &std::path::Path| {
+            super::Tdb::open(
+                path,
+                None,
+                super::Flags::ClearIfFirst,
+                libc::O_RDWR | libc::O_CREAT,
+                0o600,
+            )
+            .unwrap()
+        };
+        if let Some(path) = std::env::var_os(CHILD_ENV) {
+            assert!(open(path.as_ref()).exists(b"key"));
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = open(&path);
+        tdb.store(b"key", b"value", None).unwrap();
+        f(&mut tdb);
+        let mut child = spawn_test(test, CHILD_ENV, &path);
+        assert!(child.wait().unwrap().success());
+        assert!(tdb.exists(b"key"));
+    }
+
     fn testtdb() -> TestTdb {
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("test.tdb");
@@ -885,6 +985,43 @@ mod test {
         assert_eq!(keys.next(), None);
     }
 
+    #[test]
+    fn test_traverse() {
+        let mut tdb = testtdb();
+
+        tdb.store(b"foo", b"bar", None).unwrap();
+        tdb.store(b"blah", b"bloe", None).unwrap();
+
+        let mut items = Vec::new();
+        let count = tdb
+            .traverse(|key, val| {
+                items.push((key.to_vec(), val.to_vec()));
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        items.sort();
+        assert_eq!(
+            items,
+            vec![
+                (b"blah".to_vec(), b"bloe".to_vec()),
+                (b"foo".to_vec(), b"bar".to_vec())
+            ]
+        );
+
+        // Returning false stops the traversal
+        assert_eq!(tdb.traverse_read(|_, _| false).unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "callback panicked")]
+    fn test_traverse_panic() {
+        let mut tdb = testtdb();
+        tdb.store(b"foo", b"bar", None).unwrap();
+        tdb.traverse_read(|_, _| panic!("callback panicked"))
+            .unwrap();
+    }
+
     #[test]
     fn test_transaction() {
         let mut tdb = testtdb();
@@ -1043,6 +1180,73 @@ mod test {
         assert_eq!(tdb.fetch(b"foo").unwrap().unwrap(), b"barbaz");
     }
 
+    #[test]
+    fn test_chainlock() {
+        let tdb = testtdb();
+
+        tdb.chainlock(b"foo").unwrap();
+        tdb.chainunlock(b"foo").unwrap();
+
+        tdb.chainlock_nonblock(b"foo").unwrap();
+        tdb.chainunlock(b"foo").unwrap();
+
+        tdb.chainlock_read(b"foo").unwrap();
+        tdb.chainunlock_read(b"foo").unwrap();
+    }
+
+    #[test]
+    fn test_update() {
+        let mut tdb = testtdb();
+
+        // Create a missing key
+        tdb.update(b"counter", |old| {
+            assert_eq!(old, None);
+            Some(b"1".to_vec())
+        })
+        .unwrap();
+        assert_eq!(tdb.fetch(b"counter").unwrap().unwrap(), b"1");
+
+        // Modify an existing key
+        tdb.update(b"counter", |old| {
+            let mut new = old.unwrap().to_vec();
+            new.push(b'0');
+            Some(new)
+        })
+        .unwrap();
+        assert_eq!(tdb.fetch(b"counter").unwrap().unwrap(), b"10");
+
+        // Delete it again
+        tdb.update(b"counter", |_| None).unwrap();
+        assert!(!tdb.exists(b"counter"));
+
+        // Deleting a missing key is not an error
+        tdb.update(b"counter", |_| None).unwrap();
+
+        // The chain lock has been released
+        tdb.chainlock_nonblock(b"counter").unwrap();
+        tdb.chainunlock(b"counter").unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut tdb = testtdb();
+
+        assert!(tdb.compare_and_swap(b"foo", None, Some(b"bar")).unwrap());
+        assert!(!tdb.compare_and_swap(b"foo", None, Some(b"baz")).unwrap());
+        assert!(!tdb
+            .compare_and_swap(b"foo", Some(b"baz"), Some(b"qux"))
+            .unwrap());
+        assert_eq!(tdb.fetch(b"foo").unwrap().unwrap(), b"bar");
+
+        assert!(tdb
+            .compare_and_swap(b"foo", Some(b"bar"), Some(b"baz"))
+            .unwrap());
+        assert_eq!(tdb.fetch(b"foo").unwrap().unwrap(), b"baz");
+
+        assert!(tdb.compare_and_swap(b"foo", Some(b"baz"), None).unwrap());
+        assert!(!tdb.exists(b"foo"));
+    }
+
     #[test]
     fn test_wipe_all() {
         let mut tdb = testtdb();
@@ -1089,6 +1293,45 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = super::Tdb::open(
+            &path,
+            Some(1),
+            super::Flags::empty(),
+            libc::O_RDWR | libc::O_CREAT,
+            0o600,
+        )
+        .unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            tdb.store(key.as_bytes(), b"value", None).unwrap();
+        }
+        tdb.delete(b"key3").unwrap();
+
+        let mut seen = 0;
+        tdb.check(|_, value| {
+            seen += 1;
+            value == b"value"
+        })
+        .unwrap();
+        assert_eq!(seen, 9);
+        assert!(matches!(
+            tdb.check(|key, _| key != b"key5"),
+            Err(Error::Corrupt)
+        ));
+        drop(tdb);
+
+        // Cut the only hash chain short, leaving records that can't be reached.
+        let mut data = std::fs::read(&path).unwrap();
+        data[172..176].copy_from_slice(&[0; 4]);
+        std::fs::write(&path, data).unwrap();
+        let tdb = super::Tdb::open(&path, None, super::Flags::empty(), libc::O_RDWR, 0).unwrap();
+        assert!(matches!(tdb.check(|_, _| true), Err(Error::Corrupt)));
+    }
+
     #[test]
     fn test_set_max_dead() {
         let mut tdb = testtdb();
@@ -1119,29 +1362,6 @@ mod test {
         assert!(fd > 0);
     }
 
-    #[test]
-    fn test_tdb_data_clone() {
-        // Test cloning empty TDB_DATA
-        let empty_data = TDB_DATA {
-            dptr: std::ptr::null_mut(),
-            dsize: 0,
-        };
-        let cloned_empty = empty_data.clone();
-        assert!(cloned_empty.dptr.is_null());
-        assert_eq!(cloned_empty.dsize, 0);
-
-        // Test cloning non-empty TDB_DATA
-        let vec = vec![1, 2, 3, 4, 5];
-        let data: TDB_DATA = vec.into();
-        let cloned = data.clone();
-        assert!(!cloned.dptr.is_null());
-        assert_eq!(cloned.dsize, 5);
-
-        // Convert back to Vec to verify content
-        let vec_back: Vec<u8> = cloned.into();
-        assert_eq!(vec_back, vec![1, 2, 3, 4, 5]);
-    }
-
     #[test]
     fn test_error_display() {
         // Test all error variants display correctly
@@ -0,0 +1,461 @@
+//! Repacking databases and tuning dead records automatically, rather than calling
+//! [`Tdb::repack`] and [`Tdb::set_max_dead`] by hand.
+//!
+//! A [`Maintenance`] helper looks at the [`Layout`] of a database file and repacks it when its
+//! free space is too fragmented or too many of its records are dead. It can also check the
+//! database periodically. It is run either inline, every so many writes, or from a
+//! [`MaintenanceThread`].
+//!
+//! ```rust
+//! use trivialdb::{Flags, Maintenance, MaintenancePolicy, Tdb, O_CREAT, O_RDWR};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("test.tdb");
+//! let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+//!
+//! let mut maintenance = Maintenance::new(MaintenancePolicy {
+//!     writes_between_runs: 100,
+//!     ..Default::default()
+//! });
+//! maintenance.set_hook(|action| println!("maintenance: {:?}", action));
+//! for i in 0..1000u32 {
+//!     tdb.store(&i.to_le_bytes(), b"value", None).unwrap();
+//!     maintenance.note_writes(&mut tdb, 1).unwrap();
+//! }
+//! ```
+
+use crate::reader::RECORD_LEN;
+use crate::{Error, Flags, Layout, Tdb};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// When [`Maintenance`] acts on a database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenancePolicy {
+    /// Repack when free blocks other than the largest one take up more than this fraction of
+    /// the file.
+    ///
+    /// Repacking gathers the free space into a single block, but does not shrink the file, so
+    /// the total amount of free space is not a useful threshold.
+    pub max_fragmentation: f64,
+    /// Repack when more than this fraction of the records in the hash chains are dead.
+    pub max_dead_ratio: f64,
+    /// Don't repack files smaller than this.
+    pub min_map_size: u32,
+    /// Passed to [`Tdb::set_max_dead`] the first time the database is maintained.
+    pub max_dead: Option<u32>,
+    /// Run [`Tdb::check`] at most this often, or never if `None`.
+    pub check_interval: Option<Duration>,
+    /// How many writes [`Maintenance::note_writes`] waits for between runs.
+    pub writes_between_runs: u64,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        MaintenancePolicy {
+            max_fragmentation: 0.25,
+            max_dead_ratio: 0.25,
+            min_map_size: 1 << 20,
+            max_dead: None,
+            check_interval: None,
+            writes_between_runs: 1000,
+        }
+    }
+}
+
+/// Why [`Maintenance`] repacked a database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepackReason {
+    /// Too much of the file was in free blocks other than the largest one.
+    Fragmentation {
+        /// The size of the free blocks other than the largest one.
+        fragmented_bytes: u64,
+        /// The size of the file.
+        map_size: u32,
+    },
+    /// Too many records were dead.
+    DeadRecords {
+        /// The number of dead records.
+        dead: usize,
+        /// The number of live records.
+        records: usize,
+    },
+}
+
+/// Something [`Maintenance`] did to a database.
+#[derive(Debug)]
+pub enum MaintenanceAction {
+    /// [`Tdb::set_max_dead`] was called with this value.
+    SetMaxDead(u32),
+    /// [`Tdb::check`] found the database to be consistent.
+    Checked,
+    /// [`Tdb::check`] failed, so the database was left alone.
+    CheckFailed(Error),
+    /// The database was repacked.
+    Repacked {
+        /// Why the database was repacked.
+        reason: RepackReason,
+        /// The size of the file before repacking.
+        map_size_before: u32,
+        /// The size of the file after repacking.
+        map_size_after: u32,
+    },
+}
+
+/// A function called with every action [`Maintenance`] takes.
+type Hook = Box<dyn FnMut(&MaintenanceAction) + Send>;
+
+/// Decides when to repack or check a database, and does so.
+///
+/// Memory databases can't be repacked, so they are only checked.
+pub struct Maintenance {
+    policy: MaintenancePolicy,
+    hook: Option<Hook>,
+    max_dead_set: bool,
+    last_check: Option<Instant>,
+    writes: u64,
+}
+
+impl Maintenance {
+    /// Create a helper with the given policy.
+    pub fn new(policy: MaintenancePolicy) -> Self {
+        Maintenance {
+            policy,
+            hook: None,
+            max_dead_set: false,
+            last_check: None,
+            writes: 0,
+        }
+    }
+
+    /// Return the policy.
+    pub fn policy(&self) -> &MaintenancePolicy {
+        &self.policy
+    }
+
+    /// Call `hook` with every action taken, e.g. to log it.
+    pub fn set_hook<F: FnMut(&MaintenanceAction) + Send + 'static>(&mut self, hook: F) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    fn report(&mut self, actions: &mut Vec<MaintenanceAction>, action: MaintenanceAction) {
+        if let Some(hook) = &mut self.hook {
+            hook(&action);
+        }
+        actions.push(action);
+    }
+
+    fn repack_reason(&self, layout: &Layout) -> Option<RepackReason> {
+        if layout.map_size < self.policy.min_map_size {
+            return None;
+        }
+        let largest = layout
+            .free_blocks
+            .iter()
+            .map(|block| block.rec_len as u64 + RECORD_LEN as u64)
+            .max()
+            .unwrap_or(0);
+        let fragmented_bytes = layout.free_bytes() - largest;
+        if fragmented_bytes as f64 > layout.map_size as f64 * self.policy.max_fragmentation {
+            return Some(RepackReason::Fragmentation {
+                fragmented_bytes,
+                map_size: layout.map_size,
+            });
+        }
+        let records = layout.records();
+        let dead = layout.chains.iter().flatten().filter(|r| r.dead).count();
+        if dead as f64 > (records + dead) as f64 * self.policy.max_dead_ratio {
+            return Some(RepackReason::DeadRecords { dead, records });
+        }
+        None
+    }
+
+    /// Maintain a database now.
+    ///
+    /// # Returns
+    ///
+    /// The actions taken, which have also been passed to the hook.
+    pub fn run(&mut self, tdb: &mut Tdb) -> Result<Vec<MaintenanceAction>, Error> {
+        self.writes = 0;
+        let mut actions = Vec::new();
+        if !self.max_dead_set {
+            self.max_dead_set = true;
+            if let Some(max_dead) = self.policy.max_dead {
+                tdb.set_max_dead(max_dead);
+                self.report(&mut actions, MaintenanceAction::SetMaxDead(max_dead));
+            }
+        }
+        if let Some(interval) = self.policy.check_interval {
+            if self.last_check.is_none_or(|t| t.elapsed() >= interval) {
+                self.last_check = Some(Instant::now());
+                match tdb.check(|_, _| true) {
+                    Ok(()) => self.report(&mut actions, MaintenanceAction::Checked),
+                    Err(e) => {
+                        self.report(&mut actions, MaintenanceAction::CheckFailed(e));
+                        return Ok(actions);
+                    }
+                }
+            }
+        }
+        // Only walk the file for its layout if it is large enough to be repacked.
+        if tdb.get_flags().contains(Flags::Internal) || tdb.map_size() < self.policy.min_map_size {
+            return Ok(actions);
+        }
+        if let Some(reason) = self.repack_reason(&tdb.layout()?) {
+            let map_size_before = tdb.map_size();
+            tdb.repack()?;
+            let action = MaintenanceAction::Repacked {
+                reason,
+                map_size_before,
+                map_size_after: tdb.map_size(),
+            };
+            self.report(&mut actions, action);
+        }
+        Ok(actions)
+    }
+
+    /// Count writes to a database, maintaining it every
+    /// [`MaintenancePolicy::writes_between_runs`] writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `tdb` - The database that was written to.
+    /// * `count` - The number of writes since the last call.
+    ///
+    /// # Returns
+    ///
+    /// The actions taken, if the database was maintained.
+    pub fn note_writes(
+        &mut self,
+        tdb: &mut Tdb,
+        count: u64,
+    ) -> Result<Vec<MaintenanceAction>, Error> {
+        self.writes += count;
+        if self.writes < self.policy.writes_between_runs {
+            return Ok(Vec::new());
+        }
+        self.run(tdb)
+    }
+}
+
+/// A background thread that periodically runs [`Maintenance`] on a database.
+///
+/// The thread is stopped when it is dropped. Errors from individual runs are ignored; the
+/// database is simply maintained again after the next interval. Use
+/// [`Maintenance::set_hook`] to find out what the thread does.
+///
+/// As with [`crate::Sweeper`], the database is shared with the thread through a mutex.
+pub struct MaintenanceThread {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<Maintenance>>,
+    runs: Arc<AtomicUsize>,
+}
+
+impl MaintenanceThread {
+    /// Start a thread that calls [`Maintenance::run`] every `interval`.
+    pub fn spawn(db: Arc<Mutex<Tdb>>, mut maintenance: Maintenance, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let runs = runs.clone();
+            std::thread::spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let mut tdb = match db.lock() {
+                        Ok(tdb) => tdb,
+                        Err(_) => break,
+                    };
+                    if maintenance.run(&mut tdb).is_ok() {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                maintenance
+            })
+        };
+        MaintenanceThread {
+            stop: Some(stop),
+            thread: Some(thread),
+            runs,
+        }
+    }
+
+    /// Return the number of successful runs so far.
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    /// Stop the thread and wait for it to exit.
+    ///
+    /// # Returns
+    ///
+    /// The maintenance helper, or `None` if the thread panicked.
+    pub fn stop(mut self) -> Option<Maintenance> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Option<Maintenance> {
+        // Dropping the sender wakes up the thread.
+        self.stop.take();
+        self.thread.take()?.join().ok()
+    }
+}
+
+impl Drop for MaintenanceThread {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{O_CREAT, O_RDWR};
+
+    fn create(dir: &tempfile::TempDir) -> Tdb {
+        let path = dir.path().join("test.tdb");
+        let mut tdb = Tdb::open(path, Some(17), Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        for i in 0..200u32 {
+            tdb.store(&i.to_le_bytes(), &[0; 200], None).unwrap();
+        }
+        tdb
+    }
+
+    fn policy() -> MaintenancePolicy {
+        MaintenancePolicy {
+            min_map_size: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_repack_fragmented() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir);
+        let mut maintenance = Maintenance::new(policy());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        {
+            let seen = seen.clone();
+            maintenance.set_hook(move |action| seen.lock().unwrap().push(format!("{:?}", action)));
+        }
+        assert!(maintenance.run(&mut tdb).unwrap().is_empty());
+
+        // Every other record is freed, and the freed blocks can't be merged.
+        for i in (0..200u32).step_by(2) {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        let actions = maintenance.run(&mut tdb).unwrap();
+        assert!(matches!(
+            actions[..],
+            [MaintenanceAction::Repacked {
+                reason: RepackReason::Fragmentation { .. },
+                ..
+            }]
+        ));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(tdb.iter().count(), 100);
+        assert!(maintenance.run(&mut tdb).unwrap().is_empty());
+
+        // Small files are left alone.
+        for i in (1..200u32).step_by(4) {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            min_map_size: tdb.map_size() + 1,
+            ..policy()
+        });
+        assert!(maintenance.run(&mut tdb).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_keeps_locks() {
+        crate::test::assert_keeps_locks("maintenance::test::test_run_keeps_locks", |tdb| {
+            Maintenance::new(policy()).run(tdb).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_repack_reason_large_blocks() {
+        let block = crate::reader::FreeBlock {
+            offset: 0,
+            rec_len: u32::MAX,
+        };
+        let layout = Layout {
+            map_size: 1 << 20,
+            chains: Vec::new(),
+            free_blocks: vec![block; 2],
+        };
+        assert_eq!(
+            Maintenance::new(policy()).repack_reason(&layout),
+            Some(RepackReason::Fragmentation {
+                fragmented_bytes: u32::MAX as u64 + RECORD_LEN as u64,
+                map_size: 1 << 20,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dead_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir);
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            max_dead: Some(100),
+            max_fragmentation: 1.0,
+            ..policy()
+        });
+        let actions = maintenance.run(&mut tdb).unwrap();
+        assert!(matches!(actions[..], [MaintenanceAction::SetMaxDead(100)]));
+
+        for i in 0..100u32 {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        let actions = maintenance.run(&mut tdb).unwrap();
+        assert!(matches!(
+            actions[..],
+            [MaintenanceAction::Repacked {
+                reason: RepackReason::DeadRecords { records: 100, .. },
+                ..
+            }]
+        ));
+        assert_eq!(tdb.iter().count(), 100);
+    }
+
+    #[test]
+    fn test_note_writes_and_check() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            writes_between_runs: 10,
+            check_interval: Some(Duration::ZERO),
+            ..policy()
+        });
+        for i in 0..9u32 {
+            tdb.store(&i.to_le_bytes(), b"", None).unwrap();
+            assert!(maintenance.note_writes(&mut tdb, 1).unwrap().is_empty());
+        }
+        let actions = maintenance.note_writes(&mut tdb, 1).unwrap();
+        assert!(matches!(actions[..], [MaintenanceAction::Checked]));
+        assert!(maintenance.note_writes(&mut tdb, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir);
+        for i in (0..200u32).step_by(2) {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        let db = Arc::new(Mutex::new(tdb));
+        let thread = MaintenanceThread::spawn(
+            db.clone(),
+            Maintenance::new(policy()),
+            Duration::from_millis(10),
+        );
+        let start = Instant::now();
+        while thread.runs() == 0 && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(thread.stop().is_some());
+        let tdb = db.lock().unwrap();
+        assert!(tdb.layout().unwrap().free_blocks.len() <= 1);
+        assert_eq!(tdb.iter().count(), 100);
+    }
+}
@@ -0,0 +1,274 @@
+//! Prefixed views onto a single database.
+//!
+//! It is common to store several logical tables in one TDB file by giving each of them its own
+//! key prefix, e.g. `user:` and `config:`. A [`Namespace`] adds the prefix to keys on the way in
+//! and strips it on the way out, so code working with one table does not need to know about
+//! the others.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+//! tdb.store(b"config:timeout", b"30", None).unwrap();
+//!
+//! let mut users = tdb.namespace(b"user:");
+//! users.store(b"1", b"Alice", None).unwrap();
+//! assert_eq!(users.fetch(b"1").unwrap().unwrap(), b"Alice");
+//! assert_eq!(users.keys().unwrap().collect::<Vec<_>>(), vec![b"1".to_vec()]);
+//!
+//! assert!(tdb.exists(b"user:1"));
+//! ```
+
+use crate::{Error, StoreFlags, Tdb};
+
+/// A view of the records in a [`Tdb`] whose keys start with a common prefix.
+///
+/// Created with [`Tdb::namespace`]. Keys passed to and returned from a namespace do not include
+/// the prefix.
+pub struct Namespace<'a> {
+    tdb: &'a mut Tdb,
+    prefix: Vec<u8>,
+}
+
+impl Tdb {
+    /// Return a view of the records whose keys start with `prefix`.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The key prefix of the namespace, e.g. `b"user:"`.
+    pub fn namespace(&mut self, prefix: &[u8]) -> Namespace<'_> {
+        Namespace {
+            tdb: self,
+            prefix: prefix.to_vec(),
+        }
+    }
+}
+
+impl Namespace<'_> {
+    /// Return the prefix of this namespace.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    fn full_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut full = Vec::with_capacity(self.prefix.len() + key.len());
+        full.extend_from_slice(&self.prefix);
+        full.extend_from_slice(key);
+        full
+    }
+
+    /// Fetch a value from the namespace.
+    ///
+    /// # Arguments
+    /// * `key` - The key to fetch, without the prefix.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.tdb.fetch(&self.full_key(key))
+    }
+
+    /// Store a key/value pair in the namespace.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store, without the prefix.
+    /// * `val` - The value to store.
+    /// * `flags` - The flags to use when storing the value.
+    pub fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        let key = self.full_key(key);
+        self.tdb.store(&key, val, flags)
+    }
+
+    /// Append a value to a key in the namespace.
+    ///
+    /// # Arguments
+    /// * `key` - The key to append to, without the prefix.
+    /// * `val` - The value to append.
+    pub fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        let key = self.full_key(key);
+        self.tdb.append(&key, val)
+    }
+
+    /// Delete a key from the namespace.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to delete, without the prefix.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        let key = self.full_key(key);
+        self.tdb.delete(&key)
+    }
+
+    /// Check if a particular key exists in the namespace.
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.tdb.exists(&self.full_key(key))
+    }
+
+    /// Traverse the records in this namespace.
+    ///
+    /// The callback is called with the key (without the prefix) and value of every record in
+    /// the namespace, and should return `true` to continue or `false` to stop early.
+    ///
+    /// # Returns
+    ///
+    /// The number of records in the namespace that were visited.
+    pub fn traverse<F: FnMut(&[u8], &[u8]) -> bool>(&self, mut f: F) -> Result<usize, Error> {
+        let mut count = 0;
+        self.tdb
+            .traverse_read(|key, val| match key.strip_prefix(self.prefix.as_slice()) {
+                Some(key) => {
+                    count += 1;
+                    f(key, val)
+                }
+                None => true,
+            })?;
+        Ok(count)
+    }
+
+    /// Iterate over all keys in the namespace, without the prefix.
+    ///
+    /// The keys are collected up front with a single traversal.
+    pub fn keys(&self) -> Result<impl Iterator<Item = Vec<u8>>, Error> {
+        let mut keys = Vec::new();
+        self.traverse(|key, _| {
+            keys.push(key.to_vec());
+            true
+        })?;
+        Ok(keys.into_iter())
+    }
+
+    /// Iterate over all key/value pairs in the namespace, with the prefix stripped from the keys.
+    ///
+    /// The records are collected up front with a single traversal.
+    pub fn iter(&self) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, Error> {
+        let mut items = Vec::new();
+        self.traverse(|key, val| {
+            items.push((key.to_vec(), val.to_vec()));
+            true
+        })?;
+        Ok(items.into_iter())
+    }
+
+    /// Delete all records in the namespace, leaving other records untouched.
+    ///
+    /// For file databases this happens inside a transaction, so other processes see either all
+    /// or none of the namespace. Memory databases do not support transactions, so the whole
+    /// database is locked instead.
+    ///
+    /// # Returns
+    ///
+    /// The number of records deleted.
+    pub fn clear(&mut self) -> Result<usize, Error> {
+        let prefix = &self.prefix;
+        self.tdb.with_transaction(|tdb| {
+            let mut keys = Vec::new();
+            tdb.traverse_read(|key, _| {
+                if key.starts_with(prefix) {
+                    keys.push(key.to_vec());
+                }
+                true
+            })?;
+            for key in &keys {
+                tdb.delete(key)?;
+            }
+            Ok(keys.len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Flags, StoreFlags, Tdb};
+
+    fn populated() -> Tdb {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"user:1", b"Alice", None).unwrap();
+        tdb.store(b"user:2", b"Bob", None).unwrap();
+        tdb.store(b"config:timeout", b"30", None).unwrap();
+        tdb
+    }
+
+    #[test]
+    fn test_prefixing() {
+        let mut tdb = populated();
+        let mut users = tdb.namespace(b"user:");
+        assert_eq!(users.prefix(), b"user:");
+        assert_eq!(users.fetch(b"1").unwrap().unwrap(), b"Alice");
+        assert_eq!(users.fetch(b"timeout").unwrap(), None);
+        assert!(users.exists(b"2"));
+
+        users.store(b"3", b"Charlie", None).unwrap();
+        users.append(b"3", b"!").unwrap();
+        users.delete(b"1").unwrap();
+        assert!(users
+            .store(b"2", b"Robert", Some(StoreFlags::Insert))
+            .is_err());
+
+        assert_eq!(tdb.fetch(b"user:3").unwrap().unwrap(), b"Charlie!");
+        assert!(!tdb.exists(b"user:1"));
+    }
+
+    #[test]
+    fn test_iteration() {
+        let mut tdb = populated();
+        let users = tdb.namespace(b"user:");
+        let mut keys = users.keys().unwrap().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec![b"1".to_vec(), b"2".to_vec()]);
+
+        let mut items = users.iter().unwrap().collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(
+            items,
+            vec![
+                (b"1".to_vec(), b"Alice".to_vec()),
+                (b"2".to_vec(), b"Bob".to_vec())
+            ]
+        );
+
+        let mut seen = 0;
+        assert_eq!(
+            users
+                .traverse(|_, _| {
+                    seen += 1;
+                    false
+                })
+                .unwrap(),
+            1
+        );
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn test_clear_memory() {
+        let mut tdb = populated();
+        assert_eq!(tdb.namespace(b"user:").clear().unwrap(), 2);
+        assert_eq!(
+            tdb.keys().collect::<Vec<_>>(),
+            vec![b"config:timeout".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_clear_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = Tdb::open(
+            dir.path().join("test.tdb"),
+            None,
+            Flags::empty(),
+            libc::O_RDWR | libc::O_CREAT,
+            0o600,
+        )
+        .unwrap();
+        tdb.store(b"user:1", b"Alice", None).unwrap();
+        tdb.store(b"config:timeout", b"30", None).unwrap();
+
+        assert_eq!(tdb.namespace(b"user:").clear().unwrap(), 1);
+        assert!(!tdb.transaction_active());
+        assert!(!tdb.exists(b"user:1"));
+        assert!(tdb.exists(b"config:timeout"));
+    }
+}
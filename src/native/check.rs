@@ -0,0 +1,104 @@
+//! Consistency checking, mirroring libtdb's `check.c`.
+
+use super::summary::{PAD_MAGIC, TDB_RECOVERY_INVALID_MAGIC};
+use super::Context;
+use crate::reader::{
+    FREELIST_TOP, RECORD_LEN, RECOVERY_START_OFS, TDB_DEAD_MAGIC, TDB_FREE_MAGIC, TDB_MAGIC,
+    TDB_RECOVERY_MAGIC,
+};
+use crate::Error;
+use std::collections::{HashMap, HashSet};
+
+impl Context {
+    /// Check that every record in the file is either on the hash chain of its key or on the
+    /// freelist, exactly once. The whole database must be locked.
+    ///
+    /// Returns the offsets of the live records.
+    pub(super) fn check_structure(&mut self) -> Result<Vec<u32>, Error> {
+        let map_size = self.map_size();
+        let recovery = self.read_u32(RECOVERY_START_OFS)?;
+        if recovery != 0 && (recovery < self.data_start() || recovery >= map_size) {
+            return Err(Error::Corrupt);
+        }
+
+        // Walk the file from start to end, collecting the records by kind.
+        let mut used = HashMap::new();
+        let mut free = HashSet::new();
+        let mut live = Vec::new();
+        let mut found_recovery = false;
+        let mut off = self.data_start();
+        while off < map_size - 1 {
+            let mut rec = self.read_record(off)?;
+            match rec.magic {
+                TDB_MAGIC | TDB_DEAD_MAGIC => {
+                    if rec
+                        .key_len
+                        .checked_add(rec.data_len)
+                        .is_none_or(|len| len > rec.rec_len)
+                    {
+                        return Err(Error::Corrupt);
+                    }
+                    let key = self.read(off + RECORD_LEN, rec.key_len)?;
+                    if self.hash.hash(&key) != rec.full_hash {
+                        return Err(Error::Corrupt);
+                    }
+                    used.insert(off, self.bucket(rec.full_hash));
+                    if rec.magic == TDB_MAGIC {
+                        live.push(off);
+                    }
+                }
+                TDB_FREE_MAGIC => {
+                    free.insert(off);
+                }
+                TDB_RECOVERY_MAGIC | TDB_RECOVERY_INVALID_MAGIC | PAD_MAGIC if off == recovery => {
+                    found_recovery = true;
+                }
+                // A crash while expanding the file can leave zeroes or padding behind.
+                TDB_RECOVERY_INVALID_MAGIC | PAD_MAGIC => {
+                    let dead = self.dead_space(off);
+                    if dead < RECORD_LEN {
+                        return Err(Error::Corrupt);
+                    }
+                    rec.rec_len = dead - RECORD_LEN;
+                }
+                _ => return Err(Error::Corrupt),
+            }
+            off = off
+                .checked_add(RECORD_LEN + rec.rec_len)
+                .ok_or(Error::Corrupt)?;
+        }
+        if recovery != 0 && !found_recovery {
+            return Err(Error::Corrupt);
+        }
+
+        // Removing each record as it is reached also catches loops.
+        for bucket in 0..self.hash_size {
+            let mut off = self.read_u32(self.hash_top(bucket))?;
+            while off != 0 {
+                if used.remove(&off) != Some(bucket) {
+                    return Err(Error::Corrupt);
+                }
+                off = self.read_record(off)?.next;
+            }
+        }
+        let mut off = self.read_u32(FREELIST_TOP)?;
+        while off != 0 {
+            if !free.remove(&off) {
+                return Err(Error::Corrupt);
+            }
+            off = self.read_record(off)?.next;
+        }
+        if !used.is_empty() || !free.is_empty() {
+            return Err(Error::Corrupt);
+        }
+        Ok(live)
+    }
+
+    /// Read the key and value of the record at `off`.
+    pub(super) fn read_key_data(&mut self, off: u32) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let rec = self.read_record(off)?;
+        let mut key = self.read(off + RECORD_LEN, rec.key_len + rec.data_len)?;
+        let data = key.split_off(rec.key_len as usize);
+        Ok((key, data))
+    }
+}
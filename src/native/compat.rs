@@ -0,0 +1,261 @@
+//! Tests that this backend and libtdb can share a database, built only when testing against
+//! libtdb.
+//!
+//! The two backends are never open on the same file in the same process at the same time:
+//! closing one's descriptor would drop the locks of the other. Lock conflicts are tested from a
+//! child process instead.
+
+use super::Handle;
+use crate::{Flags, Tdb};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+fn open_native(path: &Path, flags: Flags) -> Handle {
+    Handle::open(path, Some(7), flags, libc::O_RDWR | libc::O_CREAT, 0o600).unwrap()
+}
+
+fn open_libtdb(path: &Path, flags: Flags) -> Tdb {
+    Tdb::open(path, Some(7), flags, libc::O_RDWR | libc::O_CREAT, 0o600).unwrap()
+}
+
+/// Add, overwrite and delete records, and return the records that are left.
+///
+/// `write` stores a value, or deletes the key if there is none.
+fn fill(mut write: impl FnMut(&[u8], Option<&[u8]>)) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut expected = BTreeMap::new();
+    for i in 0..100u32 {
+        let (key, value) = (
+            format!("key{}", i),
+            vec![b'a' + (i % 26) as u8; i as usize * 3],
+        );
+        write(key.as_bytes(), Some(&value));
+        expected.insert(key.into_bytes(), value);
+    }
+    // Leave records on the freelist, and records that have moved to a bigger one.
+    for i in (0..100u32).step_by(3) {
+        let key = format!("key{}", i);
+        write(key.as_bytes(), None);
+        expected.remove(key.as_bytes());
+    }
+    for i in (1..100u32).step_by(5) {
+        let (key, value) = (format!("key{}", i), vec![b'z'; 500]);
+        write(key.as_bytes(), Some(&value));
+        expected.insert(key.into_bytes(), value);
+    }
+    expected
+}
+
+fn native_contents(tdb: &Handle) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut actual = BTreeMap::new();
+    tdb.traverse(false, |key, value| {
+        actual.insert(key.to_vec(), value.to_vec());
+        true
+    })
+    .unwrap();
+    actual
+}
+
+#[test]
+fn test_native_file_read_by_libtdb() {
+    for flags in [Flags::empty(), Flags::IncompatibleHash] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut native = open_native(&path, flags);
+        let expected = fill(|key, value| match value {
+            Some(value) => native.store(key, value, None).unwrap(),
+            None => native.delete(key).unwrap(),
+        });
+        drop(native);
+
+        let tdb = open_libtdb(&path, Flags::empty());
+        tdb.check(|_, _| true).unwrap();
+        assert_eq!(tdb.iter().collect::<BTreeMap<_, _>>(), expected);
+        for (key, value) in &expected {
+            assert_eq!(tdb.fetch(key).unwrap().as_ref(), Some(value));
+        }
+    }
+}
+
+#[test]
+fn test_libtdb_file_read_by_native() {
+    for flags in [Flags::empty(), Flags::IncompatibleHash] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = open_libtdb(&path, flags);
+        let expected = fill(|key, value| match value {
+            Some(value) => tdb.store(key, value, None).unwrap(),
+            None => tdb.delete(key).unwrap(),
+        });
+        let freelist_size = tdb.freelist_size();
+        drop(tdb);
+
+        let native = open_native(&path, Flags::empty());
+        native.check(|_, _| true).unwrap();
+        assert_eq!(native_contents(&native), expected);
+        for (key, value) in &expected {
+            assert_eq!(native.fetch(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(native.freelist_size(), freelist_size);
+    }
+}
+
+#[test]
+fn test_native_writes_libtdb_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.tdb");
+    let mut tdb = open_libtdb(&path, Flags::empty());
+    let mut expected = fill(|key, value| match value {
+        Some(value) => tdb.store(key, value, None).unwrap(),
+        None => tdb.delete(key).unwrap(),
+    });
+    drop(tdb);
+
+    // Reusing libtdb's free records and growing its file, then back again.
+    let mut native = open_native(&path, Flags::empty());
+    native.transaction_start().unwrap();
+    for i in 0..100u32 {
+        let key = format!("key{}", i);
+        if i % 2 == 0 {
+            let _ = native.delete(key.as_bytes());
+            expected.remove(key.as_bytes());
+        } else {
+            native.append(key.as_bytes(), b"native").unwrap();
+            expected
+                .entry(key.into_bytes())
+                .or_default()
+                .extend(b"native");
+        }
+    }
+    native.transaction_commit().unwrap();
+    drop(native);
+
+    let tdb = open_libtdb(&path, Flags::empty());
+    tdb.check(|_, _| true).unwrap();
+    assert_eq!(tdb.iter().collect::<BTreeMap<_, _>>(), expected);
+}
+
+/// Read the whole file through `fd`, which must stay open to keep the locks of its database.
+fn read_file(fd: RawFd) -> Vec<u8> {
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut data = vec![0; file.metadata().unwrap().len() as usize];
+    file.read_exact_at(&mut data, 0).unwrap();
+    data
+}
+
+/// Return the file as it would be after a crash while writing out a transaction: only the first
+/// page of `committed` made it to disk over `prepared`.
+fn torn_commit(mut prepared: Vec<u8>, committed: &[u8]) -> Vec<u8> {
+    let end = prepared.len().min(committed.len()).min(4096);
+    assert_ne!(prepared[..end], committed[..end]);
+    prepared[..end].copy_from_slice(&committed[..end]);
+    prepared
+}
+
+#[test]
+fn test_libtdb_recovers_native_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.tdb");
+    let mut native = open_native(&path, Flags::empty());
+    let expected = fill(|key, value| match value {
+        Some(value) => native.store(key, value, None).unwrap(),
+        None => native.delete(key).unwrap(),
+    });
+    native.transaction_start().unwrap();
+    for key in expected.keys() {
+        native.store(key, &[b'x'; 1000], None).unwrap();
+    }
+    native.transaction_prepare_commit().unwrap();
+    let prepared = read_file(native.fd());
+    native.transaction_commit().unwrap();
+    let crashed = torn_commit(prepared, &read_file(native.fd()));
+    drop(native);
+    std::fs::write(&path, crashed).unwrap();
+
+    let tdb = open_libtdb(&path, Flags::empty());
+    tdb.check(|_, _| true).unwrap();
+    assert_eq!(tdb.iter().collect::<BTreeMap<_, _>>(), expected);
+}
+
+#[test]
+fn test_native_recovers_libtdb_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.tdb");
+    let mut tdb = open_libtdb(&path, Flags::empty());
+    let expected = fill(|key, value| match value {
+        Some(value) => tdb.store(key, value, None).unwrap(),
+        None => tdb.delete(key).unwrap(),
+    });
+    tdb.transaction_start().unwrap();
+    for key in expected.keys() {
+        tdb.store(key, &[b'x'; 1000], None).unwrap();
+    }
+    tdb.transaction_prepare_commit().unwrap();
+    let prepared = read_file(tdb.as_raw_fd());
+    tdb.transaction_commit().unwrap();
+    let crashed = torn_commit(prepared, &read_file(tdb.as_raw_fd()));
+    drop(tdb);
+    std::fs::write(&path, crashed).unwrap();
+
+    let native = open_native(&path, Flags::empty());
+    native.check(|_, _| true).unwrap();
+    assert_eq!(native_contents(&native), expected);
+}
+
+#[test]
+fn test_libtdb_locks_block_native() {
+    const CHILD_ENV: &str = "TRIVIALDB_NATIVE_LOCKED_PATH";
+    if let Some(path) = std::env::var_os(CHILD_ENV) {
+        let native = open_native(path.as_ref(), Flags::empty());
+        assert!(native.chainlock_nonblock(b"key").is_err());
+        native.chainlock_nonblock(b"another").unwrap();
+        native.chainunlock(b"another").unwrap();
+        assert!(native.lockall_nonblock().is_err());
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.tdb");
+    let mut tdb = open_libtdb(&path, Flags::empty());
+    tdb.store(b"key", b"value", None).unwrap();
+    assert_ne!(tdb.chain_of(b"key"), tdb.chain_of(b"another"));
+    tdb.chainlock_nonblock(b"key").unwrap();
+    let mut child = crate::test::spawn_test(
+        "native::compat::test_libtdb_locks_block_native",
+        CHILD_ENV,
+        &path,
+    );
+    assert!(child.wait().unwrap().success());
+    tdb.chainunlock(b"key").unwrap();
+}
+
+#[test]
+fn test_native_locks_block_libtdb() {
+    const CHILD_ENV: &str = "TRIVIALDB_LIBTDB_LOCKED_PATH";
+    if let Some(path) = std::env::var_os(CHILD_ENV) {
+        let mut tdb = open_libtdb(path.as_ref(), Flags::empty());
+        assert!(tdb.transaction_start_nonblock().is_err());
+        // A transaction only keeps others from writing until it commits.
+        assert!(tdb.lockall_nonblock().is_err());
+        tdb.lockall_read_nonblock().unwrap();
+        tdb.unlockall_read().unwrap();
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.tdb");
+    let mut native = open_native(&path, Flags::empty());
+    native.store(b"key", b"value", None).unwrap();
+    native.transaction_start().unwrap();
+    native.store(b"key", b"changed", None).unwrap();
+    let mut child = crate::test::spawn_test(
+        "native::compat::test_native_locks_block_libtdb",
+        CHILD_ENV,
+        &path,
+    );
+    assert!(child.wait().unwrap().success());
+    native.transaction_commit().unwrap();
+}
@@ -0,0 +1,234 @@
+//! Free space management, mirroring libtdb's `freelist.c`.
+
+use super::io::Record;
+use super::{Context, FREELIST};
+use crate::reader::{FREELIST_TOP, RECORD_LEN, TDB_FREE_MAGIC, TDB_MAGIC};
+use crate::Error;
+use libc::F_WRLCK;
+use std::collections::HashSet;
+
+/// The smallest free block worth splitting off the end of an allocation.
+const MIN_REC_SIZE: u32 = RECORD_LEN + 4 + 8;
+
+/// A tailer made of padding, left behind by expanding the file.
+const PAD_U32: u32 = 0x42424242;
+
+impl Context {
+    /// Read the header of a record on the freelist.
+    fn rec_free_read(&mut self, off: u32) -> Result<Record, Error> {
+        let mut rec = self.read_record(off)?;
+        if rec.magic == TDB_MAGIC {
+            // Old versions of libtdb left the magic of freed records alone; fix it up.
+            rec.magic = TDB_FREE_MAGIC;
+            self.rec_write(off, &rec)?;
+        }
+        if rec.magic != TDB_FREE_MAGIC {
+            return Err(Error::Corrupt);
+        }
+        self.oob(rec.next, RECORD_LEN)?;
+        Ok(rec)
+    }
+
+    /// Write the tailer of a record: its total length, stored in its last four bytes.
+    fn update_tailer(&mut self, off: u32, rec: &Record) -> Result<(), Error> {
+        self.write_u32(off + rec.rec_len + RECORD_LEN - 4, rec.rec_len + RECORD_LEN)
+    }
+
+    /// Add the record at `off` to the freelist, merging it with a free record on its left.
+    ///
+    /// The caller need not hold the freelist lock.
+    pub(super) fn free(&mut self, off: u32, rec: &Record) -> Result<(), Error> {
+        self.with_lock(FREELIST, F_WRLCK, |ctx| ctx.free_locked(off, rec))
+    }
+
+    fn free_locked(&mut self, off: u32, rec: &Record) -> Result<(), Error> {
+        let mut rec = *rec;
+        self.update_tailer(off, &rec)?;
+
+        // Look at the record on the left, to merge with it if it is free.
+        if let Some(left) = self.free_left_of(off) {
+            let mut l = self.read_record(left)?;
+            l.rec_len += rec.rec_len + RECORD_LEN;
+            self.rec_write(left, &l)?;
+            return self.update_tailer(left, &l);
+        }
+
+        rec.magic = TDB_FREE_MAGIC;
+        rec.next = self.read_u32(FREELIST_TOP)?;
+        self.rec_write(off, &rec)?;
+        self.write_u32(FREELIST_TOP, off)
+    }
+
+    /// Return the offset of the record just before `off` if it is on the freelist.
+    fn free_left_of(&mut self, off: u32) -> Option<u32> {
+        let data_start = self.data_start();
+        if off - 4 <= data_start {
+            return None;
+        }
+        // The tailer may be uninitialised data, so sanity check it before following it.
+        let left_size = self.read_u32(off - 4).ok()?;
+        if left_size == 0 || left_size == PAD_U32 || left_size > off - data_start {
+            return None;
+        }
+        let left = off - left_size;
+        let l = self.read_record(left).ok()?;
+        (l.magic == TDB_FREE_MAGIC).then_some(left)
+    }
+
+    /// Return the offset of the first record, just after the hash table.
+    pub(super) fn data_start(&self) -> u32 {
+        FREELIST_TOP + 4 + 4 * self.hash_size
+    }
+
+    /// Take `length` bytes from the end of the free record `rec` at `off`, leaving the rest
+    /// on the freelist.
+    fn allocate_ofs(
+        &mut self,
+        length: u32,
+        off: u32,
+        rec: &mut Record,
+        last: u32,
+        next: u32,
+    ) -> Result<u32, Error> {
+        if rec.rec_len < length + MIN_REC_SIZE {
+            // Not worth splitting, so use the whole record and unlink it.
+            self.write_u32(last, next)?;
+            rec.magic = TDB_MAGIC;
+            self.rec_write(off, rec)?;
+            return Ok(off);
+        }
+        // Shorten the free record in place; it stays on the freelist.
+        rec.rec_len -= length + RECORD_LEN;
+        self.rec_write(off, rec)?;
+        self.update_tailer(off, rec)?;
+
+        let new_off = off + RECORD_LEN + rec.rec_len;
+        *rec = Record {
+            rec_len: length,
+            magic: TDB_MAGIC,
+            ..Default::default()
+        };
+        self.rec_write(new_off, rec)?;
+        self.update_tailer(new_off, rec)?;
+        Ok(new_off)
+    }
+
+    /// Allocate space for a record holding `length` bytes of key and data.
+    pub(super) fn allocate(&mut self, hash: u32, length: u32) -> Result<(u32, Record), Error> {
+        if self.max_dead > 0 {
+            // Reuse a dead record from a chain we can get at without waiting, starting with
+            // our own. If the freelist is free instead, give back our dead records and use it.
+            for i in 0..self.hash_size {
+                let list = (hash.wrapping_add(i) % self.hash_size) as i32;
+                if self.lock_list(list, F_WRLCK, false).is_ok() {
+                    let found = self.alloc_dead(list, length);
+                    self.unlock_list(list, F_WRLCK)?;
+                    if let Some(found) = found? {
+                        return Ok(found);
+                    }
+                }
+                if self.lock_list(FREELIST, F_WRLCK, false).is_ok() {
+                    let _ = self.purge_dead(hash);
+                    let ret = self.allocate_from_freelist(length);
+                    self.unlock_list(FREELIST, F_WRLCK)?;
+                    return ret;
+                }
+            }
+        }
+        self.with_lock(FREELIST, F_WRLCK, |ctx| ctx.allocate_from_freelist(length))
+    }
+
+    /// Take the smallest dead record in chain `list` that can hold `length` bytes.
+    fn alloc_dead(&mut self, list: i32, length: u32) -> Result<Option<(u32, Record)>, Error> {
+        let length = length + 4;
+        let mut best: Option<(u32, u32, Record)> = None;
+        let mut last = self.hash_top(list as u32);
+        let mut off = self.read_u32(last)?;
+        while off != 0 {
+            let rec = self.rec_read(off)?;
+            if rec.is_dead()
+                && rec.rec_len >= length
+                && best.is_none_or(|(_, _, b)| rec.rec_len < b.rec_len)
+            {
+                best = Some((off, last, rec));
+            }
+            last = off;
+            off = rec.next;
+        }
+        let Some((off, last, rec)) = best else {
+            return Ok(None);
+        };
+        // Unlink it; it is about to be moved into another chain.
+        self.write_u32(last, rec.next)?;
+        Ok(Some((off, rec)))
+    }
+
+    /// Allocate from the freelist, expanding the file if nothing fits. The freelist must be
+    /// locked.
+    fn allocate_from_freelist(&mut self, length: u32) -> Result<(u32, Record), Error> {
+        // Over-allocate so records can grow in place, and leave room for the tailer.
+        let length = (length as f64 * 1.25) as u32;
+        let length = (length + 4 + 3) & !3;
+        loop {
+            let mut last = FREELIST_TOP;
+            let mut off = self.read_u32(FREELIST_TOP)?;
+            let mut best: Option<(u32, u32, Record)> = None;
+            // Accept progressively worse fits the longer the freelist is, so we don't have to
+            // walk all of it.
+            let mut multiplier = 1.0f32;
+            while off != 0 {
+                let rec = self.rec_free_read(off)?;
+                if rec.rec_len >= length && best.is_none_or(|(_, _, b)| rec.rec_len < b.rec_len) {
+                    best = Some((off, last, rec));
+                }
+                if let Some((_, _, b)) = best {
+                    if (b.rec_len as f32) < length as f32 * multiplier {
+                        break;
+                    }
+                }
+                multiplier *= 1.05;
+                last = off;
+                off = rec.next;
+            }
+            if let Some((off, last, mut rec)) = best {
+                let next = rec.next;
+                let off = self.allocate_ofs(length, off, &mut rec, last, next)?;
+                return Ok((off, rec));
+            }
+            // Nothing fits; make some room and try again.
+            self.expand(length + RECORD_LEN)?;
+        }
+    }
+
+    /// Check that the freelist has no loops and only contains free records, returning its
+    /// length.
+    pub(super) fn validate_freelist(&mut self) -> Result<u32, Error> {
+        self.with_lock(FREELIST, F_WRLCK, |ctx| {
+            let mut seen = HashSet::new();
+            let mut off = ctx.read_u32(FREELIST_TOP)?;
+            while off != 0 {
+                if !seen.insert(off) {
+                    return Err(Error::Corrupt);
+                }
+                off = ctx.rec_free_read(off)?.next;
+            }
+            Ok(seen.len() as u32)
+        })
+    }
+
+    /// Return the number of records on the freelist.
+    pub(super) fn freelist_size(&mut self) -> Result<u32, Error> {
+        self.with_lock(FREELIST, libc::F_RDLCK, |ctx| {
+            let mut count = 0;
+            let mut off = ctx.read_u32(FREELIST_TOP)?;
+            while off != 0 {
+                off = ctx.read_u32(off)?;
+                count += 1;
+                if count > ctx.map_size() / RECORD_LEN {
+                    return Err(Error::Corrupt);
+                }
+            }
+            Ok(count)
+        })
+    }
+}
@@ -0,0 +1,388 @@
+//! Reading and writing the file, mirroring libtdb's `io.c`.
+
+use super::{Context, FREELIST};
+use crate::reader::{Mmap, RECORD_LEN, TDB_DEAD_MAGIC, TDB_MAGIC};
+use crate::Error;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Byte used to fill newly expanded regions of the file, so they are not sparse.
+const PAD_BYTE: u8 = 0x42;
+
+/// The header of a record, as stored in front of its key and data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Record {
+    pub next: u32,
+    pub rec_len: u32,
+    pub key_len: u32,
+    pub data_len: u32,
+    pub full_hash: u32,
+    pub magic: u32,
+}
+
+impl Record {
+    pub fn is_dead(&self) -> bool {
+        self.magic == TDB_DEAD_MAGIC
+    }
+
+    pub fn decode(buf: &[u8], big_endian: bool) -> Record {
+        let field = |i: usize| decode_u32(&buf[i * 4..i * 4 + 4], big_endian);
+        Record {
+            next: field(0),
+            rec_len: field(1),
+            key_len: field(2),
+            data_len: field(3),
+            full_hash: field(4),
+            magic: field(5),
+        }
+    }
+
+    pub fn encode(&self, big_endian: bool) -> Vec<u8> {
+        [
+            self.next,
+            self.rec_len,
+            self.key_len,
+            self.data_len,
+            self.full_hash,
+            self.magic,
+        ]
+        .iter()
+        .flat_map(|&v| encode_u32(v, big_endian))
+        .collect()
+    }
+}
+
+pub(super) fn decode_u32(buf: &[u8], big_endian: bool) -> u32 {
+    let bytes: [u8; 4] = buf.try_into().unwrap();
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+pub(super) fn encode_u32(v: u32, big_endian: bool) -> [u8; 4] {
+    if big_endian {
+        v.to_be_bytes()
+    } else {
+        v.to_le_bytes()
+    }
+}
+
+enum Storage {
+    File { file: File, map: Option<Mmap> },
+    Memory(Vec<u8>),
+}
+
+/// The underlying storage of a database, without any transaction on top.
+pub(super) struct Io {
+    storage: Storage,
+    /// Current size of the file or memory buffer.
+    pub size: u32,
+    use_mmap: bool,
+    writable: bool,
+}
+
+impl Io {
+    pub fn memory(data: Vec<u8>) -> Io {
+        Io {
+            size: data.len() as u32,
+            storage: Storage::Memory(data),
+            use_mmap: false,
+            writable: true,
+        }
+    }
+
+    pub fn file(file: File, use_mmap: bool, writable: bool) -> Result<Io, Error> {
+        let mut io = Io {
+            storage: Storage::File { file, map: None },
+            size: 0,
+            use_mmap,
+            writable,
+        };
+        io.refresh()?;
+        Ok(io)
+    }
+
+    pub fn file_handle(&self) -> Option<&File> {
+        match &self.storage {
+            Storage::File { file, .. } => Some(file),
+            Storage::Memory(_) => None,
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.file_handle().map_or(-1, |file| file.as_raw_fd())
+    }
+
+    /// Pick up the current size of the file, and map it again if it changed.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        let Storage::File { file, map } = &mut self.storage else {
+            return Ok(());
+        };
+        let len = file.metadata().map_err(|_| Error::IO)?.len();
+        let size = u32::try_from(len).map_err(|_| Error::IO)?;
+        if size == self.size && (map.is_some() || !self.use_mmap || size == 0) {
+            return Ok(());
+        }
+        *map = None;
+        self.size = size;
+        if self.use_mmap && size > 0 {
+            // Like libtdb, fall back to read/write if the file can't be mapped.
+            *map = Mmap::new(file.as_raw_fd(), size as usize, self.writable).ok();
+        }
+        Ok(())
+    }
+
+    /// Check that `len` bytes at `off` are within the file, picking up expansions made by
+    /// other processes.
+    pub fn oob(&mut self, off: u32, len: u32) -> Result<(), Error> {
+        let end = off.checked_add(len).ok_or(Error::IO)?;
+        if end <= self.size {
+            return Ok(());
+        }
+        self.refresh()?;
+        if end <= self.size {
+            Ok(())
+        } else {
+            Err(Error::IO)
+        }
+    }
+
+    pub fn read_into(&mut self, off: u32, buf: &mut [u8]) -> Result<(), Error> {
+        self.oob(off, buf.len() as u32)?;
+        let range = off as usize..off as usize + buf.len();
+        match &self.storage {
+            Storage::Memory(data) => buf.copy_from_slice(&data[range]),
+            Storage::File { map: Some(map), .. } => buf.copy_from_slice(&map.as_slice()[range]),
+            Storage::File { file, map: None } => {
+                file.read_exact_at(buf, off as u64).map_err(|_| Error::IO)?
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, off: u32, len: u32) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; len as usize];
+        self.read_into(off, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, off: u32, data: &[u8]) -> Result<(), Error> {
+        self.oob(off, data.len() as u32)?;
+        let range = off as usize..off as usize + data.len();
+        match &mut self.storage {
+            Storage::Memory(buf) => buf[range].copy_from_slice(data),
+            Storage::File { map: Some(map), .. } => map.as_mut_slice()[range].copy_from_slice(data),
+            Storage::File { file, map: None } => {
+                file.write_all_at(data, off as u64).map_err(|_| Error::IO)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Grow the file from `size` by `addition` bytes of padding.
+    pub fn expand_file(&mut self, size: u32, addition: u32) -> Result<(), Error> {
+        let new_size = size.checked_add(addition).ok_or(Error::IO)?;
+        match &mut self.storage {
+            Storage::Memory(data) => {
+                data.resize(new_size as usize, PAD_BYTE);
+                self.size = new_size;
+                Ok(())
+            }
+            Storage::File { file, map } => {
+                // Drop the mapping first, it must never extend past the end of the file.
+                *map = None;
+                file.set_len(new_size as u64).map_err(|_| Error::IO)?;
+                // Write out the padding so the space is actually allocated, and a full disk
+                // is noticed now rather than when the region is used through the mapping.
+                const CHUNK: usize = 64 * 1024;
+                let pad = vec![PAD_BYTE; CHUNK.min(addition as usize)];
+                let mut off = size as u64;
+                while off < new_size as u64 {
+                    let n = pad.len().min((new_size as u64 - off) as usize);
+                    file.write_all_at(&pad[..n], off).map_err(|_| Error::IO)?;
+                    off += n as u64;
+                }
+                self.size = 0;
+                self.refresh()
+            }
+        }
+    }
+
+    /// Truncate the file to `size` bytes.
+    pub fn truncate(&mut self, size: u32) -> Result<(), Error> {
+        match &mut self.storage {
+            Storage::Memory(data) => data.truncate(size as usize),
+            Storage::File { file, map } => {
+                *map = None;
+                file.set_len(size as u64).map_err(|_| Error::IO)?;
+            }
+        }
+        self.size = 0;
+        self.refresh()?;
+        if let Storage::Memory(data) = &self.storage {
+            self.size = data.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of the file.
+    pub fn replace(&mut self, data: &[u8]) -> Result<(), Error> {
+        match &mut self.storage {
+            Storage::Memory(buf) => {
+                *buf = data.to_vec();
+                self.size = data.len() as u32;
+                Ok(())
+            }
+            Storage::File { file, map } => {
+                *map = None;
+                file.set_len(0).map_err(|_| Error::IO)?;
+                file.write_all_at(data, 0).map_err(|_| Error::IO)?;
+                self.size = 0;
+                self.refresh()
+            }
+        }
+    }
+
+    /// Flush the file to disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let Storage::File { file, map } = &self.storage else {
+            return Ok(());
+        };
+        if let Some(map) = map {
+            map.sync()?;
+        }
+        file.sync_data().map_err(|_| Error::IO)
+    }
+}
+
+/// Work out how much to grow a file of `map_size` bytes by to make room for at least `size`
+/// more bytes, like libtdb's `tdb_expand_adjust`.
+pub(super) fn expand_adjust(map_size: u32, size: u32, page_size: u32) -> Result<u32, Error> {
+    let align = |n: u64| n.div_ceil(page_size as u64) * page_size as u64;
+    let (map_size, size) = (map_size as u64, size as u64);
+    // Grow generously for small records so we don't have to expand again soon, but less so
+    // for large ones.
+    let top_size = map_size
+        + if size > 100 * 1024 {
+            size * 2
+        } else {
+            size * 100
+        };
+    let grown = if map_size > 100 * 1024 * 1024 {
+        (map_size as f64 * 1.10) as u64
+    } else {
+        (map_size as f64 * 1.25) as u64
+    };
+    let mut new_size = align(top_size.max(grown));
+    if new_size > u32::MAX as u64 {
+        // Fall back to growing just enough, and fail if even that doesn't fit.
+        new_size = align(map_size + size);
+    }
+    if new_size > u32::MAX as u64 {
+        return Err(Error::IO);
+    }
+    Ok((new_size - map_size) as u32)
+}
+
+impl Context {
+    /// Return the size of the database, including any expansion made by an open transaction.
+    pub(super) fn map_size(&self) -> u32 {
+        match &self.transaction {
+            Some(tr) => tr.map_size,
+            None => self.io.size,
+        }
+    }
+
+    /// Check that `len` bytes at `off` are within the database.
+    pub(super) fn oob(&mut self, off: u32, len: u32) -> Result<(), Error> {
+        match &self.transaction {
+            Some(tr) => match off.checked_add(len) {
+                Some(end) if end <= tr.map_size => Ok(()),
+                _ => Err(Error::IO),
+            },
+            None => self.io.oob(off, len),
+        }
+    }
+
+    pub(super) fn read(&mut self, off: u32, len: u32) -> Result<Vec<u8>, Error> {
+        if self.transaction.is_some() {
+            self.transaction_read(off, len)
+        } else {
+            self.io.read(off, len)
+        }
+    }
+
+    pub(super) fn write(&mut self, off: u32, data: &[u8]) -> Result<(), Error> {
+        if self.read_only || self.traverse_read > 0 {
+            return Err(Error::ReadOnly);
+        }
+        if self.transaction.is_some() {
+            self.transaction_write(off, Some(data), data.len() as u32)
+        } else {
+            self.io.write(off, data)
+        }
+    }
+
+    pub(super) fn read_u32(&mut self, off: u32) -> Result<u32, Error> {
+        Ok(decode_u32(&self.read(off, 4)?, self.big_endian))
+    }
+
+    pub(super) fn write_u32(&mut self, off: u32, v: u32) -> Result<(), Error> {
+        self.write(off, &encode_u32(v, self.big_endian))
+    }
+
+    /// Read a record header without checking it.
+    pub(super) fn read_record(&mut self, off: u32) -> Result<Record, Error> {
+        Ok(Record::decode(
+            &self.read(off, RECORD_LEN)?,
+            self.big_endian,
+        ))
+    }
+
+    /// Read the header of a used or dead record.
+    pub(super) fn rec_read(&mut self, off: u32) -> Result<Record, Error> {
+        let rec = self.read_record(off)?;
+        if rec.magic != TDB_MAGIC && !rec.is_dead() {
+            return Err(Error::Corrupt);
+        }
+        self.oob(rec.next, RECORD_LEN)?;
+        Ok(rec)
+    }
+
+    pub(super) fn rec_write(&mut self, off: u32, rec: &Record) -> Result<(), Error> {
+        self.write(off, &rec.encode(self.big_endian))
+    }
+
+    /// Grow the database from `size` by `addition` bytes.
+    fn expand_file(&mut self, size: u32, addition: u32) -> Result<(), Error> {
+        if self.read_only || self.traverse_read > 0 {
+            return Err(Error::ReadOnly);
+        }
+        if self.transaction.is_some() {
+            self.transaction_expand_file(size, addition)
+        } else {
+            self.io.expand_file(size, addition)
+        }
+    }
+
+    /// Make room for a record of at least `size` bytes and put the new space on the freelist.
+    pub(super) fn expand(&mut self, size: u32) -> Result<(), Error> {
+        self.with_lock(FREELIST, libc::F_WRLCK, |ctx| {
+            if ctx.transaction.is_none() {
+                // Another process may have expanded the file in the meantime.
+                ctx.io.refresh()?;
+            }
+            let offset = ctx.map_size();
+            let addition = expand_adjust(offset, size, ctx.page_size)?;
+            ctx.expand_file(offset, addition)?;
+            let rec = Record {
+                rec_len: addition - RECORD_LEN,
+                ..Default::default()
+            };
+            ctx.free(offset, &rec)
+        })
+    }
+}
@@ -0,0 +1,383 @@
+//! fcntl locking, using the same byte ranges as libtdb's `lock.c` so that processes using
+//! libtdb and this implementation exclude each other.
+
+use super::{Context, TravLock};
+use crate::generated::TDB_NOLOCK;
+use crate::reader::{FREELIST_TOP, RECORD_LEN, RECOVERY_START_OFS, TDB_RECOVERY_MAGIC};
+use crate::Error;
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+use std::io;
+
+/// Held while opening the database.
+pub(super) const OPEN_LOCK: u32 = 0;
+/// Held for reading by every process that opened the database with `ClearIfFirst`.
+pub(super) const ACTIVE_LOCK: u32 = 4;
+/// Held by transactions and traversals.
+pub(super) const TRANSACTION_LOCK: u32 = 8;
+
+/// A lock on a single byte that may be taken more than once.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct LockRec {
+    off: u32,
+    count: u32,
+    ltype: i32,
+}
+
+/// The lock on all hash chains and records taken by `lockall` and transactions.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct AllRecordLock {
+    pub count: u32,
+    pub ltype: i32,
+    /// A read lock that will be upgraded to a write lock when a transaction commits. It is
+    /// recorded as a write lock, since no other process can write while it is held.
+    pub upgradable: bool,
+}
+
+/// Return the offset of the byte locked for hash chain `list`, where -1 is the freelist.
+pub(super) fn lock_offset(list: i32) -> u32 {
+    (FREELIST_TOP as i64 + 4 * list as i64) as u32
+}
+
+fn fcntl_lock(fd: i32, ltype: i32, off: u32, len: u32, wait: bool) -> io::Result<()> {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = ltype as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = off as libc::off_t;
+    fl.l_len = len as libc::off_t;
+    let cmd = if wait { libc::F_SETLKW } else { libc::F_SETLK };
+    loop {
+        if unsafe { libc::fcntl(fd, cmd, &fl) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+impl Context {
+    /// Lock `len` bytes at `off`, where a length of zero means up to the end of the file.
+    pub(super) fn brlock(
+        &mut self,
+        ltype: i32,
+        off: u32,
+        len: u32,
+        wait: bool,
+    ) -> Result<(), Error> {
+        if self.flags & TDB_NOLOCK != 0 {
+            return Ok(());
+        }
+        if ltype == F_WRLCK && (self.read_only || self.traverse_read > 0) {
+            return Err(Error::ReadOnly);
+        }
+        fcntl_lock(self.io.fd(), ltype, off, len, wait).map_err(|_| Error::Lock)
+    }
+
+    pub(super) fn brunlock(&mut self, off: u32, len: u32) -> Result<(), Error> {
+        if self.flags & TDB_NOLOCK != 0 {
+            return Ok(());
+        }
+        fcntl_lock(self.io.fd(), F_UNLCK, off, len, true).map_err(|_| Error::Lock)
+    }
+
+    fn find_lock(&self, off: u32) -> Option<usize> {
+        self.locks.iter().position(|lck| lck.off == off)
+    }
+
+    /// Take a lock that may already be held, in which case only its count is increased.
+    pub(super) fn nest_lock(&mut self, off: u32, ltype: i32, wait: bool) -> Result<(), Error> {
+        if off >= lock_offset(self.hash_size as i32) {
+            return Err(Error::Invalid);
+        }
+        if self.flags & TDB_NOLOCK != 0 {
+            return Ok(());
+        }
+        if let Some(i) = self.find_lock(off) {
+            if self.locks[i].ltype == F_RDLCK && ltype == F_WRLCK {
+                self.brlock(F_WRLCK, off, 1, wait)?;
+                self.locks[i].ltype = F_WRLCK;
+            }
+            self.locks[i].count += 1;
+            return Ok(());
+        }
+        self.brlock(ltype, off, 1, wait)?;
+        self.locks.push(LockRec {
+            off,
+            count: 1,
+            ltype,
+        });
+        Ok(())
+    }
+
+    pub(super) fn nest_unlock(&mut self, off: u32, _ltype: i32) -> Result<(), Error> {
+        if self.flags & TDB_NOLOCK != 0 {
+            return Ok(());
+        }
+        if off >= lock_offset(self.hash_size as i32) {
+            return Err(Error::Invalid);
+        }
+        let i = self.find_lock(off).ok_or(Error::Lock)?;
+        if self.locks[i].count > 1 {
+            self.locks[i].count -= 1;
+            return Ok(());
+        }
+        self.locks.swap_remove(i);
+        self.brunlock(off, 1)
+    }
+
+    /// Take the active lock again after reopening the file, if we held it.
+    pub(super) fn relock_active(&mut self) -> Result<(), Error> {
+        match self.find_lock(ACTIVE_LOCK) {
+            Some(i) => self.brlock(self.locks[i].ltype, ACTIVE_LOCK, 1, true),
+            None => Ok(()),
+        }
+    }
+
+    /// Return whether any hash chain or the freelist is locked.
+    fn have_data_locks(&self) -> bool {
+        self.locks.iter().any(|lck| lck.off >= lock_offset(-1))
+    }
+
+    /// Return whether any locks are held besides the ones expected to be held throughout.
+    pub(super) fn have_extra_locks(&self) -> bool {
+        if self.transaction.is_none() && self.allrecord.count > 0 {
+            return true;
+        }
+        let mut extra = self.locks.len();
+        // We always hold the active lock with ClearIfFirst.
+        if self.find_lock(ACTIVE_LOCK).is_some() {
+            extra -= 1;
+        }
+        // In a transaction, we expect to hold the transaction lock.
+        if self.transaction.is_some() && self.find_lock(TRANSACTION_LOCK).is_some() {
+            extra -= 1;
+        }
+        extra > 0
+    }
+
+    /// Lock hash chain `list`, where -1 is the freelist.
+    pub(super) fn lock_list(&mut self, list: i32, ltype: i32, wait: bool) -> Result<(), Error> {
+        if self.allrecord.count > 0 {
+            // The all-record lock covers every chain, but only for the type it was taken with.
+            return if ltype == self.allrecord.ltype || ltype == F_RDLCK {
+                Ok(())
+            } else {
+                Err(Error::Lock)
+            };
+        }
+        let check = !self.have_data_locks();
+        self.nest_lock(lock_offset(list), ltype, wait)?;
+        // Recovery is checked on the first data lock, since another process may have crashed
+        // in the middle of a commit.
+        if check && self.flags & TDB_NOLOCK == 0 && self.needs_recovery() {
+            self.nest_unlock(lock_offset(list), ltype)?;
+            self.lock_and_recover()?;
+            return self.lock_list(list, ltype, wait);
+        }
+        Ok(())
+    }
+
+    pub(super) fn unlock_list(&mut self, list: i32, ltype: i32) -> Result<(), Error> {
+        if self.allrecord.count > 0 {
+            return if self.allrecord.ltype == F_RDLCK && ltype == F_WRLCK {
+                Err(Error::Lock)
+            } else {
+                Ok(())
+            };
+        }
+        self.nest_unlock(lock_offset(list), ltype)
+    }
+
+    /// Run `f` with hash chain `list` locked.
+    pub(super) fn with_lock<R>(
+        &mut self,
+        list: i32,
+        ltype: i32,
+        f: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.lock_list(list, ltype, true)?;
+        let ret = f(self);
+        let unlocked = self.unlock_list(list, ltype);
+        let ret = ret?;
+        unlocked?;
+        Ok(ret)
+    }
+
+    /// Lock all hash chains and records.
+    pub(super) fn allrecord_lock(
+        &mut self,
+        ltype: i32,
+        wait: bool,
+        upgradable: bool,
+    ) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::Lock);
+        }
+        if self.allrecord.count > 0 && (ltype == F_RDLCK || self.allrecord.ltype == F_WRLCK) {
+            self.allrecord.count += 1;
+            return Ok(());
+        }
+        if self.allrecord.count > 0 || self.have_extra_locks() {
+            // Can't upgrade, or combine the all-record lock with chain locks.
+            return Err(Error::Lock);
+        }
+        if upgradable && ltype != F_RDLCK {
+            return Err(Error::Lock);
+        }
+        self.brlock(ltype, FREELIST_TOP, 4 * self.hash_size, wait)?;
+        // The records themselves are covered by a lock from the end of the hash table to the
+        // end of the file.
+        if let Err(e) = self.brlock(ltype, lock_offset(self.hash_size as i32), 0, wait) {
+            let _ = self.brunlock(FREELIST_TOP, 4 * self.hash_size);
+            return Err(e);
+        }
+        self.allrecord = AllRecordLock {
+            count: 1,
+            ltype: if upgradable { F_WRLCK } else { ltype },
+            upgradable,
+        };
+        if self.flags & TDB_NOLOCK == 0 && self.needs_recovery() {
+            self.allrecord_unlock(ltype)?;
+            self.lock_and_recover()?;
+            return self.allrecord_lock(ltype, wait, upgradable);
+        }
+        Ok(())
+    }
+
+    pub(super) fn allrecord_unlock(&mut self, ltype: i32) -> Result<(), Error> {
+        if self.allrecord.count == 0 {
+            return Err(Error::Lock);
+        }
+        if self.allrecord.ltype != ltype && !(self.allrecord.upgradable && ltype == F_RDLCK) {
+            return Err(Error::Lock);
+        }
+        if self.allrecord.count > 1 {
+            self.allrecord.count -= 1;
+            return Ok(());
+        }
+        self.allrecord = AllRecordLock::default();
+        self.brunlock(FREELIST_TOP, 0)
+    }
+
+    /// Upgrade the all-record lock of a transaction to a write lock before committing.
+    pub(super) fn allrecord_upgrade(&mut self) -> Result<(), Error> {
+        if self.allrecord.count != 1 || !self.allrecord.upgradable {
+            return Err(Error::Lock);
+        }
+        if self.flags & TDB_NOLOCK == 0 {
+            // Two processes upgrading at the same time deadlock, in which case the kernel fails
+            // one of them; back off and retry.
+            let mut delay = std::time::Duration::from_millis(1);
+            loop {
+                match fcntl_lock(self.io.fd(), F_WRLCK, FREELIST_TOP, 0, true) {
+                    Ok(()) => break,
+                    Err(e) if e.raw_os_error() == Some(libc::EDEADLK) && delay.as_secs() < 1 => {
+                        std::thread::sleep(delay);
+                        delay *= 2;
+                    }
+                    Err(_) => return Err(Error::Lock),
+                }
+            }
+        }
+        self.allrecord.ltype = F_WRLCK;
+        self.allrecord.upgradable = false;
+        Ok(())
+    }
+
+    /// Lock the record at `off` against deletion while a traversal is positioned on it.
+    pub(super) fn lock_record(&mut self, off: u32) -> Result<(), Error> {
+        if off == 0 || self.allrecord.count > 0 || self.transaction.is_some() {
+            return Ok(());
+        }
+        self.brlock(F_RDLCK, off, 1, true)
+    }
+
+    pub(super) fn unlock_record(&mut self, off: u32) -> Result<(), Error> {
+        if off == 0 || self.allrecord.count > 0 {
+            return Ok(());
+        }
+        // Several traversals may be positioned on the same record.
+        let count = self.travlocks.iter().filter(|tl| tl.off == off).count();
+        if count != 1 || self.transaction.is_some() {
+            return Ok(());
+        }
+        self.brunlock(off, 1)
+    }
+
+    /// Try to lock the record at `off` for deletion, failing if a traversal is on it.
+    pub(super) fn write_lock_record(&mut self, off: u32) -> Result<(), Error> {
+        if self.travlocks.iter().any(|tl: &TravLock| tl.off == off) {
+            return Err(Error::Lock);
+        }
+        if self.allrecord.count > 0 {
+            return if self.allrecord.ltype == F_WRLCK {
+                Ok(())
+            } else {
+                Err(Error::Lock)
+            };
+        }
+        if self.transaction.is_some() {
+            return Ok(());
+        }
+        self.brlock(F_WRLCK, off, 1, false)
+    }
+
+    pub(super) fn write_unlock_record(&mut self, off: u32) -> Result<(), Error> {
+        if self.allrecord.count > 0 || self.transaction.is_some() {
+            return Ok(());
+        }
+        self.brunlock(off, 1)
+    }
+
+    pub(super) fn transaction_lock(&mut self, ltype: i32, wait: bool) -> Result<(), Error> {
+        self.nest_lock(TRANSACTION_LOCK, ltype, wait)
+    }
+
+    pub(super) fn transaction_unlock(&mut self, ltype: i32) -> Result<(), Error> {
+        self.nest_unlock(TRANSACTION_LOCK, ltype)
+    }
+
+    /// Release all locks taken by a transaction, except the active lock.
+    pub(super) fn release_transaction_locks(&mut self) {
+        if self.allrecord.count > 0 {
+            self.allrecord = AllRecordLock::default();
+            let _ = self.brunlock(FREELIST_TOP, 0);
+        }
+        for lck in std::mem::take(&mut self.locks) {
+            if lck.off == ACTIVE_LOCK {
+                self.locks.push(lck);
+            } else {
+                let _ = self.brunlock(lck.off, 1);
+            }
+        }
+    }
+
+    /// Return whether a crashed commit left a recovery record to be replayed.
+    pub(super) fn needs_recovery(&mut self) -> bool {
+        let Ok(head) = self.read_u32(RECOVERY_START_OFS) else {
+            return true;
+        };
+        if head == 0 {
+            return false;
+        }
+        // The recovery area is outside the transaction, so always read it from the file.
+        match self.io.read(head, RECORD_LEN) {
+            Ok(rec) => super::io::decode_u32(&rec[20..24], self.big_endian) == TDB_RECOVERY_MAGIC,
+            Err(_) => true,
+        }
+    }
+
+    /// Take the locks needed to replay the recovery area and replay it.
+    pub(super) fn lock_and_recover(&mut self) -> Result<(), Error> {
+        self.brlock(F_WRLCK, FREELIST_TOP, 0, true)?;
+        if let Err(e) = self.brlock(F_WRLCK, OPEN_LOCK, 1, true) {
+            let _ = self.brunlock(FREELIST_TOP, 0);
+            return Err(e);
+        }
+        let ret = self.transaction_recover();
+        let _ = self.brunlock(OPEN_LOCK, 1);
+        let _ = self.brunlock(FREELIST_TOP, 0);
+        ret
+    }
+}
@@ -0,0 +1,921 @@
+//! The native backend: an implementation of the TDB file format in Rust.
+//!
+//! The code follows the structure of libtdb closely. Files are laid out exactly as libtdb lays
+//! them out, and the same byte ranges are locked with `fcntl`, so processes using libtdb and
+//! processes using this backend can safely share a database.
+//!
+//! Not supported are robust mutex locking (`Flags::MutexLocking`) and files created with a
+//! custom hash function; opening such files fails.
+
+mod check;
+#[cfg(all(test, not(feature = "native")))]
+mod compat;
+mod freelist;
+mod io;
+mod lock;
+mod summary;
+mod tdb;
+mod transaction;
+mod traverse;
+
+use self::io::Io;
+use self::lock::{AllRecordLock, LockRec, ACTIVE_LOCK, OPEN_LOCK};
+use self::transaction::Transaction;
+use self::traverse::TravLock;
+use crate::generated::{
+    mode_t, TDB_ALLOW_NESTING, TDB_CLEAR_IF_FIRST, TDB_CONVERT, TDB_DISALLOW_NESTING,
+    TDB_INCOMPATIBLE_HASH, TDB_INTERNAL, TDB_MUTEX_LOCKING, TDB_NOLOCK, TDB_NOMMAP, TDB_SEQNUM,
+    TDB_VOLATILE,
+};
+use crate::reader::{
+    detect_hash, parse_header, HashFunction, FREELIST_TOP, HASH_SIZE_OFS, HEADER_LEN,
+    MAGIC1_HASH_OFS, MAGIC2_HASH_OFS, MAGIC_FOOD, RWLOCKS_OFS, TDB_FEATURE_FLAG_MAGIC,
+    TDB_HASH_RWLOCK_MAGIC, TDB_VERSION, VERSION_OFS,
+};
+use crate::{Error, Flags, StoreFlags, O_CREAT, O_RDWR, O_TRUNC};
+use libc::{F_RDLCK, F_WRLCK};
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, TryLockError};
+
+/// The list number of the freelist, which is locked like a hash chain.
+pub(super) const FREELIST: i32 = -1;
+
+/// A key and its value.
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// The hash size used when none is given, as in libtdb.
+const DEFAULT_HASH_SIZE: u32 = 131;
+
+/// The device and inode of every database open in this process.
+///
+/// fcntl locks belong to the process, and closing any descriptor of a file drops all of them,
+/// so a file must not be opened twice.
+static OPEN_FILES: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+/// An open database.
+pub(crate) struct Context {
+    path: PathBuf,
+    /// The `TDB_*` flags the database was opened with.
+    flags: u32,
+    read_only: bool,
+    open_flags: i32,
+    io: Io,
+    hash: HashFunction,
+    hash_size: u32,
+    /// The byte order of the file.
+    big_endian: bool,
+    page_size: u32,
+    dev_ino: Option<(u64, u64)>,
+    /// Locks on single bytes, such as hash chains, held by this handle.
+    locks: Vec<LockRec>,
+    allrecord: AllRecordLock,
+    /// Positions of the traversals and key iterators in progress.
+    travlocks: Vec<TravLock>,
+    next_travlock_id: u64,
+    traverse_read: u32,
+    traverse_write: u32,
+    /// How many dead records to keep in a hash chain before freeing them.
+    max_dead: u32,
+    transaction: Option<Box<Transaction>>,
+}
+
+/// Build the contents of an empty database.
+fn new_database(hash_size: u32, hash: HashFunction, big_endian: bool) -> Vec<u8> {
+    let mut data = vec![0; (FREELIST_TOP + 4 * (hash_size + 1)) as usize];
+    let mut put = |ofs: u32, v: u32| {
+        data[ofs as usize..ofs as usize + 4].copy_from_slice(&io::encode_u32(v, big_endian))
+    };
+    put(VERSION_OFS, TDB_VERSION);
+    put(HASH_SIZE_OFS, hash_size);
+    if hash == HashFunction::Jenkins {
+        // Keep versions of libtdb that only know the old hash from opening the file.
+        put(RWLOCKS_OFS, TDB_HASH_RWLOCK_MAGIC);
+    }
+    let (magic1, magic2) = hash.magic_hashes(big_endian);
+    put(MAGIC1_HASH_OFS, magic1);
+    put(MAGIC2_HASH_OFS, magic2);
+    data[..MAGIC_FOOD.len()].copy_from_slice(MAGIC_FOOD);
+    data
+}
+
+fn open_file(path: &Path, open_flags: i32, mode: mode_t) -> Result<File, Error> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::Invalid)?;
+    let fd = unsafe {
+        libc::open(
+            c_path.as_ptr(),
+            open_flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    };
+    if fd == -1 {
+        return Err(Error::IO);
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn page_size() -> u32 {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        n if n > 0 => n as u32,
+        _ => 4096,
+    }
+}
+
+impl Context {
+    fn open(
+        path: &Path,
+        hash_size: Option<u32>,
+        mut flags: u32,
+        open_flags: i32,
+        mode: mode_t,
+    ) -> Result<Context, Error> {
+        if flags & TDB_INTERNAL != 0 {
+            flags |= TDB_NOLOCK | TDB_NOMMAP;
+            flags &= !TDB_CLEAR_IF_FIRST;
+        }
+        if flags & TDB_ALLOW_NESTING != 0 && flags & TDB_DISALLOW_NESTING != 0 {
+            return Err(Error::Invalid);
+        }
+        // Nesting is the default.
+        if flags & TDB_DISALLOW_NESTING == 0 {
+            flags |= TDB_ALLOW_NESTING;
+        }
+        if flags & TDB_MUTEX_LOCKING != 0 || open_flags & libc::O_ACCMODE == libc::O_WRONLY {
+            return Err(Error::Invalid);
+        }
+        let read_only = open_flags & libc::O_ACCMODE == libc::O_RDONLY;
+        if read_only {
+            // Read-only databases don't lock, and can't be cleared.
+            flags |= TDB_NOLOCK;
+            flags &= !TDB_CLEAR_IF_FIRST;
+        }
+        let hash_size = hash_size.filter(|&n| n > 0).unwrap_or(DEFAULT_HASH_SIZE);
+        let hash = if flags & TDB_INCOMPATIBLE_HASH != 0 {
+            HashFunction::Jenkins
+        } else {
+            HashFunction::Old
+        };
+        let big_endian = cfg!(target_endian = "big");
+
+        let io = if flags & TDB_INTERNAL != 0 {
+            Io::memory(new_database(hash_size, hash, big_endian))
+        } else {
+            let file = open_file(path, open_flags, mode)?;
+            Io::file(file, flags & TDB_NOMMAP == 0, !read_only)?
+        };
+        let mut ctx = Context {
+            path: path.to_path_buf(),
+            flags,
+            read_only,
+            open_flags,
+            io,
+            hash,
+            hash_size,
+            big_endian,
+            page_size: page_size(),
+            dev_ino: None,
+            locks: Vec::new(),
+            allrecord: AllRecordLock::default(),
+            travlocks: Vec::new(),
+            next_travlock_id: 0,
+            traverse_read: 0,
+            traverse_write: 0,
+            max_dead: if flags & TDB_VOLATILE != 0 { 5 } else { 0 },
+            transaction: None,
+        };
+        if flags & TDB_INTERNAL != 0 {
+            return Ok(ctx);
+        }
+
+        let meta = ctx
+            .io
+            .file_handle()
+            .unwrap()
+            .metadata()
+            .map_err(|_| Error::IO)?;
+        let dev_ino = (meta.dev(), meta.ino());
+        {
+            let mut open_files = OPEN_FILES.lock().unwrap();
+            if open_files.contains(&dev_ino) {
+                return Err(Error::Invalid);
+            }
+            open_files.push(dev_ino);
+        }
+        ctx.dev_ino = Some(dev_ino);
+
+        // Only one process may initialise the database at a time.
+        ctx.nest_lock(OPEN_LOCK, F_WRLCK, true)?;
+        ctx.open_locked(open_flags)?;
+        ctx.nest_unlock(OPEN_LOCK, F_WRLCK)?;
+        Ok(ctx)
+    }
+
+    /// Read the header, creating or clearing the database if needed. The open lock is held.
+    fn open_locked(&mut self, open_flags: i32) -> Result<(), Error> {
+        // Clear the database if we are the only process with it open.
+        let mut active_locked = false;
+        if self.flags & TDB_CLEAR_IF_FIRST != 0
+            && self.nest_lock(ACTIVE_LOCK, F_WRLCK, false).is_ok()
+        {
+            active_locked = true;
+            self.brlock(F_WRLCK, FREELIST_TOP, 0, true)?;
+            let data = new_database(self.hash_size, self.hash, self.big_endian);
+            let ret = self.io.replace(&data);
+            self.brunlock(FREELIST_TOP, 0)?;
+            ret?;
+        }
+
+        let mut head = vec![0; HEADER_LEN as usize];
+        let header = match self
+            .io
+            .read_into(0, &mut head)
+            .and_then(|_| parse_header(&head))
+        {
+            Ok(header) => header,
+            Err(_) if head.starts_with(MAGIC_FOOD) => return Err(Error::IO),
+            Err(_) if open_flags & O_CREAT != 0 => {
+                let data = new_database(self.hash_size, self.hash, self.big_endian);
+                self.io.replace(&data)?;
+                parse_header(&data)?
+            }
+            Err(_) => return Err(Error::IO),
+        };
+        if header.rwlocks == TDB_FEATURE_FLAG_MAGIC && header.feature_flags != 0 {
+            // Only mutexes use feature flags, and they aren't supported.
+            return Err(Error::Invalid);
+        }
+        self.hash_size = header.hash_size;
+        self.hash = detect_hash(&header)?;
+        self.big_endian = header.big_endian;
+        if self.big_endian != cfg!(target_endian = "big") {
+            self.flags |= TDB_CONVERT;
+        }
+        self.io.oob(0, self.data_start())?;
+
+        // A process may have crashed while committing.
+        self.transaction_recover()?;
+
+        if active_locked {
+            self.nest_unlock(ACTIVE_LOCK, F_WRLCK)?;
+        }
+        if self.flags & TDB_CLEAR_IF_FIRST != 0 {
+            // Let other processes know the database is in use; this is held until closing.
+            self.nest_lock(ACTIVE_LOCK, F_RDLCK, true)?;
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> Result<(), Error> {
+        if self.transaction.is_some() || self.have_extra_locks() {
+            return Err(Error::Invalid);
+        }
+        if self.flags & TDB_INTERNAL != 0 {
+            return Ok(());
+        }
+        let file = open_file(&self.path, self.open_flags & !(O_CREAT | O_TRUNC), 0)?;
+        let meta = file.metadata().map_err(|_| Error::IO)?;
+        if Some((meta.dev(), meta.ino())) != self.dev_ino {
+            // The file was replaced since we opened it.
+            return Err(Error::IO);
+        }
+        // Closing the old descriptor drops all our locks, so take the active lock again.
+        self.io = Io::file(file, self.flags & TDB_NOMMAP == 0, !self.read_only)?;
+        self.relock_active()
+    }
+
+    fn reopen_after_fork(&mut self, parent_longlived: bool) -> Result<(), Error> {
+        if parent_longlived && self.flags & TDB_CLEAR_IF_FIRST != 0 {
+            // The parent keeps holding the active lock for as long as it runs, so the child
+            // doesn't need it.
+            self.flags &= !TDB_CLEAR_IF_FIRST;
+            self.nest_unlock(ACTIVE_LOCK, F_RDLCK)?;
+        }
+        self.reopen()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.transaction.is_some() {
+            let _ = self.transaction_cancel();
+        }
+        if let Some(dev_ino) = self.dev_ino {
+            OPEN_FILES.lock().unwrap().retain(|&open| open != dev_ino);
+        }
+    }
+}
+
+/// The addresses of the contexts of all open handles, for [`reopen_all`].
+static HANDLES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+pub(crate) struct Handle {
+    name: String,
+    /// Boxed, so that its address stays the same while it is registered in [`HANDLES`].
+    ctx: Box<RefCell<Context>>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let addr = &*self.ctx as *const RefCell<Context> as usize;
+        HANDLES.lock().unwrap().retain(|&handle| handle != addr);
+    }
+}
+
+/// Reopen every open database, e.g. in the child after a fork.
+///
+/// The databases that can be reopened are, even if others can't; the first error is returned.
+///
+/// # Safety
+///
+/// No other thread may be using any of the databases.
+pub(crate) unsafe fn reopen_all(parent_longlived: bool) -> Result<(), Error> {
+    // In the child of a fork, a thread of the parent that was opening or closing a database may
+    // have held the registry, and will never release it; don't wait for it.
+    let handles = match HANDLES.try_lock() {
+        Ok(handles) => handles,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return Err(Error::Lock),
+    };
+    let mut ret = Ok(());
+    for &addr in handles.iter() {
+        let ctx = &*(addr as *const RefCell<Context>);
+        let reopened = ctx
+            .try_borrow_mut()
+            .map_err(|_| Error::Invalid)
+            .and_then(|mut ctx| ctx.reopen_after_fork(parent_longlived));
+        if ret.is_ok() {
+            ret = reopened;
+        }
+    }
+    ret
+}
+
+/// Ends a traversal when dropped, even if the callback panicked.
+struct Traversal<'a> {
+    handle: &'a Handle,
+    id: u64,
+    ltype: i32,
+}
+
+impl Drop for Traversal<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .handle
+            .ctx
+            .borrow_mut()
+            .end_traverse(self.id, self.ltype);
+    }
+}
+
+/// Releases a read lock on all records when dropped, even if a callback panicked.
+struct AllRecordRead<'a>(&'a Handle);
+
+impl Drop for AllRecordRead<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.ctx.borrow_mut().allrecord_unlock(F_RDLCK);
+    }
+}
+
+impl Handle {
+    pub(crate) fn open(
+        name: &Path,
+        hash_size: Option<u32>,
+        tdb_flags: Flags,
+        open_flags: i32,
+        mode: mode_t,
+    ) -> Option<Handle> {
+        let ctx = Context::open(name, hash_size, tdb_flags.bits(), open_flags, mode).ok()?;
+        let ctx = Box::new(RefCell::new(ctx));
+        HANDLES
+            .lock()
+            .unwrap()
+            .push(&*ctx as *const RefCell<Context> as usize);
+        Some(Handle {
+            name: name.to_string_lossy().into_owned(),
+            ctx,
+        })
+    }
+
+    pub(crate) fn memory(hash_size: Option<u32>, tdb_flags: Flags) -> Option<Handle> {
+        Handle::open(
+            Path::new(":memory:"),
+            hash_size,
+            tdb_flags | Flags::Internal,
+            O_RDWR | O_CREAT,
+            0,
+        )
+    }
+
+    fn lock_list_of(&self, key: &[u8], ltype: i32, wait: bool) -> Result<(), Error> {
+        let mut ctx = self.ctx.borrow_mut();
+        let list = ctx.bucket(ctx.hash.hash(key)) as i32;
+        ctx.lock_list(list, ltype, wait)
+    }
+
+    fn unlock_list_of(&self, key: &[u8], ltype: i32) -> Result<(), Error> {
+        let mut ctx = self.ctx.borrow_mut();
+        let list = ctx.bucket(ctx.hash.hash(key)) as i32;
+        ctx.unlock_list(list, ltype)
+    }
+
+    pub(crate) fn set_max_dead(&mut self, max_dead: u32) {
+        self.ctx.get_mut().max_dead = max_dead;
+    }
+
+    pub(crate) fn reopen(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().reopen()
+    }
+
+    pub(crate) fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.ctx.borrow_mut().fetch(key)
+    }
+
+    #[cfg(feature = "bytes")]
+    pub(crate) fn fetch_bytes(&self, key: &[u8]) -> Result<Option<::bytes::Bytes>, Error> {
+        Ok(self.fetch(key)?.map(::bytes::Bytes::from))
+    }
+
+    pub(crate) fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        self.ctx.get_mut().store(key, val, flags)
+    }
+
+    pub(crate) fn storev(
+        &mut self,
+        key: &[u8],
+        vals: &[&[u8]],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        self.store(key, &vals.concat(), flags)
+    }
+
+    pub(crate) fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.ctx.get_mut().delete(key)
+    }
+
+    pub(crate) fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        self.ctx.get_mut().append(key, val)
+    }
+
+    pub(crate) fn keys(&self) -> Keys<'_> {
+        let id = self.ctx.borrow_mut().push_travlock(F_RDLCK);
+        Keys {
+            handle: self,
+            id,
+            prev: None,
+        }
+    }
+
+    pub(crate) fn keys_after(&self, key: &[u8]) -> Keys<'_> {
+        let id = self.ctx.borrow_mut().push_travlock(F_RDLCK);
+        Keys {
+            handle: self,
+            id,
+            prev: Some(key.to_vec()),
+        }
+    }
+
+    pub(crate) fn traverse<F: FnMut(&[u8], &[u8]) -> bool>(
+        &self,
+        write: bool,
+        mut f: F,
+    ) -> Result<usize, Error> {
+        let (id, ltype) = self.ctx.borrow_mut().start_traverse(write)?;
+        let _traversal = Traversal {
+            handle: self,
+            id,
+            ltype,
+        };
+        let mut count = 0;
+        // The context is not borrowed while the callback runs, so it may use the database.
+        while let Some((key, data)) = self.ctx.borrow_mut().traverse_next(id)? {
+            count += 1;
+            if !f(&key, &data) {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn exists(&self, key: &[u8]) -> bool {
+        self.ctx.borrow_mut().exists(key).unwrap_or(false)
+    }
+
+    pub(crate) fn check<F: FnMut(&[u8], &[u8]) -> bool>(&self, mut f: F) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_lock(F_RDLCK, true, false)?;
+        let _lock = AllRecordRead(self);
+        let live = self.ctx.borrow_mut().check_structure()?;
+        // As with traversals, the context is not borrowed while the callback runs.
+        for off in live {
+            let (key, data) = self.ctx.borrow_mut().read_key_data(off)?;
+            if !f(&key, &data) {
+                return Err(Error::Corrupt);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn lockall(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_lock(F_WRLCK, true, false)
+    }
+
+    pub(crate) fn unlockall(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_unlock(F_WRLCK)
+    }
+
+    pub(crate) fn lockall_nonblock(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_lock(F_WRLCK, false, false)
+    }
+
+    pub(crate) fn lockall_read(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_lock(F_RDLCK, true, false)
+    }
+
+    pub(crate) fn lockall_read_nonblock(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_lock(F_RDLCK, false, false)
+    }
+
+    pub(crate) fn unlockall_read(&self) -> Result<(), Error> {
+        self.ctx.borrow_mut().allrecord_unlock(F_RDLCK)
+    }
+
+    pub(crate) fn chainlock(&self, key: &[u8]) -> Result<(), Error> {
+        self.lock_list_of(key, F_WRLCK, true)
+    }
+
+    pub(crate) fn chainlock_nonblock(&self, key: &[u8]) -> Result<(), Error> {
+        self.lock_list_of(key, F_WRLCK, false)
+    }
+
+    pub(crate) fn chainunlock(&self, key: &[u8]) -> Result<(), Error> {
+        self.unlock_list_of(key, F_WRLCK)
+    }
+
+    pub(crate) fn chainlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        self.lock_list_of(key, F_RDLCK, true)
+    }
+
+    pub(crate) fn chainunlock_read(&self, key: &[u8]) -> Result<(), Error> {
+        self.unlock_list_of(key, F_RDLCK)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn hash_size(&self) -> u32 {
+        self.ctx.borrow().hash_size
+    }
+
+    pub(crate) fn map_size(&self) -> u32 {
+        self.ctx.borrow().map_size()
+    }
+
+    pub(crate) fn hash_function(&self) -> HashFunction {
+        self.ctx.borrow().hash
+    }
+
+    pub(crate) fn get_seqnum(&self) -> u64 {
+        self.ctx.borrow_mut().get_seqnum() as u64
+    }
+
+    pub(crate) fn get_flags(&self) -> Flags {
+        Flags::from_bits_truncate(self.ctx.borrow().flags)
+    }
+
+    pub(crate) fn add_flags(&mut self, flags: Flags) {
+        let ctx = self.ctx.get_mut();
+        let flags = flags.bits();
+        if flags & TDB_ALLOW_NESTING != 0 && flags & TDB_DISALLOW_NESTING != 0 {
+            return;
+        }
+        if flags & TDB_ALLOW_NESTING != 0 {
+            ctx.flags &= !TDB_DISALLOW_NESTING;
+        }
+        if flags & TDB_DISALLOW_NESTING != 0 {
+            ctx.flags &= !TDB_ALLOW_NESTING;
+        }
+        ctx.flags |= flags;
+    }
+
+    pub(crate) fn remove_flags(&mut self, flags: Flags) {
+        let ctx = self.ctx.get_mut();
+        let flags = flags.bits();
+        if flags & TDB_ALLOW_NESTING != 0 && flags & TDB_DISALLOW_NESTING != 0 {
+            return;
+        }
+        if flags & TDB_ALLOW_NESTING != 0 {
+            ctx.flags |= TDB_DISALLOW_NESTING;
+        }
+        if flags & TDB_DISALLOW_NESTING != 0 {
+            ctx.flags |= TDB_ALLOW_NESTING;
+        }
+        ctx.flags &= !flags;
+    }
+
+    pub(crate) fn enable_seqnum(&mut self) {
+        self.ctx.get_mut().flags |= TDB_SEQNUM;
+    }
+
+    pub(crate) fn increment_seqnum_nonblock(&mut self) {
+        self.ctx.get_mut().increment_seqnum_nonblock()
+    }
+
+    pub(crate) fn repack(&mut self) -> Result<(), Error> {
+        let ctx = self.ctx.get_mut();
+        ctx.transaction_start(true)?;
+        match ctx.repack_locked() {
+            Ok(()) => ctx.transaction_commit(),
+            Err(e) => {
+                let _ = ctx.transaction_cancel();
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn wipe_all(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().wipe_all()
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        self.ctx.borrow_mut().summary().unwrap_or_default()
+    }
+
+    pub(crate) fn freelist_size(&self) -> u32 {
+        self.ctx.borrow_mut().freelist_size().unwrap_or(0)
+    }
+
+    pub(crate) fn validate_freelist(&self) -> Result<u32, Error> {
+        self.ctx.borrow_mut().validate_freelist()
+    }
+
+    pub(crate) fn transaction_start(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().transaction_start(true)
+    }
+
+    pub(crate) fn transaction_active(&self) -> bool {
+        self.ctx.borrow().transaction.is_some()
+    }
+
+    pub(crate) fn transaction_start_nonblock(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().transaction_start(false)
+    }
+
+    pub(crate) fn transaction_prepare_commit(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().transaction_prepare_commit()
+    }
+
+    pub(crate) fn transaction_commit(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().transaction_commit()
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.ctx.borrow().io.fd()
+    }
+
+    pub(crate) fn transaction_cancel(&mut self) -> Result<(), Error> {
+        self.ctx.get_mut().transaction_cancel()
+    }
+}
+
+pub(crate) struct Keys<'a> {
+    handle: &'a Handle,
+    id: u64,
+    prev: Option<Vec<u8>>,
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut ctx = self.handle.ctx.borrow_mut();
+        let key = match self.prev.take() {
+            Some(prev) => ctx.nextkey(self.id, &prev),
+            None => ctx.firstkey(self.id),
+        };
+        match key {
+            Ok(Some(key)) => {
+                self.prev = Some(key.clone());
+                Some(key)
+            }
+            Ok(None) | Err(Error::NoExist) => None,
+            Err(e) => panic!("TDB iterator error: {}", e),
+        }
+    }
+}
+
+impl Drop for Keys<'_> {
+    fn drop(&mut self) {
+        let _ = self.handle.ctx.borrow_mut().pop_travlock(self.id);
+    }
+}
+
+pub(crate) fn jenkins_hash(key: &[u8]) -> u32 {
+    HashFunction::Jenkins.hash(key)
+}
+
+#[cfg(all(test, feature = "native"))]
+mod test {
+    use crate::reader::TdbReader;
+    use crate::{Flags, Tdb};
+    use std::collections::BTreeMap;
+    use std::os::unix::io::AsRawFd;
+
+    fn create(dir: &tempfile::TempDir, flags: Flags) -> Tdb {
+        Tdb::open(
+            dir.path().join("test.tdb"),
+            Some(7),
+            flags,
+            libc::O_RDWR | libc::O_CREAT,
+            0o600,
+        )
+        .unwrap()
+    }
+
+    /// Check that the database, and the file as seen by `TdbReader`, hold exactly `expected`.
+    fn assert_contents(dir: &tempfile::TempDir, tdb: &Tdb, expected: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        let actual = tdb.iter().collect::<BTreeMap<_, _>>();
+        assert_eq!(&actual, expected);
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        let actual = reader
+            .iter()
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(&actual, expected);
+        assert_eq!(
+            reader.freelist().unwrap().len(),
+            tdb.freelist_size() as usize
+        );
+    }
+
+    #[test]
+    fn test_random_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir, Flags::empty());
+        let mut expected = BTreeMap::new();
+        let mut seed = 0x2545f491u32;
+        let mut rand = move |n: u32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed % n
+        };
+
+        for round in 0..20 {
+            let transaction = round % 3 != 0;
+            let mut pending = expected.clone();
+            if transaction {
+                tdb.transaction_start().unwrap();
+            }
+            for _ in 0..100 {
+                let key = format!("key{}", rand(150)).into_bytes();
+                let value = vec![b'a' + rand(26) as u8; rand(300) as usize];
+                match rand(4) {
+                    0 | 1 => {
+                        tdb.store(&key, &value, None).unwrap();
+                        pending.insert(key, value);
+                    }
+                    2 => {
+                        tdb.append(&key, &value).unwrap();
+                        pending.entry(key).or_default().extend(value);
+                    }
+                    _ => match pending.remove(&key) {
+                        Some(_) => tdb.delete(&key).unwrap(),
+                        None => assert!(tdb.delete(&key).is_err()),
+                    },
+                }
+            }
+            if transaction && round % 5 == 4 {
+                tdb.transaction_cancel().unwrap();
+            } else {
+                if transaction {
+                    tdb.transaction_commit().unwrap();
+                }
+                expected = pending;
+            }
+            if round % 7 == 6 {
+                tdb.repack().unwrap();
+            }
+            assert_contents(&dir, &tdb, &expected);
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let mut tdb = create(&dir, Flags::empty());
+        let mut expected = BTreeMap::new();
+        for i in 0..50u32 {
+            let (key, value) = (format!("key{}", i), format!("value{}", i));
+            tdb.store(key.as_bytes(), value.as_bytes(), None).unwrap();
+            expected.insert(key.into_bytes(), value.into_bytes());
+        }
+
+        tdb.transaction_start().unwrap();
+        for i in 0..50u32 {
+            tdb.store(format!("key{}", i).as_bytes(), &[b'x'; 1000], None)
+                .unwrap();
+        }
+        tdb.transaction_prepare_commit().unwrap();
+        // Pretend we crashed half-way through writing out the transaction, after the recovery
+        // area was written and some of the hash table was overwritten.
+        let mut crashed = std::fs::read(&path).unwrap();
+        crashed[200..300].fill(0xff);
+        tdb.transaction_cancel().unwrap();
+        drop(tdb);
+        std::fs::write(&path, crashed).unwrap();
+
+        let tdb = create(&dir, Flags::empty());
+        assert_contents(&dir, &tdb, &expected);
+    }
+
+    #[test]
+    fn test_freelist_reuse() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir, Flags::empty());
+        let free_space = |tdb: &Tdb| {
+            let reader = TdbReader::from_fd(tdb.as_raw_fd()).unwrap();
+            let freelist = reader.freelist().unwrap();
+            freelist.iter().map(|block| block.rec_len).sum::<u32>()
+        };
+        let mut expected = BTreeMap::new();
+        for i in 0..20u32 {
+            let key = format!("key{:02}", i).into_bytes();
+            tdb.store(&key, &[b'v'; 100], None).unwrap();
+            expected.insert(key, vec![b'v'; 100]);
+        }
+        for i in 0..10u32 {
+            let key = format!("key{:02}", i * 2).into_bytes();
+            tdb.delete(&key).unwrap();
+            expected.remove(&key);
+        }
+        let map_size = tdb.map_size();
+        let free = free_space(&tdb);
+
+        // Records of the same size fit in the space of the deleted ones.
+        for i in 0..10u32 {
+            let key = format!("new{:02}", i).into_bytes();
+            tdb.store(&key, &[b'w'; 100], None).unwrap();
+            expected.insert(key, vec![b'w'; 100]);
+        }
+        assert_eq!(tdb.map_size(), map_size);
+        assert!(free_space(&tdb) < free);
+        tdb.validate_freelist().unwrap();
+        assert_contents(&dir, &tdb, &expected);
+    }
+
+    #[test]
+    fn test_open_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let _tdb = create(&dir, Flags::empty());
+        assert!(Tdb::open(
+            dir.path().join("test.tdb"),
+            None,
+            Flags::empty(),
+            libc::O_RDWR,
+            0
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_clear_if_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir, Flags::ClearIfFirst);
+        tdb.store(b"foo", b"bar", None).unwrap();
+        drop(tdb);
+        let tdb = create(&dir, Flags::ClearIfFirst);
+        assert_eq!(tdb.fetch(b"foo").unwrap(), None);
+    }
+
+    #[test]
+    fn test_reopen_all_does_not_wait() {
+        // As in the child of a fork made while another thread was opening a database.
+        let _handles = super::HANDLES.lock().unwrap();
+        assert!(matches!(
+            unsafe { super::reopen_all(false) },
+            Err(crate::Error::Lock)
+        ));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        tdb.store(b"foo", b"bar", None).unwrap();
+        let summary = tdb.summary();
+        assert!(summary.contains("Number of records: 1\n"));
+        assert!(summary.contains("Number of hash chains: 131\n"));
+    }
+}
@@ -0,0 +1,184 @@
+//! A human-readable summary of the database, in the format of libtdb's `summary.c`.
+
+use super::io::Record;
+use super::Context;
+use crate::reader::HashFunction;
+use crate::reader::{
+    RECORD_LEN, RECOVERY_START_OFS, TDB_DEAD_MAGIC, TDB_FREE_MAGIC, TDB_MAGIC, TDB_RECOVERY_MAGIC,
+};
+use crate::Error;
+use libc::F_RDLCK;
+
+/// Magic of a recovery area that has been invalidated.
+pub(super) const TDB_RECOVERY_INVALID_MAGIC: u32 = 0;
+/// Magic read from the padding of an expansion that was never written to.
+pub(super) const PAD_MAGIC: u32 = 0x42424242;
+
+/// The minimum, maximum and total of a set of sizes.
+#[derive(Default)]
+struct Tally {
+    num: usize,
+    total: usize,
+    min: usize,
+    max: usize,
+}
+
+impl Tally {
+    fn add(&mut self, len: usize) {
+        if self.num == 0 {
+            self.min = len;
+            self.max = len;
+        } else {
+            self.min = self.min.min(len);
+            self.max = self.max.max(len);
+        }
+        self.num += 1;
+        self.total += len;
+    }
+
+    fn mean(&self) -> usize {
+        self.total.checked_div(self.num).unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for Tally {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.min, self.mean(), self.max)
+    }
+}
+
+impl Context {
+    pub(super) fn summary(&mut self) -> Result<String, Error> {
+        self.allrecord_lock(F_RDLCK, true, false)?;
+        let ret = self.summary_locked();
+        self.allrecord_unlock(F_RDLCK)?;
+        ret
+    }
+
+    /// Return the length of the run of zeroes or padding at `off`, left behind by a crash
+    /// while expanding the file.
+    pub(super) fn dead_space(&mut self, off: u32) -> u32 {
+        let mut len = 0;
+        while off + len < self.map_size() {
+            match self.read(off + len, 1) {
+                Ok(c) if c[0] == 0 || c[0] == 0x42 => len += 1,
+                Ok(_) => break,
+                Err(_) => return 0,
+            }
+        }
+        len
+    }
+
+    fn summary_locked(&mut self) -> Result<String, Error> {
+        let (mut keys, mut data, mut extra) =
+            (Tally::default(), Tally::default(), Tally::default());
+        let (mut dead, mut free, mut hashes) =
+            (Tally::default(), Tally::default(), Tally::default());
+        let mut uncoal = Tally::default();
+        let mut unc = 0;
+
+        let recovery = self.read_u32(RECOVERY_START_OFS)?;
+        let map_size = self.map_size();
+        let mut off = self.data_start();
+        while off < map_size - 1 {
+            let mut rec: Record = self.read_record(off)?;
+            match rec.magic {
+                TDB_MAGIC => {
+                    keys.add(rec.key_len as usize);
+                    data.add(rec.data_len as usize);
+                    extra.add((rec.rec_len - (rec.key_len + rec.data_len)) as usize);
+                    if unc > 1 {
+                        uncoal.add(unc - 1);
+                    }
+                    unc = 0;
+                }
+                TDB_FREE_MAGIC => {
+                    free.add(rec.rec_len as usize);
+                    unc += 1;
+                }
+                TDB_RECOVERY_INVALID_MAGIC | PAD_MAGIC | TDB_RECOVERY_MAGIC | TDB_DEAD_MAGIC => {
+                    if matches!(rec.magic, TDB_RECOVERY_INVALID_MAGIC | PAD_MAGIC) {
+                        unc += 1;
+                        // Only the length of a recovery area can be trusted.
+                        if off != recovery {
+                            rec.rec_len = self.dead_space(off).saturating_sub(RECORD_LEN);
+                        }
+                    }
+                    dead.add(rec.rec_len as usize);
+                }
+                _ => return Err(Error::Corrupt),
+            }
+            off = off
+                .checked_add(RECORD_LEN + rec.rec_len)
+                .ok_or(Error::Corrupt)?;
+        }
+        if unc > 1 {
+            uncoal.add(unc - 1);
+        }
+
+        for bucket in 0..self.hash_size {
+            let mut len = 0;
+            let mut off = self.read_u32(self.hash_top(bucket))?;
+            while off != 0 {
+                off = self.read_u32(off)?;
+                len += 1;
+                if len > (map_size / RECORD_LEN) as usize {
+                    return Err(Error::Corrupt);
+                }
+            }
+            hashes.add(len);
+        }
+
+        let percent = |n: usize| n as f64 * 100.0 / map_size as f64;
+        let headers = (keys.num + free.num + dead.num) * (RECORD_LEN as usize + 4);
+        let hash_table = self.hash_size as usize * 4;
+        Ok(format!(
+            "Size of file/data: {}/{}\n\
+             Number of records: {}\n\
+             Incompatible hash: {}\n\
+             Active/supported feature flags: 0x{:08x}/0x{:08x}\n\
+             Robust mutexes locking: no\n\
+             Smallest/average/largest keys: {}\n\
+             Smallest/average/largest data: {}\n\
+             Smallest/average/largest padding: {}\n\
+             Number of dead records: {}\n\
+             Smallest/average/largest dead records: {}\n\
+             Number of free records: {}\n\
+             Smallest/average/largest free records: {}\n\
+             Number of hash chains: {}\n\
+             Smallest/average/largest hash chains: {}\n\
+             Number of uncoalesced records: {}\n\
+             Smallest/average/largest uncoalesced runs: {}\n\
+             Percentage keys/data/padding/free/dead/rechdrs&tailers/hashes: \
+             {:.0}/{:.0}/{:.0}/{:.0}/{:.0}/{:.0}/{:.0}\n",
+            map_size,
+            keys.total + data.total,
+            keys.num,
+            if self.hash == HashFunction::Jenkins {
+                "yes"
+            } else {
+                "no"
+            },
+            0,
+            0,
+            keys,
+            data,
+            extra,
+            dead.num,
+            dead,
+            free.num,
+            free,
+            hashes.num,
+            hashes,
+            uncoal.total,
+            uncoal,
+            percent(keys.total),
+            percent(data.total),
+            percent(extra.total),
+            percent(free.total),
+            percent(dead.total),
+            percent(headers),
+            percent(hash_table),
+        ))
+    }
+}
@@ -0,0 +1,339 @@
+//! Fetching, storing and deleting records, mirroring libtdb's `tdb.c`.
+
+use super::io::Record;
+use super::{Context, Entry};
+use crate::generated::TDB_SEQNUM;
+use crate::reader::{
+    FREELIST_TOP, RECORD_LEN, RECOVERY_START_OFS, SEQUENCE_NUMBER_OFS, TDB_DEAD_MAGIC, TDB_MAGIC,
+};
+use crate::{Error, StoreFlags};
+use libc::{F_RDLCK, F_WRLCK};
+
+impl Context {
+    /// Return the offset of the head of hash chain `bucket`.
+    pub(super) fn hash_top(&self, bucket: u32) -> u32 {
+        FREELIST_TOP + (bucket + 1) * 4
+    }
+
+    pub(super) fn bucket(&self, hash: u32) -> u32 {
+        hash % self.hash_size
+    }
+
+    /// Find the live record for `key` in its hash chain, which must be locked.
+    pub(super) fn find(&mut self, key: &[u8], hash: u32) -> Result<Option<(u32, Record)>, Error> {
+        let mut off = self.read_u32(self.hash_top(self.bucket(hash)))?;
+        while off != 0 {
+            let rec = self.rec_read(off)?;
+            if !rec.is_dead()
+                && rec.full_hash == hash
+                && rec.key_len as usize == key.len()
+                && self.read(off + RECORD_LEN, rec.key_len)? == key
+            {
+                return Ok(Some((off, rec)));
+            }
+            // Detect a chain pointing at itself, rather than looping forever.
+            if off == rec.next {
+                return Err(Error::Corrupt);
+            }
+            off = rec.next;
+        }
+        Ok(None)
+    }
+
+    fn fetch_locked(&mut self, key: &[u8], hash: u32) -> Result<Option<Vec<u8>>, Error> {
+        match self.find(key, hash)? {
+            Some((off, rec)) => Ok(Some(
+                self.read(off + RECORD_LEN + rec.key_len, rec.data_len)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub(super) fn fetch(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let hash = self.hash.hash(key);
+        let bucket = self.bucket(hash) as i32;
+        self.with_lock(bucket, F_RDLCK, |ctx| ctx.fetch_locked(key, hash))
+    }
+
+    pub(super) fn exists(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let hash = self.hash.hash(key);
+        let bucket = self.bucket(hash) as i32;
+        self.with_lock(bucket, F_RDLCK, |ctx| Ok(ctx.find(key, hash)?.is_some()))
+    }
+
+    /// Overwrite the data of an existing record in place, if it has room.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - The record was updated.
+    /// * `Ok(false)` - The record is too small for the new data.
+    /// * `Err(Error::NoExist)` - There is no record for the key.
+    fn update_hash(&mut self, key: &[u8], hash: u32, val: &[u8]) -> Result<bool, Error> {
+        let (off, mut rec) = self.find(key, hash)?.ok_or(Error::NoExist)?;
+        // Leave room for the tailer.
+        if (rec.rec_len as usize) < key.len() + val.len() + 4 {
+            return Ok(false);
+        }
+        self.write(off + RECORD_LEN + rec.key_len, val)?;
+        if rec.data_len != val.len() as u32 {
+            rec.data_len = val.len() as u32;
+            self.rec_write(off, &rec)?;
+        }
+        Ok(true)
+    }
+
+    pub(super) fn store(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+    ) -> Result<(), Error> {
+        if self.read_only || self.traverse_read > 0 {
+            return Err(Error::ReadOnly);
+        }
+        let hash = self.hash.hash(key);
+        let bucket = self.bucket(hash) as i32;
+        self.with_lock(bucket, F_WRLCK, |ctx| {
+            ctx.store_locked(key, val, flags, hash)
+        })
+    }
+
+    fn store_locked(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        flags: Option<StoreFlags>,
+        hash: u32,
+    ) -> Result<(), Error> {
+        if matches!(flags, Some(StoreFlags::Insert)) {
+            if self.find(key, hash)?.is_some() {
+                return Err(Error::Exists);
+            }
+        } else {
+            match self.update_hash(key, hash, val) {
+                Ok(true) => {
+                    self.increment_seqnum();
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(Error::NoExist) if matches!(flags, Some(StoreFlags::Modify)) => {
+                    return Err(Error::NoExist)
+                }
+                Err(Error::NoExist) => {}
+                Err(e) => return Err(e),
+            }
+            // The existing record is too small, so replace it.
+            match self.delete_locked(key, hash) {
+                Ok(()) | Err(Error::NoExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len = u32::try_from(key.len() + val.len()).map_err(|_| Error::OOM)?;
+        let (off, mut rec) = self.allocate(hash, len)?;
+        let top = self.hash_top(self.bucket(hash));
+        rec.next = self.read_u32(top)?;
+        rec.key_len = key.len() as u32;
+        rec.data_len = val.len() as u32;
+        rec.full_hash = hash;
+        rec.magic = TDB_MAGIC;
+        self.rec_write(off, &rec)?;
+        self.write(off + RECORD_LEN, &[key, val].concat())?;
+        self.write_u32(top, off)?;
+        self.increment_seqnum();
+        Ok(())
+    }
+
+    pub(super) fn append(&mut self, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        if self.read_only || self.traverse_read > 0 {
+            return Err(Error::ReadOnly);
+        }
+        let hash = self.hash.hash(key);
+        let bucket = self.bucket(hash) as i32;
+        self.with_lock(bucket, F_WRLCK, |ctx| {
+            let mut new = ctx.fetch_locked(key, hash)?.unwrap_or_default();
+            new.extend_from_slice(val);
+            ctx.store_locked(key, &new, None, hash)
+        })
+    }
+
+    pub(super) fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.read_only || self.traverse_read > 0 {
+            return Err(Error::ReadOnly);
+        }
+        let hash = self.hash.hash(key);
+        let bucket = self.bucket(hash) as i32;
+        self.with_lock(bucket, F_WRLCK, |ctx| ctx.delete_locked(key, hash))
+    }
+
+    fn delete_locked(&mut self, key: &[u8], hash: u32) -> Result<(), Error> {
+        let (off, rec) = self.find(key, hash)?.ok_or(Error::NoExist)?;
+        if self.max_dead > 0 {
+            // Leave the record in the chain as dead, so it can be reused without touching the
+            // freelist. Give dead records back once there are too many of them.
+            if self.count_dead(hash)? >= self.max_dead {
+                let _ = self.purge_dead(hash);
+            }
+            self.write_u32(off + 20, TDB_DEAD_MAGIC)?;
+        } else {
+            self.do_delete(off, &rec)?;
+        }
+        self.increment_seqnum();
+        Ok(())
+    }
+
+    /// Unlink the record at `off` from its chain and free it, or just mark it dead if a
+    /// traversal is positioned on it.
+    pub(super) fn do_delete(&mut self, off: u32, rec: &Record) -> Result<(), Error> {
+        if (self.traverse_write > 0 && !rec.is_dead()) || self.write_lock_record(off).is_err() {
+            // The traversal that holds it will delete it once it moves on.
+            return self.write_u32(off + 20, TDB_DEAD_MAGIC);
+        }
+        self.write_unlock_record(off)?;
+
+        let mut last = self.hash_top(self.bucket(rec.full_hash));
+        let mut i = self.read_u32(last)?;
+        while i != off {
+            if i == 0 {
+                return Err(Error::Corrupt);
+            }
+            last = i;
+            i = self.read_u32(i)?;
+        }
+        self.write_u32(last, rec.next)?;
+        self.free(off, rec)
+    }
+
+    fn count_dead(&mut self, hash: u32) -> Result<u32, Error> {
+        let mut count = 0;
+        let mut off = self.read_u32(self.hash_top(self.bucket(hash)))?;
+        while off != 0 {
+            let rec = self.rec_read(off)?;
+            if rec.is_dead() {
+                count += 1;
+            }
+            off = rec.next;
+        }
+        Ok(count)
+    }
+
+    /// Free all dead records in the (locked) hash chain of `hash`.
+    pub(super) fn purge_dead(&mut self, hash: u32) -> Result<(), Error> {
+        self.lock_list(super::FREELIST, F_WRLCK, false)?;
+        let ret = self.purge_dead_locked(hash);
+        self.unlock_list(super::FREELIST, F_WRLCK)?;
+        ret
+    }
+
+    fn purge_dead_locked(&mut self, hash: u32) -> Result<(), Error> {
+        let mut off = self.read_u32(self.hash_top(self.bucket(hash)))?;
+        while off != 0 {
+            let rec = self.rec_read(off)?;
+            if rec.is_dead() {
+                self.do_delete(off, &rec)?;
+            }
+            off = rec.next;
+        }
+        Ok(())
+    }
+
+    pub(super) fn get_seqnum(&mut self) -> u32 {
+        self.read_u32(SEQUENCE_NUMBER_OFS).unwrap_or(0)
+    }
+
+    /// Increment the sequence number, ignoring errors as libtdb does.
+    pub(super) fn increment_seqnum_nonblock(&mut self) {
+        let seqnum = self.get_seqnum().wrapping_add(1);
+        let _ = self.write_u32(SEQUENCE_NUMBER_OFS, seqnum);
+    }
+
+    fn increment_seqnum(&mut self) {
+        if self.flags & TDB_SEQNUM != 0 {
+            self.increment_seqnum_nonblock();
+        }
+    }
+
+    /// Add the region of `len` bytes at `off` to the freelist as a single record.
+    fn free_region(&mut self, off: u32, len: u32) -> Result<(), Error> {
+        if len <= RECORD_LEN {
+            return Ok(());
+        }
+        if off.checked_add(len).is_none_or(|end| end > self.map_size()) {
+            return Err(Error::Corrupt);
+        }
+        let rec = Record {
+            rec_len: len - RECORD_LEN,
+            ..Default::default()
+        };
+        self.free(off, &rec)
+    }
+
+    pub(super) fn wipe_all(&mut self) -> Result<(), Error> {
+        self.allrecord_lock(F_WRLCK, true, false)?;
+        let ret = self.wipe_all_locked();
+        self.allrecord_unlock(F_WRLCK)?;
+        ret
+    }
+
+    fn wipe_all_locked(&mut self) -> Result<(), Error> {
+        // Keep the recovery area where it is, otherwise each wipe inside a transaction would
+        // grow the file by its size.
+        let recovery_head = self.read_u32(RECOVERY_START_OFS)?;
+        let recovery_size = if recovery_head != 0 {
+            self.read_record(recovery_head)?.rec_len + RECORD_LEN
+        } else {
+            0
+        };
+
+        for bucket in 0..self.hash_size {
+            self.write_u32(self.hash_top(bucket), 0)?;
+        }
+        self.write_u32(FREELIST_TOP, 0)?;
+
+        let data_start = self.data_start();
+        let map_size = self.map_size();
+        if recovery_size == 0 {
+            self.free_region(data_start, map_size - data_start)?;
+        } else {
+            self.free_region(data_start, recovery_head - data_start)?;
+            let end = recovery_head + recovery_size;
+            self.free_region(end, map_size.saturating_sub(end))?;
+        }
+        self.increment_seqnum_nonblock();
+        Ok(())
+    }
+
+    /// Collect every live record, in chain order.
+    fn all_records(&mut self) -> Result<Vec<Entry>, Error> {
+        let mut records = Vec::new();
+        for bucket in 0..self.hash_size {
+            let mut off = self.read_u32(self.hash_top(bucket))?;
+            while off != 0 {
+                let rec = self.rec_read(off)?;
+                if !rec.is_dead() {
+                    let mut key = self.read(off + RECORD_LEN, rec.key_len + rec.data_len)?;
+                    let data = key.split_off(rec.key_len as usize);
+                    records.push((key, data));
+                }
+                if off == rec.next {
+                    return Err(Error::Corrupt);
+                }
+                off = rec.next;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Rewrite the database without free space or dead records. Must be called within a
+    /// transaction.
+    pub(super) fn repack_locked(&mut self) -> Result<(), Error> {
+        let records = self.all_records()?;
+        self.wipe_all()?;
+        // Records are added at the head of their chain, so add them in reverse to keep the
+        // order they are traversed in.
+        for (key, data) in records.iter().rev() {
+            self.store(key, data, Some(StoreFlags::Insert))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+//! Transactions and the recovery area, mirroring libtdb's `transaction.c`.
+//!
+//! While a transaction is open, writes go to an overlay of page-sized blocks rather than to the
+//! file. Committing first copies the original contents of every block that is about to be
+//! overwritten into the recovery area and syncs it, so that a crash half-way through writing
+//! the blocks out can be undone by the next process that opens or locks the database.
+
+use super::io::{decode_u32, encode_u32, expand_adjust, Record};
+use super::lock::OPEN_LOCK;
+use super::Context;
+use crate::generated::{TDB_ALLOW_NESTING, TDB_INTERNAL, TDB_NOSYNC};
+use crate::reader::{RECORD_LEN, RECOVERY_START_OFS, TDB_RECOVERY_MAGIC};
+use crate::Error;
+use libc::{F_RDLCK, F_WRLCK};
+
+/// The magic of a recovery record that must not be replayed.
+const TDB_RECOVERY_INVALID_MAGIC: u32 = 0;
+
+/// The state of an open transaction.
+pub(super) struct Transaction {
+    /// Modified blocks of the file, indexed by offset / `block_size`.
+    blocks: Vec<Option<Vec<u8>>>,
+    block_size: u32,
+    /// How much of the last block has been written to.
+    last_block_size: u32,
+    /// The size of the database as seen inside the transaction.
+    pub map_size: u32,
+    /// The size of the file when the transaction started.
+    old_map_size: u32,
+    nesting: u32,
+    prepared: bool,
+    /// Set when a write failed or a nested transaction was cancelled; the transaction can then
+    /// only be cancelled.
+    error: bool,
+    /// Offset of the magic of the recovery record, once it has been written.
+    magic_offset: u32,
+}
+
+impl Context {
+    pub(super) fn transaction_read(&mut self, off: u32, len: u32) -> Result<Vec<u8>, Error> {
+        let tr = self.transaction.as_ref().unwrap();
+        let end = match off.checked_add(len) {
+            Some(end) if end <= tr.map_size => end,
+            _ => return Err(Error::IO),
+        };
+        let mut buf = Vec::with_capacity(len as usize);
+        let mut pos = off;
+        while pos < end {
+            let blk = pos / tr.block_size;
+            let start = pos % tr.block_size;
+            let n = (tr.block_size - start).min(end - pos);
+            match tr.blocks.get(blk as usize).and_then(Option::as_ref) {
+                Some(block) => buf.extend_from_slice(&block[start as usize..(start + n) as usize]),
+                None => buf.extend(self.io.read(pos, n)?),
+            }
+            pos += n;
+        }
+        Ok(buf)
+    }
+
+    /// Write `len` bytes of `data` at `off` into the transaction, or zeroes if `data` is `None`.
+    pub(super) fn transaction_write(
+        &mut self,
+        off: u32,
+        data: Option<&[u8]>,
+        len: u32,
+    ) -> Result<(), Error> {
+        let ret = self.transaction_write_blocks(off, data, len);
+        if ret.is_err() {
+            self.transaction.as_mut().unwrap().error = true;
+        }
+        ret
+    }
+
+    fn transaction_write_blocks(
+        &mut self,
+        off: u32,
+        data: Option<&[u8]>,
+        len: u32,
+    ) -> Result<(), Error> {
+        let tr = self.transaction.as_mut().unwrap();
+        if tr.prepared {
+            // Everything written now would be missing from the recovery area.
+            return Err(Error::Invalid);
+        }
+        let end = match off.checked_add(len) {
+            Some(end) if end <= tr.map_size => end,
+            _ => return Err(Error::IO),
+        };
+        let block_size = tr.block_size;
+        let mut pos = off;
+        while pos < end {
+            let blk = pos / block_size;
+            let start = pos % block_size;
+            let n = (block_size - start).min(end - pos);
+            let tr = self.transaction.as_mut().unwrap();
+            if blk as usize >= tr.blocks.len() {
+                tr.blocks.resize(blk as usize + 1, None);
+                tr.last_block_size = 0;
+            }
+            if tr.blocks[blk as usize].is_none() {
+                // Start from the current contents of the file, zero-filled past its end.
+                let mut block = vec![0; block_size as usize];
+                let block_off = blk * block_size;
+                if block_off < tr.old_map_size {
+                    let avail = (tr.old_map_size - block_off).min(block_size);
+                    self.io.read_into(block_off, &mut block[..avail as usize])?;
+                }
+                self.transaction.as_mut().unwrap().blocks[blk as usize] = Some(block);
+            }
+            let tr = self.transaction.as_mut().unwrap();
+            let block = tr.blocks[blk as usize].as_mut().unwrap();
+            let dest = &mut block[start as usize..(start + n) as usize];
+            match data {
+                Some(data) => {
+                    let from = (pos - off) as usize;
+                    dest.copy_from_slice(&data[from..from + n as usize]);
+                }
+                None => dest.fill(0),
+            }
+            if blk as usize == tr.blocks.len() - 1 {
+                tr.last_block_size = tr.last_block_size.max(start + n);
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Update blocks that are already part of the transaction, without adding new ones.
+    ///
+    /// Used to keep the blocks in step with data written directly to the file while
+    /// committing, so writing the blocks out doesn't overwrite it.
+    fn transaction_write_existing(&mut self, off: u32, data: &[u8]) -> Result<(), Error> {
+        let tr = self.transaction.as_mut().unwrap();
+        let end = off + data.len() as u32;
+        let mut pos = off;
+        while pos < end {
+            let blk = pos / tr.block_size;
+            let start = pos % tr.block_size;
+            let n = (tr.block_size - start).min(end - pos);
+            if let Some(Some(block)) = tr.blocks.get_mut(blk as usize) {
+                let from = (pos - off) as usize;
+                block[start as usize..(start + n) as usize]
+                    .copy_from_slice(&data[from..from + n as usize]);
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Grow the database inside the transaction; the file itself is expanded on commit.
+    pub(super) fn transaction_expand_file(
+        &mut self,
+        size: u32,
+        addition: u32,
+    ) -> Result<(), Error> {
+        let new_size = size.checked_add(addition).ok_or(Error::IO)?;
+        self.transaction.as_mut().unwrap().map_size = new_size;
+        self.transaction_write(size, None, addition)
+    }
+
+    /// Sync the file to disk, unless the database was opened with `NoSync`.
+    fn transaction_sync(&mut self) -> Result<(), Error> {
+        if self.flags & TDB_NOSYNC != 0 {
+            return Ok(());
+        }
+        self.io.sync()
+    }
+
+    pub(super) fn transaction_start(&mut self, wait: bool) -> Result<(), Error> {
+        if self.read_only || self.flags & TDB_INTERNAL != 0 || self.traverse_read > 0 {
+            return Err(Error::Invalid);
+        }
+        if let Some(tr) = &mut self.transaction {
+            if self.flags & TDB_ALLOW_NESTING == 0 {
+                return Err(Error::Nesting);
+            }
+            tr.nesting += 1;
+            return Ok(());
+        }
+        if self.have_extra_locks() || self.traverse_write > 0 {
+            // A transaction can't be started while holding chain locks, as the all-record lock
+            // it takes can't be combined with them.
+            return Err(Error::Lock);
+        }
+
+        self.io.refresh()?;
+        // The transaction has to exist before locking, so the locks are attributed to it.
+        self.transaction = Some(Box::new(Transaction {
+            blocks: Vec::new(),
+            block_size: self.page_size,
+            last_block_size: 0,
+            map_size: self.io.size,
+            old_map_size: self.io.size,
+            nesting: 0,
+            prepared: false,
+            error: false,
+            magic_offset: 0,
+        }));
+
+        // Only one transaction at a time; this also keeps write traversals out.
+        if let Err(e) = self.transaction_lock(F_WRLCK, wait) {
+            self.transaction = None;
+            return Err(e);
+        }
+        // Keep other processes from writing while we build up the transaction, and upgrade
+        // to a write lock when committing.
+        if let Err(e) = self.allrecord_lock(F_RDLCK, wait, true) {
+            let _ = self.transaction_unlock(F_WRLCK);
+            self.transaction = None;
+            return Err(e);
+        }
+
+        // Pick up expansions made by other processes before we locked.
+        if let Err(e) = self.io.refresh() {
+            let _ = self.transaction_cancel();
+            return Err(e);
+        }
+        let size = self.io.size;
+        let tr = self.transaction.as_mut().unwrap();
+        tr.map_size = size;
+        tr.old_map_size = size;
+        Ok(())
+    }
+
+    pub(super) fn transaction_cancel(&mut self) -> Result<(), Error> {
+        let tr = self.transaction.as_mut().ok_or(Error::Invalid)?;
+        if tr.nesting > 0 {
+            // The outer transaction can no longer commit.
+            tr.error = true;
+            tr.nesting -= 1;
+            return Ok(());
+        }
+        let tr = self.transaction.take().unwrap();
+
+        let mut ret = Ok(());
+        if tr.magic_offset != 0 {
+            // Invalidate the recovery area; the file was never touched, or has been fully
+            // written, so there is nothing to undo.
+            let invalid = encode_u32(TDB_RECOVERY_INVALID_MAGIC, self.big_endian);
+            ret = self
+                .io
+                .write(tr.magic_offset, &invalid)
+                .and_then(|_| self.transaction_sync());
+        }
+        // This also releases the open lock, if we took it to commit.
+        self.release_transaction_locks();
+        ret
+    }
+
+    /// Return the size of the recovery data needed to undo the transaction.
+    fn recovery_size(&self) -> u32 {
+        let tr = self.transaction.as_ref().unwrap();
+        // The tailer.
+        let mut size = 4;
+        for (i, block) in tr.blocks.iter().enumerate() {
+            if i as u32 * tr.block_size >= tr.old_map_size {
+                break;
+            }
+            if block.is_none() {
+                continue;
+            }
+            size += 8;
+            size += if i == tr.blocks.len() - 1 {
+                tr.last_block_size
+            } else {
+                tr.block_size
+            };
+        }
+        size
+    }
+
+    /// Find or make room for the recovery area.
+    ///
+    /// # Returns
+    ///
+    /// The offset of the recovery record, the size of its data, and its total capacity.
+    fn recovery_allocate(&mut self) -> Result<(u32, u32, u32), Error> {
+        let mut size = self.recovery_size();
+        let head = self.read_u32(RECOVERY_START_OFS)?;
+        if head != 0 {
+            // The recovery area is outside the transaction, so read it from the file.
+            let rec = Record::decode(&self.io.read(head, RECORD_LEN)?, self.big_endian);
+            if rec.rec_len >= size {
+                return Ok((head, size, rec.rec_len));
+            }
+            // Too small; give it back, and put a bigger one at the end of the file. This is
+            // done inside the transaction, so it is undone if we crash.
+            self.free(head, &rec)?;
+            // Freeing it touched more blocks.
+            size = self.recovery_size();
+        }
+
+        let map_size = self.map_size();
+        let max = expand_adjust(map_size, size, self.page_size)? - RECORD_LEN;
+        let head = map_size;
+
+        // Expand the file itself, both for the expansions made by the transaction and for the
+        // recovery area, so that committing doesn't expand it again over the recovery area.
+        let tr = self.transaction.as_ref().unwrap();
+        let old_map_size = tr.old_map_size;
+        let addition = (map_size - old_map_size) + RECORD_LEN + max;
+        self.io.expand_file(old_map_size, addition)?;
+        let tr = self.transaction.as_mut().unwrap();
+        tr.map_size = self.io.size;
+        tr.old_map_size = self.io.size;
+
+        // Nothing points at the new area until its magic is written, so this can be written
+        // straight away.
+        let head_buf = encode_u32(head, self.big_endian);
+        self.io.write(RECOVERY_START_OFS, &head_buf)?;
+        self.transaction_write_existing(RECOVERY_START_OFS, &head_buf)?;
+        Ok((head, size, max))
+    }
+
+    /// Write the original contents of every block the transaction modifies to the recovery
+    /// area, and mark it valid.
+    fn setup_recovery(&mut self) -> Result<(), Error> {
+        let old_map_size = self.transaction.as_ref().unwrap().old_map_size;
+        let (head, size, max) = self.recovery_allocate()?;
+
+        let mut data = Vec::with_capacity(size as usize);
+        let tr = self.transaction.as_ref().unwrap();
+        let (block_size, num_blocks, new_map_size) =
+            (tr.block_size, tr.blocks.len(), tr.old_map_size);
+        for i in 0..num_blocks {
+            let tr = self.transaction.as_ref().unwrap();
+            if tr.blocks[i].is_none() {
+                continue;
+            }
+            let offset = i as u32 * block_size;
+            if offset >= old_map_size {
+                continue;
+            }
+            let length = if i == num_blocks - 1 {
+                tr.last_block_size
+            } else {
+                block_size
+            };
+            if offset + length > new_map_size {
+                return Err(Error::Corrupt);
+            }
+            data.extend(encode_u32(offset, self.big_endian));
+            data.extend(encode_u32(length, self.big_endian));
+            // The old data is in the file; anything past its old end is new, so zero-fill it.
+            let avail = (old_map_size - offset).min(length);
+            data.extend(self.io.read(offset, avail)?);
+            data.resize(data.len() + (length - avail) as usize, 0);
+        }
+        data.extend(encode_u32(RECORD_LEN + max, self.big_endian));
+
+        let rec = Record {
+            next: 0,
+            rec_len: max,
+            key_len: old_map_size,
+            data_len: size,
+            full_hash: 0,
+            magic: TDB_RECOVERY_INVALID_MAGIC,
+        };
+        let mut buf = rec.encode(self.big_endian);
+        buf.extend(data);
+        self.io.write(head, &buf)?;
+        self.transaction_write_existing(head, &buf)?;
+        self.transaction_sync()?;
+
+        // Only now that the recovery data is safely on disk may it be replayed.
+        let magic_offset = head + 20;
+        let magic = encode_u32(TDB_RECOVERY_MAGIC, self.big_endian);
+        self.io.write(magic_offset, &magic)?;
+        self.transaction_write_existing(magic_offset, &magic)?;
+        self.transaction_sync()?;
+        self.transaction.as_mut().unwrap().magic_offset = magic_offset;
+        Ok(())
+    }
+
+    pub(super) fn transaction_prepare_commit(&mut self) -> Result<(), Error> {
+        let tr = self.transaction.as_mut().ok_or(Error::Invalid)?;
+        if tr.prepared {
+            tr.error = true;
+            let _ = self.transaction_cancel();
+            return Err(Error::Invalid);
+        }
+        if tr.error {
+            let _ = self.transaction_cancel();
+            return Err(Error::IO);
+        }
+        if tr.nesting != 0 || tr.blocks.is_empty() {
+            return Ok(());
+        }
+        if self.have_extra_locks() {
+            let _ = self.transaction_cancel();
+            return Err(Error::Lock);
+        }
+        let ret = self.prepare_commit_locked();
+        if ret.is_err() {
+            let _ = self.transaction_cancel();
+        }
+        ret
+    }
+
+    fn prepare_commit_locked(&mut self) -> Result<(), Error> {
+        // Keep readers out while the file is being modified.
+        self.allrecord_upgrade()?;
+        // Keep other processes from opening the database, since they would run recovery.
+        self.nest_lock(OPEN_LOCK, F_WRLCK, true)?;
+        if self.flags & TDB_NOSYNC == 0 {
+            self.setup_recovery()?;
+        }
+        let tr = self.transaction.as_mut().unwrap();
+        tr.prepared = true;
+        if tr.map_size != tr.old_map_size {
+            let (old_map_size, map_size) = (tr.old_map_size, tr.map_size);
+            self.io.expand_file(old_map_size, map_size - old_map_size)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn transaction_commit(&mut self) -> Result<(), Error> {
+        let tr = self.transaction.as_mut().ok_or(Error::Invalid)?;
+        if tr.error {
+            let _ = self.transaction_cancel();
+            return Err(Error::IO);
+        }
+        if tr.nesting != 0 {
+            tr.nesting -= 1;
+            return Ok(());
+        }
+        if tr.blocks.is_empty() {
+            return self.transaction_cancel();
+        }
+        if !tr.prepared {
+            self.transaction_prepare_commit()?;
+        }
+
+        let tr = self.transaction.as_mut().unwrap();
+        let blocks = std::mem::take(&mut tr.blocks);
+        let (block_size, last_block_size) = (tr.block_size, tr.last_block_size);
+        for (i, block) in blocks.iter().enumerate() {
+            let Some(block) = block else {
+                continue;
+            };
+            let length = if i == blocks.len() - 1 {
+                last_block_size
+            } else {
+                block_size
+            };
+            if let Err(e) = self
+                .io
+                .write(i as u32 * block_size, &block[..length as usize])
+            {
+                // Part of the file has been overwritten; put it back as it was.
+                let _ = self.transaction_recover();
+                let _ = self.transaction_cancel();
+                return Err(e);
+            }
+        }
+        self.transaction_sync()?;
+
+        // Update the modification time, so tools such as rsync notice the change even if the
+        // size of the file didn't.
+        if self.io.file_handle().is_some() {
+            unsafe { libc::futimens(self.io.fd(), std::ptr::null()) };
+        }
+        self.transaction_cancel()
+    }
+
+    /// Replay the recovery area left behind by a commit that didn't complete.
+    pub(super) fn transaction_recover(&mut self) -> Result<(), Error> {
+        // This runs outside of any transaction, so always use the file.
+        let head = decode_u32(&self.io.read(RECOVERY_START_OFS, 4)?, self.big_endian);
+        if head == 0 {
+            return Ok(());
+        }
+        let rec = Record::decode(&self.io.read(head, RECORD_LEN)?, self.big_endian);
+        if rec.magic != TDB_RECOVERY_MAGIC {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(Error::Corrupt);
+        }
+
+        let recovery_eof = rec.key_len;
+        let data = self.io.read(head + RECORD_LEN, rec.data_len)?;
+        let mut p = 0;
+        while p + 8 < data.len() {
+            let ofs = decode_u32(&data[p..p + 4], self.big_endian);
+            let len = decode_u32(&data[p + 4..p + 8], self.big_endian) as usize;
+            p += 8;
+            let old = data.get(p..p + len).ok_or(Error::Corrupt)?;
+            self.io.write(ofs, old)?;
+            p += len;
+        }
+        self.transaction_sync()?;
+
+        // If the recovery area was added by the transaction, it is about to be truncated away.
+        if recovery_eof <= head {
+            self.io
+                .write(RECOVERY_START_OFS, &encode_u32(0, self.big_endian))?;
+        }
+        self.io.write(
+            head + 20,
+            &encode_u32(TDB_RECOVERY_INVALID_MAGIC, self.big_endian),
+        )?;
+        self.transaction_sync()?;
+        self.io.truncate(recovery_eof)?;
+        self.transaction_sync()
+    }
+}
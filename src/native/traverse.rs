@@ -0,0 +1,232 @@
+//! Walking all records, mirroring libtdb's `traverse.c`.
+
+use super::io::Record;
+use super::{Context, Entry};
+use crate::reader::RECORD_LEN;
+use crate::Error;
+use libc::{F_RDLCK, F_WRLCK};
+
+/// The position of a traversal or key iterator.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct TravLock {
+    pub id: u64,
+    /// The hash chain being walked.
+    pub list: u32,
+    /// The record the traversal is positioned on, which is kept read-locked, or 0.
+    pub off: u32,
+    pub ltype: i32,
+}
+
+impl Context {
+    pub(super) fn push_travlock(&mut self, ltype: i32) -> u64 {
+        self.next_travlock_id += 1;
+        self.travlocks.push(TravLock {
+            id: self.next_travlock_id,
+            list: 0,
+            off: 0,
+            ltype,
+        });
+        self.next_travlock_id
+    }
+
+    /// Release the record lock of a traversal and forget about it.
+    pub(super) fn pop_travlock(&mut self, id: u64) -> Result<(), Error> {
+        let tl = self.travlock(id);
+        let ret = self.unlock_record(tl.off);
+        self.travlocks.retain(|tl| tl.id != id);
+        ret
+    }
+
+    fn travlock(&self, id: u64) -> TravLock {
+        *self.travlocks.iter().find(|tl| tl.id == id).unwrap()
+    }
+
+    fn travlock_mut(&mut self, id: u64) -> &mut TravLock {
+        self.travlocks.iter_mut().find(|tl| tl.id == id).unwrap()
+    }
+
+    /// Move a traversal to the next live record.
+    ///
+    /// On success the chain of the record is left locked and the record itself read-locked.
+    /// Returns `None` once all chains have been walked.
+    fn next_lock(&mut self, id: u64) -> Result<Option<Record>, Error> {
+        let tl = self.travlock(id);
+        let mut want_next = tl.off != 0;
+        let (mut list, ltype) = (tl.list, tl.ltype);
+        while list < self.hash_size {
+            if !want_next && list != 0 {
+                // Skip empty chains without locking them; most chains of a large hash table
+                // are empty.
+                while list < self.hash_size
+                    && self.read_u32(self.hash_top(list)).is_ok_and(|top| top == 0)
+                {
+                    list += 1;
+                }
+                if list == self.hash_size {
+                    break;
+                }
+            }
+            self.travlock_mut(id).list = list;
+            self.lock_list(list as i32, ltype, true)?;
+            match self.next_in_chain(id, want_next) {
+                Ok(Some(rec)) => return Ok(Some(rec)),
+                Ok(None) => {}
+                Err(e) => {
+                    self.travlock_mut(id).off = 0;
+                    let _ = self.unlock_list(list as i32, ltype);
+                    return Err(e);
+                }
+            }
+            self.unlock_list(list as i32, ltype)?;
+            want_next = false;
+            list += 1;
+        }
+        self.travlock_mut(id).list = list;
+        Ok(None)
+    }
+
+    /// Find the next live record in the (locked) chain of a traversal.
+    fn next_in_chain(&mut self, id: u64, want_next: bool) -> Result<Option<Record>, Error> {
+        let tl = self.travlock(id);
+        let mut off = if tl.off == 0 {
+            self.read_u32(self.hash_top(tl.list))?
+        } else {
+            self.unlock_record(tl.off)?;
+            tl.off
+        };
+        if want_next {
+            off = self.rec_read(off)?.next;
+        }
+        self.travlock_mut(id).off = off;
+        while off != 0 {
+            let rec = self.rec_read(off)?;
+            if off == rec.next {
+                return Err(Error::Corrupt);
+            }
+            if !rec.is_dead() {
+                self.lock_record(off)?;
+                return Ok(Some(rec));
+            }
+            // Clean up records left dead by earlier traversals.
+            self.travlock_mut(id).off = rec.next;
+            if !(self.read_only || self.traverse_read > 0) {
+                self.do_delete(off, &rec)?;
+            }
+            off = rec.next;
+        }
+        Ok(None)
+    }
+
+    /// Start a traversal, returning its id and the lock type it holds.
+    ///
+    /// A write traversal of a read-only database, or inside a read traversal, becomes a read
+    /// traversal.
+    pub(super) fn start_traverse(&mut self, write: bool) -> Result<(u64, i32), Error> {
+        let write = write && !(self.read_only || self.traverse_read > 0);
+        let ltype = if write { F_WRLCK } else { F_RDLCK };
+        // Don't wait for the transaction lock while holding the all-record lock, or we could
+        // deadlock against a process that holds the former and wants the latter.
+        let wait = !write || self.allrecord.count == 0;
+        self.transaction_lock(ltype, wait)?;
+        if write {
+            self.traverse_write += 1;
+        } else {
+            self.traverse_read += 1;
+        }
+        Ok((self.push_travlock(ltype), ltype))
+    }
+
+    /// Move a traversal to the next record and read it, leaving the record read-locked.
+    pub(super) fn traverse_next(&mut self, id: u64) -> Result<Option<Entry>, Error> {
+        let Some(rec) = self.next_lock(id)? else {
+            return Ok(None);
+        };
+        let tl = self.travlock(id);
+        let read = self.read(tl.off + RECORD_LEN, rec.key_len + rec.data_len);
+        // Drop the chain lock while the caller looks at the record.
+        let unlocked = self.unlock_list(tl.list as i32, tl.ltype);
+        let mut key = read?;
+        unlocked?;
+        let data = key.split_off(rec.key_len as usize);
+        Ok(Some((key, data)))
+    }
+
+    pub(super) fn end_traverse(&mut self, id: u64, ltype: i32) -> Result<(), Error> {
+        let ret = self.pop_travlock(id);
+        if ltype == F_WRLCK {
+            self.traverse_write -= 1;
+        } else {
+            self.traverse_read -= 1;
+        }
+        self.transaction_unlock(ltype)?;
+        ret
+    }
+
+    /// Return the first key, positioning key iterator `id` on it.
+    pub(super) fn firstkey(&mut self, id: u64) -> Result<Option<Vec<u8>>, Error> {
+        let tl = self.travlock(id);
+        self.unlock_record(tl.off)?;
+        let tl = self.travlock_mut(id);
+        tl.off = 0;
+        tl.list = 0;
+        self.read_current_key(id)
+    }
+
+    /// Read the key of the record found by `next_lock`, and unlock its chain.
+    fn read_current_key(&mut self, id: u64) -> Result<Option<Vec<u8>>, Error> {
+        let Some(rec) = self.next_lock(id)? else {
+            return Ok(None);
+        };
+        let tl = self.travlock(id);
+        let key = self.read(tl.off + RECORD_LEN, rec.key_len);
+        self.unlock_list(tl.list as i32, tl.ltype)?;
+        key.map(Some)
+    }
+
+    /// Return the key after `oldkey`, positioning key iterator `id` on it.
+    pub(super) fn nextkey(&mut self, id: u64, oldkey: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let tl = self.travlock(id);
+        if tl.off != 0 {
+            // Check that the iterator is still positioned on the old key; if so the walk is
+            // reliable, otherwise start again from wherever the old key is now.
+            self.lock_list(tl.list as i32, tl.ltype, true)?;
+            let same = self
+                .rec_read(tl.off)
+                .and_then(|rec| self.read(tl.off + RECORD_LEN, rec.key_len))
+                .is_ok_and(|key| key == oldkey);
+            if !same {
+                self.unlock_record(tl.off)?;
+                self.unlock_list(tl.list as i32, tl.ltype)?;
+                self.travlock_mut(id).off = 0;
+            }
+        }
+        if self.travlock(id).off == 0 {
+            let hash = self.hash.hash(oldkey);
+            let list = self.bucket(hash);
+            self.lock_list(list as i32, tl.ltype, true)?;
+            let found = match self.find(oldkey, hash) {
+                Ok(Some((off, _))) => self.lock_record(off).map(|_| Some(off)),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            let off = match found {
+                Ok(Some(off)) => off,
+                Ok(None) => {
+                    self.unlock_list(list as i32, tl.ltype)?;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    let _ = self.unlock_list(list as i32, tl.ltype);
+                    return Err(e);
+                }
+            };
+            let tl = self.travlock_mut(id);
+            tl.off = off;
+            tl.list = list;
+        }
+        let oldlist = self.travlock(id).list;
+        let key = self.read_current_key(id);
+        self.unlock_list(oldlist as i32, tl.ltype)?;
+        key
+    }
+}
@@ -0,0 +1,361 @@
+//! Traversing a database from several threads at once.
+//!
+//! A handle can't be shared between threads, and libtdb refuses to open the same file twice in
+//! one process. Instead, the handle locks the whole database for reading while worker threads
+//! walk ranges of hash chains through a [`TdbReader`] mapping of the file. Writers in other
+//! processes wait until the traversal is done, as they would for [`Tdb::lockall_read`].
+//!
+//! ```rust
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use trivialdb::{Flags, Tdb, O_CREAT, O_RDWR};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("test.tdb");
+//! let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+//! for i in 0..1000u32 {
+//!     tdb.store(&i.to_le_bytes(), &[1; 10], None).unwrap();
+//! }
+//!
+//! let total = AtomicU64::new(0);
+//! let count = tdb
+//!     .par_traverse(4, |_key, value| {
+//!         total.fetch_add(value.len() as u64, Ordering::Relaxed);
+//!         true
+//!     })
+//!     .unwrap();
+//! assert_eq!(count, 1000);
+//! assert_eq!(total.into_inner(), 10000);
+//! ```
+
+use crate::reader::{Record, TdbReader};
+use crate::{Error, Flags, Tdb};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A key and value, or the error reading them.
+type Item<'a> = Result<(&'a [u8], &'a [u8]), Error>;
+
+/// The key and value of `record`, unless it is dead.
+fn live(record: Result<Record<'_>, Error>) -> Option<Item<'_>> {
+    match record {
+        Ok(record) if record.dead => None,
+        Ok(record) => Some(Ok((record.key, record.data))),
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Split the hash chains `0..hash_size` into at most `partitions` ranges of about the same
+/// size.
+fn chain_ranges(hash_size: u32, partitions: usize) -> Vec<Range<u32>> {
+    let partitions = partitions.clamp(1, hash_size.max(1) as usize) as u32;
+    let (len, extra) = (hash_size / partitions, hash_size % partitions);
+    let mut start = 0;
+    (0..partitions)
+        .map(|i| {
+            let end = start + len + u32::from(i < extra);
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+/// A mapping of the file of a database that is locked for reading.
+struct Snapshot<'a> {
+    tdb: &'a Tdb,
+    reader: TdbReader,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Lock `tdb` for reading and map its file.
+    ///
+    /// Returns `Ok(None)` if the file doesn't hold the contents of the database, because it
+    /// is in memory or has an uncommitted transaction.
+    fn new(tdb: &'a Tdb) -> Result<Option<Self>, Error> {
+        if tdb.get_flags().contains(Flags::Internal) || tdb.transaction_active() {
+            return Ok(None);
+        }
+        tdb.lockall_read()?;
+        // Map the file through the handle's descriptor: opening and closing another one would
+        // release the lock.
+        match TdbReader::from_fd(tdb.as_raw_fd()) {
+            Ok(reader) => Ok(Some(Snapshot { tdb, reader })),
+            Err(e) => {
+                tdb.unlockall_read()?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        let _ = self.tdb.unlockall_read();
+    }
+}
+
+impl Tdb {
+    /// Traverse all records in the database from several threads, holding a read lock.
+    ///
+    /// The hash chains are split into `partitions` ranges, each of which is traversed by its
+    /// own thread. The callback is called with each key and value, in no particular order, and
+    /// should return `true` to continue or `false` to stop all threads early.
+    ///
+    /// Databases that are in memory or in the middle of a transaction are traversed on the
+    /// calling thread instead, as with [`Tdb::traverse_read`], since their file does not hold
+    /// their current contents.
+    ///
+    /// # Arguments
+    ///
+    /// * `partitions` - The number of threads to use. There are never more partitions than
+    ///   hash chains.
+    /// * `f` - The callback, called from all threads at once.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(count)` - The number of records visited.
+    /// * `Err(Error::Invalid)` - `partitions` is zero.
+    /// * `Err(Error::Corrupt)` - A hash chain is corrupt.
+    pub fn par_traverse<F: Fn(&[u8], &[u8]) -> bool + Sync>(
+        &self,
+        partitions: usize,
+        f: F,
+    ) -> Result<usize, Error> {
+        if partitions == 0 {
+            return Err(Error::Invalid);
+        }
+        let snapshot = match Snapshot::new(self)? {
+            Some(snapshot) => snapshot,
+            None => return self.traverse_read(f),
+        };
+        let reader = &snapshot.reader;
+        let stop = AtomicBool::new(false);
+        let (f, stop) = (&f, &stop);
+        std::thread::scope(|scope| {
+            let workers = chain_ranges(reader.hash_size(), partitions)
+                .into_iter()
+                .map(|chains| {
+                    scope.spawn(move || {
+                        let mut count = 0;
+                        for record in chains
+                            .flat_map(|chain| reader.chain(chain))
+                            .filter_map(live)
+                        {
+                            if stop.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            let (key, data) = record?;
+                            count += 1;
+                            if !f(key, data) {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                        Ok(count)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .try_fold(0, |total, worker| match worker.join() {
+                    Ok(count) => count.map(|count: usize| total + count),
+                    Err(panic) => std::panic::resume_unwind(panic),
+                })
+        })
+    }
+
+    /// Lock the database for reading, to iterate over its records with rayon.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(records)` - The records, until `records` is dropped.
+    /// * `Err(Error::Invalid)` - The database is in memory or in the middle of a transaction,
+    ///   so its file does not hold its current contents.
+    #[cfg(feature = "rayon")]
+    pub fn par_records(&self) -> Result<ParRecords<'_>, Error> {
+        Snapshot::new(self)?.map(ParRecords).ok_or(Error::Invalid)
+    }
+}
+
+/// The records of a database that is locked for reading, returned by [`Tdb::par_records`].
+///
+/// ```rust
+/// use rayon::prelude::*;
+/// use trivialdb::{Flags, Tdb, O_CREAT, O_RDWR};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let path = dir.path().join("test.tdb");
+/// let mut tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+/// for i in 0..1000u32 {
+///     tdb.store(&i.to_le_bytes(), &i.to_le_bytes(), None).unwrap();
+/// }
+///
+/// let records = tdb.par_records().unwrap();
+/// let sum: u64 = records
+///     .par_iter()
+///     .map(|record| {
+///         let (_key, value) = record.unwrap();
+///         u32::from_le_bytes(value.try_into().unwrap()) as u64
+///     })
+///     .sum();
+/// assert_eq!(sum, 999 * 1000 / 2);
+/// ```
+#[cfg(feature = "rayon")]
+pub struct ParRecords<'a>(Snapshot<'a>);
+
+#[cfg(feature = "rayon")]
+impl ParRecords<'_> {
+    /// Iterate over all live records in parallel, splitting the work by hash chain.
+    pub fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = Item<'_>> + '_ {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        let reader = &self.0.reader;
+        (0..reader.hash_size())
+            .into_par_iter()
+            .flat_map_iter(move |chain| reader.chain(chain).filter_map(live))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{O_CREAT, O_RDWR};
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    fn create(path: &std::path::Path) -> Tdb {
+        let mut tdb = Tdb::open(path, Some(31), Flags::empty(), O_RDWR | O_CREAT, 0o600).unwrap();
+        for i in 0..500u32 {
+            tdb.store(&i.to_le_bytes(), &i.to_be_bytes(), None).unwrap();
+        }
+        for i in (0..500u32).step_by(5) {
+            tdb.delete(&i.to_le_bytes()).unwrap();
+        }
+        tdb
+    }
+
+    #[test]
+    fn test_chain_ranges() {
+        assert_eq!(chain_ranges(10, 3), vec![0..4, 4..7, 7..10]);
+        assert_eq!(chain_ranges(3, 8), vec![0..1, 1..2, 2..3]);
+        assert_eq!(chain_ranges(7, 1), vec![0..7]);
+    }
+
+    #[test]
+    fn test_par_traverse() {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = create(&dir.path().join("test.tdb"));
+        for partitions in [1, 4, 100] {
+            let seen = Mutex::new(HashSet::new());
+            let count = tdb
+                .par_traverse(partitions, |key, value| {
+                    let mut rev = value.to_vec();
+                    rev.reverse();
+                    assert_eq!(key, rev);
+                    assert!(seen.lock().unwrap().insert(key.to_vec()));
+                    true
+                })
+                .unwrap();
+            assert_eq!(count, 400);
+            assert_eq!(seen.into_inner().unwrap(), tdb.keys().collect());
+        }
+        assert!(matches!(
+            tdb.par_traverse(0, |_, _| true),
+            Err(Error::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_par_traverse_locks() {
+        const CHILD_ENV: &str = "TRIVIALDB_PARALLEL_PATH";
+        if let Some(path) = std::env::var_os(CHILD_ENV) {
+            let tdb = Tdb::open(&path, None, Flags::empty(), O_RDWR, 0).unwrap();
+            assert!(tdb.lockall_nonblock().is_err());
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.tdb");
+        let tdb = create(&path);
+        // Writers in other processes have to wait while the workers run.
+        tdb.par_traverse(1, |_, _| {
+            let mut child = crate::test::spawn_test(
+                "parallel::test::test_par_traverse_locks",
+                CHILD_ENV,
+                &path,
+            );
+            assert!(child.wait().unwrap().success());
+            false
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_par_traverse_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = create(&dir.path().join("test.tdb"));
+        let calls = AtomicUsize::new(0);
+        let count = tdb
+            .par_traverse(4, |_, _| calls.fetch_add(1, Ordering::Relaxed) < 10)
+            .unwrap();
+        assert_eq!(count, calls.into_inner());
+        assert!(count < 400);
+    }
+
+    #[test]
+    fn test_par_traverse_memory() {
+        let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+        for i in 0..50u32 {
+            tdb.store(&i.to_le_bytes(), b"", None).unwrap();
+        }
+        let count = AtomicUsize::new(0);
+        assert_eq!(
+            tdb.par_traverse(4, |_, _| count.fetch_add(1, Ordering::Relaxed) < 100)
+                .unwrap(),
+            50
+        );
+        assert_eq!(count.into_inner(), 50);
+    }
+
+    #[test]
+    fn test_par_traverse_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tdb = create(&dir.path().join("test.tdb"));
+        tdb.transaction_start().unwrap();
+        tdb.store(b"uncommitted", b"", None).unwrap();
+        let seen = Mutex::new(Vec::new());
+        tdb.par_traverse(4, |key, _| {
+            seen.lock().unwrap().push(key.to_vec());
+            true
+        })
+        .unwrap();
+        assert!(seen
+            .into_inner()
+            .unwrap()
+            .contains(&b"uncommitted".to_vec()));
+        tdb.transaction_cancel().unwrap();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_records() {
+        use rayon::iter::ParallelIterator;
+
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = create(&dir.path().join("test.tdb"));
+        let keys = {
+            let records = tdb.par_records().unwrap();
+            records
+                .par_iter()
+                .map(|record| record.unwrap().0.to_vec())
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(keys, tdb.keys().collect());
+        // The read lock has been released.
+        tdb.lockall().unwrap();
+        tdb.unlockall().unwrap();
+
+        let memory = Tdb::memory(None, Flags::empty()).unwrap();
+        assert!(matches!(memory.par_records(), Err(Error::Invalid)));
+    }
+}
@@ -0,0 +1,806 @@
+//! A pure-Rust, read-only reader for the TDB file format.
+//!
+//! [`TdbReader`] parses TDB files directly, without going through libtdb. It maps the file
+//! into memory and walks the hash chains itself, so values are returned as slices borrowed
+//! from the mapping.
+//!
+//! The reader does not take any locks. If other processes write to the file while it is being
+//! read, the reader may observe a partially updated file - in that case it returns
+//! [`Error::Corrupt`] rather than misbehaving. Files that were left behind by an interrupted
+//! transaction commit need to be recovered by libtdb before they can be read.
+//!
+//! ```rust,no_run
+//! use trivialdb::reader::TdbReader;
+//!
+//! let reader = TdbReader::open("/var/lib/samba/private/secrets.tdb").unwrap();
+//! for item in reader.iter() {
+//!     let (key, value) = item.unwrap();
+//!     println!("{:?} => {} bytes", String::from_utf8_lossy(key), value.len());
+//! }
+//! ```
+
+use crate::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// The magic string at the start of every TDB file, including its terminating NUL.
+pub(crate) const MAGIC_FOOD: &[u8] = b"TDB file\n\0";
+/// Size of the file header.
+pub(crate) const HEADER_LEN: u32 = 168;
+/// Size of a record header.
+pub(crate) const RECORD_LEN: u32 = 24;
+/// Offset of the head of the freelist, directly followed by the hash table.
+pub(crate) const FREELIST_TOP: u32 = HEADER_LEN;
+
+pub(crate) const TDB_VERSION: u32 = 0x26011967 + 6;
+pub(crate) const TDB_MAGIC: u32 = 0x26011999;
+pub(crate) const TDB_FREE_MAGIC: u32 = !TDB_MAGIC;
+pub(crate) const TDB_DEAD_MAGIC: u32 = 0xFEE1DEAD;
+pub(crate) const TDB_RECOVERY_MAGIC: u32 = 0xf53bc0e7;
+pub(crate) const TDB_HASH_RWLOCK_MAGIC: u32 = 0xbad1a51;
+pub(crate) const TDB_FEATURE_FLAG_MAGIC: u32 = 0xbad1a52;
+
+/// Offsets of the header fields.
+pub(crate) const VERSION_OFS: u32 = 32;
+pub(crate) const HASH_SIZE_OFS: u32 = 36;
+pub(crate) const RWLOCKS_OFS: u32 = 40;
+pub(crate) const RECOVERY_START_OFS: u32 = 44;
+pub(crate) const SEQUENCE_NUMBER_OFS: u32 = 48;
+pub(crate) const MAGIC1_HASH_OFS: u32 = 52;
+pub(crate) const MAGIC2_HASH_OFS: u32 = 56;
+pub(crate) const FEATURE_FLAGS_OFS: u32 = 60;
+pub(crate) const MUTEX_SIZE_OFS: u32 = 64;
+
+/// The hash functions supported by libtdb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    /// The original TDB hash, used unless `Flags::IncompatibleHash` is set.
+    Old,
+    /// Bob Jenkins' lookup3 `hashlittle`, used with `Flags::IncompatibleHash`.
+    Jenkins,
+}
+
+impl HashFunction {
+    /// Hash a key.
+    pub fn hash(&self, key: &[u8]) -> u32 {
+        match self {
+            HashFunction::Old => old_hash(key),
+            HashFunction::Jenkins => jenkins_hash(key),
+        }
+    }
+
+    /// Return the values stored in the `magic1_hash` and `magic2_hash` header fields of a file
+    /// that uses this hash function.
+    pub(crate) fn magic_hashes(&self, big_endian: bool) -> (u32, u32) {
+        let magic = if big_endian {
+            TDB_MAGIC.to_be_bytes()
+        } else {
+            TDB_MAGIC.to_le_bytes()
+        };
+        match (self.hash(MAGIC_FOOD), self.hash(&magic)) {
+            // At least one of the hashes is always non-zero, so files with magic hashes can be
+            // told apart from files created before they were introduced.
+            (0, 0) => (1, 0),
+            hashes => hashes,
+        }
+    }
+}
+
+fn old_hash(key: &[u8]) -> u32 {
+    let mut value = 0x238F13AFu32.wrapping_mul(key.len() as u32);
+    for (i, &b) in key.iter().enumerate() {
+        value = value.wrapping_add((b as u32) << (i * 5 % 24));
+    }
+    1103515243u32.wrapping_mul(value).wrapping_add(12345)
+}
+
+fn jenkins_hash(key: &[u8]) -> u32 {
+    fn mix(a: &mut u32, b: &mut u32, c: &mut u32) {
+        *a = a.wrapping_sub(*c);
+        *a ^= c.rotate_left(4);
+        *c = c.wrapping_add(*b);
+        *b = b.wrapping_sub(*a);
+        *b ^= a.rotate_left(6);
+        *a = a.wrapping_add(*c);
+        *c = c.wrapping_sub(*b);
+        *c ^= b.rotate_left(8);
+        *b = b.wrapping_add(*a);
+        *a = a.wrapping_sub(*c);
+        *a ^= c.rotate_left(16);
+        *c = c.wrapping_add(*b);
+        *b = b.wrapping_sub(*a);
+        *b ^= a.rotate_left(19);
+        *a = a.wrapping_add(*c);
+        *c = c.wrapping_sub(*b);
+        *c ^= b.rotate_left(4);
+        *b = b.wrapping_add(*a);
+    }
+
+    fn word(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    let mut a = 0xdeadbeefu32.wrapping_add(key.len() as u32);
+    let (mut b, mut c) = (a, a);
+    if key.is_empty() {
+        return c;
+    }
+
+    let mut rest = key;
+    while rest.len() > 12 {
+        a = a.wrapping_add(word(&rest[0..4]));
+        b = b.wrapping_add(word(&rest[4..8]));
+        c = c.wrapping_add(word(&rest[8..12]));
+        mix(&mut a, &mut b, &mut c);
+        rest = &rest[12..];
+    }
+
+    // The last block is zero-padded to 12 bytes.
+    let mut tail = [0u8; 12];
+    tail[..rest.len()].copy_from_slice(rest);
+    a = a.wrapping_add(word(&tail[0..4]));
+    b = b.wrapping_add(word(&tail[4..8]));
+    c = c.wrapping_add(word(&tail[8..12]));
+
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(14));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(11));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(25));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(16));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(4));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(14));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(24));
+    c
+}
+
+/// The header of a TDB file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// The file format version.
+    pub version: u32,
+    /// Number of hash chains.
+    pub hash_size: u32,
+    /// Obsolete; used to detect old formats and the presence of feature flags.
+    pub rwlocks: u32,
+    /// Offset of the transaction recovery area, or zero if there is none.
+    pub recovery_start: u32,
+    /// Sequence number, maintained when the database is opened with `Flags::Seqnum`.
+    pub sequence_number: u32,
+    /// Hash of the magic string, used to identify the hash function.
+    pub magic1_hash: u32,
+    /// Hash of the record magic, used to identify the hash function.
+    pub magic2_hash: u32,
+    /// Feature flags, e.g. whether robust mutexes are in use.
+    pub feature_flags: u32,
+    /// Size of the mutex area, if mutexes are in use.
+    pub mutex_size: u32,
+    /// Whether the file was written on a machine with the other byte order.
+    pub big_endian: bool,
+}
+
+/// A record in a hash chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record<'a> {
+    /// Offset of the record header in the file.
+    pub offset: u32,
+    /// Offset of the next record in the same hash chain, or zero.
+    pub next: u32,
+    /// Length of the record, excluding its header but including padding.
+    pub rec_len: u32,
+    /// The full 32-bit hash of the key.
+    pub full_hash: u32,
+    /// Whether the record has been deleted but not yet removed from its hash chain.
+    pub dead: bool,
+    /// The key of the record.
+    pub key: &'a [u8],
+    /// The value of the record.
+    pub data: &'a [u8],
+}
+
+/// A block on the freelist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FreeBlock {
+    /// Offset of the block in the file.
+    pub offset: u32,
+    /// Length of the block, excluding its record header.
+    pub rec_len: u32,
+}
+
+/// A memory mapping of a file.
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is owned exclusively by this struct, and writes go through `&mut self`.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the first `len` bytes of `fd`, which must not be zero.
+    pub(crate) fn new(fd: RawFd, len: usize, writable: bool) -> Result<Mmap, Error> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::IO);
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// The mapping must have been created writable.
+    #[cfg(any(feature = "native", test))]
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+
+    /// Flush changes made through the mapping to disk.
+    #[cfg(any(feature = "native", test))]
+    pub(crate) fn sync(&self) -> Result<(), Error> {
+        if unsafe { libc::msync(self.ptr, self.len, libc::MS_SYNC) } != 0 {
+            return Err(Error::IO);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+enum Data {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Data {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Data::Mapped(map) => map.as_slice(),
+            Data::Owned(data) => data,
+        }
+    }
+}
+
+/// A read-only view of a TDB file that does not use libtdb.
+pub struct TdbReader {
+    data: Data,
+    header: Header,
+    hash: HashFunction,
+}
+
+/// Read a 32-bit integer at `ofs` in the given byte order.
+pub(crate) fn read_u32(data: &[u8], ofs: u32, big_endian: bool) -> Result<u32, Error> {
+    let bytes: [u8; 4] = data
+        .get(ofs as usize..ofs as usize + 4)
+        .ok_or(Error::Corrupt)?
+        .try_into()
+        .unwrap();
+    Ok(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// Add a length or offset read from the file to an offset, which fails rather than overflowing
+/// in a corrupt file.
+fn add_offset(ofs: u32, len: u32) -> Result<u32, Error> {
+    ofs.checked_add(len).ok_or(Error::Corrupt)
+}
+
+/// Parse the header of a TDB file.
+pub(crate) fn parse_header(data: &[u8]) -> Result<Header, Error> {
+    if data.len() < HEADER_LEN as usize || !data.starts_with(MAGIC_FOOD) {
+        return Err(Error::Corrupt);
+    }
+    let big_endian = match read_u32(data, VERSION_OFS, false)? {
+        TDB_VERSION => false,
+        v if v.swap_bytes() == TDB_VERSION => true,
+        _ => return Err(Error::Corrupt),
+    };
+    let field = |ofs| read_u32(data, ofs, big_endian);
+    let header = Header {
+        version: TDB_VERSION,
+        hash_size: field(HASH_SIZE_OFS)?,
+        rwlocks: field(RWLOCKS_OFS)?,
+        recovery_start: field(RECOVERY_START_OFS)?,
+        sequence_number: field(SEQUENCE_NUMBER_OFS)?,
+        magic1_hash: field(MAGIC1_HASH_OFS)?,
+        magic2_hash: field(MAGIC2_HASH_OFS)?,
+        feature_flags: field(FEATURE_FLAGS_OFS)?,
+        mutex_size: field(MUTEX_SIZE_OFS)?,
+        big_endian,
+    };
+    if header.hash_size == 0
+        || !matches!(
+            header.rwlocks,
+            0 | TDB_HASH_RWLOCK_MAGIC | TDB_FEATURE_FLAG_MAGIC
+        )
+    {
+        return Err(Error::Corrupt);
+    }
+    Ok(header)
+}
+
+/// Read the header of the file open as `fd`.
+///
+/// The header is read through `fd` itself: opening the file again and closing that descriptor
+/// would release every fcntl lock this process holds on the file.
+#[cfg(not(feature = "native"))]
+pub(crate) fn read_header(fd: RawFd) -> Result<Header, Error> {
+    let mut data = [0u8; HEADER_LEN as usize];
+    let n = unsafe { libc::pread(fd, data.as_mut_ptr() as *mut libc::c_void, data.len(), 0) };
+    if n != data.len() as isize {
+        return Err(Error::IO);
+    }
+    parse_header(&data)
+}
+
+/// Determine which hash function a file was created with.
+pub(crate) fn detect_hash(header: &Header) -> Result<HashFunction, Error> {
+    // Files created before the magic hashes were introduced always use the old hash.
+    if header.magic1_hash == 0 && header.magic2_hash == 0 {
+        return Ok(HashFunction::Old);
+    }
+    [HashFunction::Old, HashFunction::Jenkins]
+        .into_iter()
+        .find(|hash| {
+            hash.magic_hashes(header.big_endian) == (header.magic1_hash, header.magic2_hash)
+        })
+        // The file was created with a custom hash function.
+        .ok_or(Error::Invalid)
+}
+
+impl TdbReader {
+    /// Open a TDB file for reading.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(Error::IO)` - The file could not be opened or mapped.
+    /// * `Err(Error::Corrupt)` - The file is not a TDB file, or needs transaction recovery.
+    /// * `Err(Error::Invalid)` - The file uses a custom hash function.
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<TdbReader, Error> {
+        let file = std::fs::File::open(path).map_err(|_| Error::IO)?;
+        Self::from_fd(file.as_raw_fd())
+    }
+
+    /// Map the file open as `fd`, e.g. the descriptor of a [`crate::Tdb`].
+    ///
+    /// The reader doesn't take over `fd`, and the mapping stays valid after it is closed.
+    pub(crate) fn from_fd(fd: RawFd) -> Result<TdbReader, Error> {
+        let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+        if unsafe { libc::fstat(fd, st.as_mut_ptr()) } != 0 {
+            return Err(Error::IO);
+        }
+        let len = unsafe { st.assume_init() }.st_size as usize;
+        if len < HEADER_LEN as usize {
+            return Err(Error::Corrupt);
+        }
+        let map = Mmap::new(fd, len, false)?;
+        Self::new(Data::Mapped(map))
+    }
+
+    /// Parse a TDB file that has already been read into memory.
+    pub fn from_bytes(data: Vec<u8>) -> Result<TdbReader, Error> {
+        Self::new(Data::Owned(data))
+    }
+
+    fn new(data: Data) -> Result<TdbReader, Error> {
+        let header = parse_header(data.bytes())?;
+        let hash = detect_hash(&header)?;
+        let reader = TdbReader { data, header, hash };
+        if reader.header.recovery_start != 0
+            && reader.u32_at(add_offset(reader.header.recovery_start, 20)?)? == TDB_RECOVERY_MAGIC
+        {
+            // A transaction commit was interrupted; libtdb would roll it back on open.
+            return Err(Error::Corrupt);
+        }
+        Ok(reader)
+    }
+
+    fn u32_at(&self, ofs: u32) -> Result<u32, Error> {
+        read_u32(self.data.bytes(), ofs, self.header.big_endian)
+    }
+
+    fn bytes_at(&self, ofs: u32, len: u32) -> Result<&[u8], Error> {
+        let start = ofs as usize;
+        start
+            .checked_add(len as usize)
+            .and_then(|end| self.data.bytes().get(start..end))
+            .ok_or(Error::Corrupt)
+    }
+
+    /// Return the file header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Return the hash function used by the file.
+    pub fn hash_function(&self) -> HashFunction {
+        self.hash
+    }
+
+    /// Return the number of hash chains.
+    pub fn hash_size(&self) -> u32 {
+        self.header.hash_size
+    }
+
+    /// Return the size of the file.
+    pub fn map_size(&self) -> usize {
+        self.data.bytes().len()
+    }
+
+    /// Return the hash chain a key is stored in.
+    pub fn bucket(&self, key: &[u8]) -> u32 {
+        self.hash.hash(key) % self.header.hash_size
+    }
+
+    /// Iterate over all records in a hash chain, including dead ones.
+    pub fn chain(&self, bucket: u32) -> Chain<'_> {
+        let next = if bucket < self.header.hash_size {
+            (bucket + 1)
+                .checked_mul(4)
+                .ok_or(Error::Corrupt)
+                .and_then(|ofs| self.u32_at(add_offset(FREELIST_TOP, ofs)?))
+        } else {
+            Err(Error::Invalid)
+        };
+        Chain {
+            reader: self,
+            next: Some(next),
+            remaining: self.map_size() / RECORD_LEN as usize,
+        }
+    }
+
+    fn record(&self, offset: u32) -> Result<Record<'_>, Error> {
+        let field = |i: u32| self.u32_at(add_offset(offset, i * 4)?);
+        let (next, rec_len, key_len, data_len, full_hash, magic) = (
+            field(0)?,
+            field(1)?,
+            field(2)?,
+            field(3)?,
+            field(4)?,
+            field(5)?,
+        );
+        if (magic != TDB_MAGIC && magic != TDB_DEAD_MAGIC)
+            || key_len
+                .checked_add(data_len)
+                .is_none_or(|len| len > rec_len)
+        {
+            return Err(Error::Corrupt);
+        }
+        let key_ofs = add_offset(offset, RECORD_LEN)?;
+        let key = self.bytes_at(key_ofs, key_len)?;
+        let data = self.bytes_at(add_offset(key_ofs, key_len)?, data_len)?;
+        Ok(Record {
+            offset,
+            next,
+            rec_len,
+            full_hash,
+            dead: magic == TDB_DEAD_MAGIC,
+            key,
+            data,
+        })
+    }
+
+    /// Fetch a value from the database.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(value))` - The value associated with the key.
+    /// * `Ok(None)` - The key was not found.
+    /// * `Err(e)` - The file is corrupt.
+    pub fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        let hash = self.hash.hash(key);
+        for record in self.chain(hash % self.header.hash_size) {
+            let record = record?;
+            if !record.dead && record.full_hash == hash && record.key == key {
+                return Ok(Some(record.data));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check if a particular key exists
+    pub fn exists(&self, key: &[u8]) -> bool {
+        matches!(self.fetch(key), Ok(Some(_)))
+    }
+
+    /// Iterate over all live records, in the same order as `Tdb::iter`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(&[u8], &[u8]), Error>> + '_ {
+        (0..self.header.hash_size)
+            .flat_map(move |bucket| self.chain(bucket))
+            .filter_map(|record| match record {
+                Ok(record) if record.dead => None,
+                Ok(record) => Some(Ok((record.key, record.data))),
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// Iterate over all keys, in the same order as `Tdb::keys`.
+    pub fn keys(&self) -> impl Iterator<Item = Result<&[u8], Error>> + '_ {
+        self.iter().map(|item| item.map(|(key, _)| key))
+    }
+
+    /// Return the blocks on the freelist.
+    pub fn freelist(&self) -> Result<Vec<FreeBlock>, Error> {
+        let mut blocks = Vec::new();
+        let mut offset = self.u32_at(FREELIST_TOP)?;
+        while offset != 0 {
+            if blocks.len() > self.map_size() / RECORD_LEN as usize
+                || self.u32_at(add_offset(offset, 20)?)? != TDB_FREE_MAGIC
+            {
+                return Err(Error::Corrupt);
+            }
+            blocks.push(FreeBlock {
+                offset,
+                rec_len: self.u32_at(add_offset(offset, 4)?)?,
+            });
+            offset = self.u32_at(offset)?;
+        }
+        Ok(blocks)
+    }
+}
+
+/// Iterator over the records in a hash chain, created by [`TdbReader::chain`].
+pub struct Chain<'a> {
+    reader: &'a TdbReader,
+    next: Option<Result<u32, Error>>,
+    /// Upper bound on the number of records left, to detect loops in corrupt files.
+    remaining: usize,
+}
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = match self.next.take()? {
+            Ok(0) => return None,
+            Ok(offset) => offset,
+            Err(e) => return Some(Err(e)),
+        };
+        if self.remaining == 0 {
+            return Some(Err(Error::Corrupt));
+        }
+        self.remaining -= 1;
+        let record = self.reader.record(offset);
+        if let Ok(record) = &record {
+            self.next = Some(Ok(record.next));
+        }
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Flags, Tdb};
+
+    fn create(flags: Flags) -> (tempfile::TempDir, Tdb) {
+        let dir = tempfile::tempdir().unwrap();
+        let tdb = Tdb::open(
+            dir.path().join("test.tdb"),
+            Some(17),
+            flags,
+            libc::O_RDWR | libc::O_CREAT,
+            0o600,
+        )
+        .unwrap();
+        (dir, tdb)
+    }
+
+    fn populate(tdb: &mut Tdb) {
+        for i in 0..200 {
+            let key = format!("key{}", i);
+            let value = format!("value{}", i).repeat(i % 7);
+            tdb.store(key.as_bytes(), value.as_bytes(), None).unwrap();
+        }
+        tdb.store(b"", b"empty key", None).unwrap();
+        tdb.store(b"empty value", b"", None).unwrap();
+    }
+
+    fn assert_same(tdb: &Tdb, reader: &TdbReader) {
+        let expected = tdb.iter().collect::<Vec<_>>();
+        let actual = reader
+            .iter()
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+        for (key, value) in &expected {
+            assert_eq!(reader.fetch(key).unwrap(), Some(value.as_slice()));
+        }
+        assert_eq!(reader.fetch(b"missing").unwrap(), None);
+        assert_eq!(reader.hash_size(), tdb.hash_size());
+    }
+
+    #[test]
+    fn test_jenkins_hash() {
+        assert_eq!(jenkins_hash(b""), 0xdeadbeef);
+        assert_eq!(jenkins_hash(b"Four score and seven years ago"), 0x17770551);
+        for len in 0..40 {
+            let key = (0..len).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+            assert_eq!(jenkins_hash(&key), crate::jenkins_hash(&key));
+        }
+    }
+
+    #[test]
+    fn test_old_hash() {
+        let (dir, mut tdb) = create(Flags::empty());
+        populate(&mut tdb);
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        assert_eq!(reader.hash_function(), HashFunction::Old);
+        assert_same(&tdb, &reader);
+    }
+
+    #[test]
+    fn test_incompatible_hash() {
+        let (dir, mut tdb) = create(Flags::IncompatibleHash);
+        populate(&mut tdb);
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        assert_eq!(reader.hash_function(), HashFunction::Jenkins);
+        assert_same(&tdb, &reader);
+    }
+
+    #[test]
+    fn test_header() {
+        let (dir, mut tdb) = create(Flags::Seqnum);
+        tdb.store(b"foo", b"bar", None).unwrap();
+        tdb.store(b"foo", b"baz", None).unwrap();
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        let header = reader.header();
+        assert_eq!(header.version, TDB_VERSION);
+        assert_eq!(header.hash_size, 17);
+        assert_eq!(header.sequence_number as u64, tdb.get_seqnum());
+        assert_eq!(reader.map_size(), tdb.map_size() as usize);
+    }
+
+    #[test]
+    fn test_deleted_records() {
+        let (dir, mut tdb) = create(Flags::empty());
+        populate(&mut tdb);
+        // Dead records stay in their hash chain, the others are moved to the freelist.
+        tdb.set_max_dead(5);
+        for i in (0..200).step_by(3) {
+            tdb.delete(format!("key{}", i).as_bytes()).unwrap();
+        }
+
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        // Count the dead records first: iterating over the database cleans them up.
+        let dead = (0..reader.hash_size())
+            .flat_map(|bucket| reader.chain(bucket))
+            .filter(|record| record.as_ref().unwrap().dead)
+            .count();
+        assert!(dead > 0);
+        assert_same(&tdb, &reader);
+        assert!(!reader.exists(b"key0"));
+        assert!(reader.exists(b"key1"));
+
+        let free = reader.freelist().unwrap();
+        assert_eq!(free.len(), tdb.freelist_size() as usize);
+        for block in free {
+            assert!(block.offset >= FREELIST_TOP + (reader.hash_size() + 1) * 4);
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let (dir, mut tdb) = create(Flags::empty());
+        populate(&mut tdb);
+        let reader = TdbReader::open(dir.path().join("test.tdb")).unwrap();
+        let bucket = reader.bucket(b"key42");
+        let record = reader
+            .chain(bucket)
+            .map(Result::unwrap)
+            .find(|record| record.key == b"key42")
+            .unwrap();
+        assert!(record.data.is_empty());
+        assert_eq!(record.full_hash, HashFunction::Old.hash(b"key42"));
+        assert!(record.rec_len as usize >= record.key.len() + record.data.len());
+        assert!(matches!(
+            reader.chain(reader.hash_size()).next(),
+            Some(Err(Error::Invalid))
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            TdbReader::from_bytes(Vec::new()),
+            Err(Error::Corrupt)
+        ));
+        assert!(matches!(
+            TdbReader::from_bytes(vec![0; 1024]),
+            Err(Error::Corrupt)
+        ));
+
+        let (dir, mut tdb) = create(Flags::empty());
+        populate(&mut tdb);
+        let data = std::fs::read(dir.path().join("test.tdb")).unwrap();
+
+        // Bad version
+        let mut bad = data.clone();
+        bad[VERSION_OFS as usize] ^= 0xff;
+        assert!(matches!(TdbReader::from_bytes(bad), Err(Error::Corrupt)));
+
+        // Unknown hash function
+        let mut bad = data.clone();
+        bad[MAGIC1_HASH_OFS as usize] ^= 0xff;
+        assert!(matches!(TdbReader::from_bytes(bad), Err(Error::Invalid)));
+
+        // A truncated file is detected when reading the records
+        let truncated = data[..data.len() / 2].to_vec();
+        let reader = TdbReader::from_bytes(truncated).unwrap();
+        assert!(reader.iter().any(|item| item.is_err()));
+    }
+
+    #[test]
+    fn test_offsets_near_end() {
+        let (dir, mut tdb) = create(Flags::empty());
+        tdb.store(b"key", b"value", None).unwrap();
+        let data = std::fs::read(dir.path().join("test.tdb")).unwrap();
+        let reader = TdbReader::from_bytes(data.clone()).unwrap();
+        let head = FREELIST_TOP + (reader.bucket(b"key") + 1) * 4;
+        let record = reader.u32_at(head).unwrap();
+        let big_endian = reader.header().big_endian;
+        // A copy of the file with the given fields changed.
+        let with = |fields: &[(u32, u32)]| {
+            let mut data = data.clone();
+            for &(ofs, value) in fields {
+                let bytes = if big_endian {
+                    value.to_be_bytes()
+                } else {
+                    value.to_le_bytes()
+                };
+                data[ofs as usize..ofs as usize + 4].copy_from_slice(&bytes);
+            }
+            data
+        };
+
+        // Recovery area
+        let bad = with(&[(RECOVERY_START_OFS, u32::MAX - 10)]);
+        assert!(matches!(TdbReader::from_bytes(bad), Err(Error::Corrupt)));
+
+        // Hash chain
+        let reader = TdbReader::from_bytes(with(&[(HASH_SIZE_OFS, u32::MAX)])).unwrap();
+        assert!(matches!(
+            reader.chain(u32::MAX - 1).next(),
+            Some(Err(Error::Corrupt))
+        ));
+
+        // Record
+        let reader = TdbReader::from_bytes(with(&[(head, u32::MAX - 8)])).unwrap();
+        assert!(matches!(reader.fetch(b"key"), Err(Error::Corrupt)));
+
+        // Key length
+        let bad = with(&[
+            (record + 4, u32::MAX),
+            (record + 8, u32::MAX - 30),
+            (record + 12, 0),
+        ]);
+        let reader = TdbReader::from_bytes(bad).unwrap();
+        assert!(matches!(reader.fetch(b"key"), Err(Error::Corrupt)));
+
+        // Freelist
+        let reader = TdbReader::from_bytes(with(&[(FREELIST_TOP, u32::MAX - 4)])).unwrap();
+        assert!(matches!(reader.freelist(), Err(Error::Corrupt)));
+    }
+}
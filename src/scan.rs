@@ -0,0 +1,204 @@
+//! Listing keys page by page, with tokens to continue from later.
+//!
+//! Unlike [`Tdb::keys`], a scan does not need to be kept open between pages, so it can be
+//! continued in a later request, e.g. by an admin UI that lists keys over HTTP.
+//!
+//! ```rust
+//! use trivialdb::{Flags, Tdb};
+//!
+//! let mut tdb = Tdb::memory(None, Flags::empty()).unwrap();
+//! for i in 0..25u32 {
+//!     tdb.store(&i.to_le_bytes(), b"value", None).unwrap();
+//! }
+//!
+//! let mut keys = Vec::new();
+//! let mut token = None;
+//! loop {
+//!     let page = tdb.scan(token, 10).unwrap();
+//!     keys.extend(page.keys);
+//!     match page.next {
+//!         Some(next) => token = Some(next),
+//!         None => break,
+//!     }
+//! }
+//! assert_eq!(keys.len(), 25);
+//! ```
+
+use crate::reader::HashFunction;
+use crate::{Error, Tdb};
+
+/// Where a [`Tdb::scan`] stopped: the last key it returned.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScanToken(Vec<u8>);
+
+impl ScanToken {
+    /// Create a token that continues a scan after `key`, e.g. one that was passed to a client
+    /// with [`ScanToken::key`].
+    pub fn from_key(key: Vec<u8>) -> Self {
+        ScanToken(key)
+    }
+
+    /// The last key returned by the scan.
+    pub fn key(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Return the last key returned by the scan.
+    pub fn into_key(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// A page of keys returned by [`Tdb::scan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    /// The keys, in the same order as [`Tdb::keys`].
+    pub keys: Vec<Vec<u8>>,
+    /// The token to pass to [`Tdb::scan`] for the next page, or `None` if there are no more
+    /// keys.
+    pub next: Option<ScanToken>,
+}
+
+impl Tdb {
+    /// The hash function records are stored with, which the handle finds out when it is
+    /// opened.
+    pub(crate) fn hash_function(&self) -> HashFunction {
+        self.0.hash_function()
+    }
+
+    /// The hash chain `key` is stored in.
+    #[cfg(test)]
+    pub(crate) fn chain_of(&self, key: &[u8]) -> u32 {
+        self.hash_function().hash(key) % self.hash_size()
+    }
+
+    /// Iterate over the keys from the start of the hash chain `key` is stored in.
+    fn keys_from_chain_of(&self, key: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+        let (hash, hash_size) = (self.hash_function(), self.hash_size());
+        let chain = hash.hash(key) % hash_size;
+        let mut keys = self.keys().peekable();
+        // Keys are returned one hash chain after the other, so skip the earlier chains.
+        while let Some(key) = keys.peek() {
+            if hash.hash(key) % hash_size >= chain {
+                break;
+            }
+            keys.next();
+        }
+        keys
+    }
+
+    /// List a page of keys, starting from the beginning or where an earlier scan stopped.
+    ///
+    /// Records that are added or removed while scanning may or may not be returned. If the
+    /// last key of the previous page has been removed in the meantime, the scan continues from
+    /// the start of its hash chain, so that no keys are skipped; keys on that chain may be
+    /// returned again.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - The token returned with the previous page, or `None` to start from the
+    ///   beginning.
+    /// * `limit` - The maximum number of keys to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(page)` - The keys, and a token for the next page.
+    /// * `Err(Error::Invalid)` - `limit` is zero.
+    pub fn scan(&self, after: Option<ScanToken>, limit: usize) -> Result<ScanPage, Error> {
+        if limit == 0 {
+            return Err(Error::Invalid);
+        }
+        let mut keys: Box<dyn Iterator<Item = Vec<u8>>> = match &after {
+            None => Box::new(self.keys()),
+            Some(token) => {
+                let mut keys = self.0.keys_after(token.key()).peekable();
+                // Continuing after a key fails if it no longer exists.
+                if keys.peek().is_none() && !self.exists(token.key()) {
+                    Box::new(self.keys_from_chain_of(token.key()))
+                } else {
+                    Box::new(keys)
+                }
+            }
+        };
+        // Fetch one more key than needed to tell whether this is the last page.
+        let mut page = keys.by_ref().take(limit + 1).collect::<Vec<_>>();
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().cloned().map(ScanToken)
+        } else {
+            None
+        };
+        Ok(ScanPage { keys: page, next })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+    use std::collections::HashSet;
+
+    fn tdb() -> Tdb {
+        let mut tdb = Tdb::memory(Some(7), Flags::empty()).unwrap();
+        for i in 0..50u32 {
+            tdb.store(&i.to_le_bytes(), b"", None).unwrap();
+        }
+        tdb
+    }
+
+    #[test]
+    fn test_scan() {
+        let tdb = tdb();
+        let mut keys = Vec::new();
+        let mut token = None;
+        let mut pages = 0;
+        loop {
+            let page = tdb.scan(token, 7).unwrap();
+            assert!(page.keys.len() <= 7);
+            keys.extend(page.keys);
+            pages += 1;
+            match page.next {
+                // Tokens only hold the key, so they survive a round trip through a client.
+                Some(next) => token = Some(ScanToken::from_key(next.into_key())),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 8);
+        assert_eq!(keys, tdb.keys().collect::<Vec<_>>());
+
+        let page = tdb.scan(None, 50).unwrap();
+        assert_eq!(page.keys.len(), 50);
+        assert_eq!(page.next, None);
+        assert!(matches!(tdb.scan(None, 0), Err(Error::Invalid)));
+    }
+
+    #[test]
+    fn test_scan_deleted_token() {
+        let mut tdb = tdb();
+        let first = tdb.scan(None, 20).unwrap();
+        let token = first.next.unwrap();
+        tdb.delete(token.key()).unwrap();
+
+        let chain = tdb.chain_of(token.key());
+        let rest = tdb.scan(Some(token), 100).unwrap();
+        assert_eq!(rest.next, None);
+        // The scan restarted at the hash chain of the deleted key.
+        assert_eq!(tdb.chain_of(&rest.keys[0]), chain);
+        // Every key was seen, including the deleted one on the first page.
+        let seen = first.keys.iter().chain(&rest.keys).collect::<HashSet<_>>();
+        assert_eq!(seen.len(), 50);
+        for key in tdb.keys() {
+            assert!(seen.contains(&key));
+        }
+    }
+
+    #[test]
+    fn test_scan_deleted_token_keeps_locks() {
+        crate::test::assert_keeps_locks("scan::test::test_scan_deleted_token_keeps_locks", |tdb| {
+            tdb.store(b"gone", b"", None).unwrap();
+            tdb.delete(b"gone").unwrap();
+            tdb.scan(Some(ScanToken::from_key(b"gone".to_vec())), 10)
+                .unwrap();
+        });
+    }
+}
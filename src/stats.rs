@@ -0,0 +1,169 @@
+//! Typed statistics about a database, as an alternative to parsing [`Tdb::summary`].
+
+use crate::{Error, Tdb};
+
+/// Statistics about the contents and layout of a database.
+///
+/// With the `serde` feature, this implements `Serialize`, e.g. for exporting to dashboards.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    /// Number of live records.
+    pub records: usize,
+    /// Total size of the keys of all live records.
+    pub key_bytes: u64,
+    /// Total size of the values of all live records.
+    pub data_bytes: u64,
+    /// Number of deleted records that are still in their hash chain.
+    pub dead_records: usize,
+    /// Number of records in the freelist, as counted by walking the file.
+    pub free_records: usize,
+    /// Number of entries in the freelist, as returned by [`Tdb::freelist_size`].
+    pub freelist_size: u32,
+    /// Number of hash chains.
+    pub hash_chains: u32,
+    /// Length of the shortest hash chain, including dead records.
+    pub min_chain_length: usize,
+    /// Length of the longest hash chain, including dead records.
+    pub max_chain_length: usize,
+    /// Average length of the hash chains, including dead records.
+    pub avg_chain_length: f64,
+    /// Size of the database file, or of the memory area of a memory database.
+    pub map_size: u32,
+}
+
+/// The parts of the text returned by `tdb_summary` that [`Stats`] is built from.
+#[derive(Debug, Default, PartialEq)]
+struct Summary {
+    dead_records: usize,
+    free_records: usize,
+    min_chain_length: usize,
+    max_chain_length: usize,
+}
+
+/// Parse a "smallest/average/largest" triple.
+fn parse_range(value: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = value.split('/').map(|v| v.trim().parse().ok());
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+fn parse_summary(summary: &str) -> Option<Summary> {
+    let mut ret = Summary::default();
+    let mut chains = None;
+    for line in summary.lines() {
+        let Some((label, value)) = line.split_once(':') else {
+            continue;
+        };
+        match label {
+            "Number of dead records" => ret.dead_records = value.trim().parse().ok()?,
+            "Number of free records" => ret.free_records = value.trim().parse().ok()?,
+            "Smallest/average/largest hash chains" => chains = Some(parse_range(value)?),
+            _ => {}
+        }
+    }
+    let (min, _, max) = chains?;
+    ret.min_chain_length = min;
+    ret.max_chain_length = max;
+    Some(ret)
+}
+
+impl Tdb {
+    /// Gather statistics about the database.
+    ///
+    /// The records and their sizes are counted with a read traversal; dead and free records
+    /// and hash chain lengths are taken from [`Tdb::summary`]. Each of these locks the whole
+    /// database for reading, but not both at once, so a database that is being modified by
+    /// other processes may produce slightly inconsistent numbers.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(stats)` - The statistics.
+    /// * `Err(Error::Corrupt)` - The summary could not be generated or parsed.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let (mut key_bytes, mut data_bytes) = (0, 0);
+        let records = self.traverse_read(|key, data| {
+            key_bytes += key.len() as u64;
+            data_bytes += data.len() as u64;
+            true
+        })?;
+        let summary = parse_summary(&self.summary()).ok_or(Error::Corrupt)?;
+        let hash_chains = self.hash_size();
+        Ok(Stats {
+            records,
+            key_bytes,
+            data_bytes,
+            dead_records: summary.dead_records,
+            free_records: summary.free_records,
+            freelist_size: self.freelist_size(),
+            hash_chains,
+            min_chain_length: summary.min_chain_length,
+            max_chain_length: summary.max_chain_length,
+            avg_chain_length: (records + summary.dead_records) as f64 / hash_chains as f64,
+            map_size: self.map_size(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Flags;
+
+    #[test]
+    fn test_parse_summary() {
+        let summary = "Size of file/data: 12288/30\n\
+             Number of records: 3\n\
+             Incompatible hash: no\n\
+             Smallest/average/largest keys: 4/4/4\n\
+             Number of dead records: 2\n\
+             Smallest/average/largest dead records: 40/40/40\n\
+             Number of free records: 1\n\
+             Smallest/average/largest free records: 8000/8000/8000\n\
+             Number of hash chains: 131\n\
+             Smallest/average/largest hash chains: 0/0/3\n";
+        assert_eq!(
+            parse_summary(summary),
+            Some(Summary {
+                dead_records: 2,
+                free_records: 1,
+                min_chain_length: 0,
+                max_chain_length: 3,
+            })
+        );
+        assert_eq!(parse_summary("Number of dead records: many\n"), None);
+        assert_eq!(parse_summary(""), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut tdb = Tdb::memory(Some(7), Flags::empty()).unwrap();
+        for i in 0..20u32 {
+            tdb.store(format!("key{:02}", i).as_bytes(), &[0; 10], None)
+                .unwrap();
+        }
+        let stats = tdb.stats().unwrap();
+        assert_eq!(stats.records, 20);
+        assert_eq!(stats.key_bytes, 20 * 5);
+        assert_eq!(stats.data_bytes, 20 * 10);
+        assert_eq!(stats.hash_chains, 7);
+        assert!(stats.min_chain_length <= stats.max_chain_length);
+        assert!(stats.max_chain_length >= 3);
+        assert_eq!(stats.map_size, tdb.map_size());
+        assert_eq!(
+            stats.avg_chain_length,
+            (20 + stats.dead_records) as f64 / 7.0
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let stats = Stats {
+            records: 1,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(stats).unwrap();
+        assert_eq!(json["records"], 1);
+        assert_eq!(json["avg_chain_length"], 0.0);
+    }
+}
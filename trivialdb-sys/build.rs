@@ -0,0 +1,82 @@
+extern crate bindgen;
+extern crate pkg_config;
+
+use std::path::{Path, PathBuf};
+
+/// Symbols that only exist in some releases of libtdb, with the cfg that is set when `tdb.h`
+/// declares them.
+const OPTIONAL_SYMBOLS: &[(&str, &str)] = &[
+    ("tdb_storev", "tdb_storev"),
+    ("tdb_traverse_chain", "tdb_traverse_chain"),
+    ("tdb_runtime_check_for_robust_mutexes", "tdb_mutex_locking"),
+];
+
+fn main() {
+    for (_, cfg) in OPTIONAL_SYMBOLS {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+    }
+
+    generate_bindings();
+}
+
+/// Tell rustc and the build scripts of dependent crates which version of libtdb is used and
+/// which of the optional symbols its header declares.
+///
+/// Dependent crates see these as `DEP_TDB_VERSION` and e.g. `DEP_TDB_STOREV`.
+fn emit_features(version: &str, header: &Path) {
+    println!("cargo:version={}", version);
+    let header = std::fs::read_to_string(header)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", header.display(), e));
+    for (symbol, cfg) in OPTIONAL_SYMBOLS {
+        if declares(&header, symbol) {
+            println!("cargo:rustc-cfg={}", cfg);
+            println!("cargo:{}=1", cfg.trim_start_matches("tdb_"));
+        }
+    }
+}
+
+/// Whether `header` contains a declaration of the function `symbol`.
+fn declares(header: &str, symbol: &str) -> bool {
+    header.match_indices(symbol).any(|(i, _)| {
+        let before = header[..i].chars().next_back();
+        let after = header[i + symbol.len()..].trim_start().chars().next();
+        !matches!(before, Some(c) if c.is_alphanumeric() || c == '_') && after == Some('(')
+    })
+}
+
+fn generate_bindings() {
+    system_deps::Config::new().probe().unwrap();
+
+    // Use pkg-config to get the necessary flags for the `tdb` library
+    let pc_tdb = pkg_config::Config::new()
+        .probe("tdb")
+        .unwrap_or_else(|e| panic!("Failed to find tdb library: {}", e));
+
+    if pc_tdb.include_paths.len() != 1 {
+        panic!("Expected to find exactly one tdb include path");
+    }
+
+    let tdb_header = pc_tdb.include_paths[0].join("tdb.h");
+    emit_features(&pc_tdb.version, &tdb_header);
+
+    // Generate bindings using bindgen
+    let bindings = bindgen::Builder::default()
+        .header("sys/stat.h")
+        .header(tdb_header.to_str().unwrap())
+        .allowlist_file(tdb_header.to_str().unwrap())
+        .allowlist_type("mode_t")
+        .clang_args(
+            pc_tdb
+                .include_paths
+                .iter()
+                .map(|path| format!("-I{}", path.display())),
+        )
+        .generate_inline_functions(true)
+        .generate()
+        .expect("Failed to generate bindings");
+
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("tdb_sys.rs"))
+        .expect("Failed to write bindings");
+}
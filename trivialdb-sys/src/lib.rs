@@ -0,0 +1,26 @@
+//! Raw FFI bindings to libtdb, the trivial database library used by Samba.
+//!
+//! The bindings are generated by bindgen from the installed `tdb.h`. See the `trivialdb` crate
+//! for a safe API.
+//!
+//! libtdb is always linked from the system, and found with pkg-config; there is no option to
+//! build it from source.
+//!
+//! # Optional Symbols
+//!
+//! Some functions are only available in newer releases of libtdb. The build script checks
+//! which of them `tdb.h` declares and sets a cfg for each:
+//!
+//! - `tdb_storev`: `tdb_storev()`
+//! - `tdb_traverse_chain`: `tdb_traverse_chain()` and `tdb_traverse_key_chain()`
+//! - `tdb_mutex_locking`: `TDB_MUTEX_LOCKING` and `tdb_runtime_check_for_robust_mutexes()`
+//!
+//! Build scripts of dependent crates can read the same information from the
+//! `DEP_TDB_STOREV`, `DEP_TDB_TRAVERSE_CHAIN` and `DEP_TDB_MUTEX_LOCKING` environment
+//! variables, which are set when the symbol is available, and the version of libtdb from
+//! `DEP_TDB_VERSION`.
+#![allow(non_upper_case_globals)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+
+include!(concat!(env!("OUT_DIR"), "/tdb_sys.rs"));